@@ -0,0 +1,169 @@
+// Compares the threaded and epoll I/O modes while many idle keep-alive
+// connections are held open. Run with:
+//
+//     cargo bench --features epoll
+//
+// BENCH_IDLE_CONNECTIONS (default 1000), BENCH_CLIENTS (default 8) and
+// BENCH_SECONDS (default 3) tune the run. For tens of thousands of idle
+// connections, raise `ulimit -n` first.
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REQUEST: &[u8] = b"GET /healthz HTTP/1.1\r\nHost: bench\r\n\r\n";
+
+fn env_or(name: &str, default: u64) -> u64 {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+fn start_server(mode: &str, port: u16) -> Child {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_my_http_server"))
+        .env("IO_MODE", mode)
+        .env("PORT", port.to_string())
+        .env("IDLE_TIMEOUT_SECS", "120")
+        .env("MAX_REQUESTS_PER_CONNECTION", "1000000")
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start server");
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return child;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    panic!("server did not start on port {}", port);
+}
+
+// Sends one request and reads one response, returning its status code.
+fn round_trip(stream: &mut TcpStream) -> io::Result<u16> {
+    stream.write_all(REQUEST)?;
+    read_response(stream)
+}
+
+fn read_response(stream: &mut TcpStream) -> io::Result<u16> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        let text = String::from_utf8_lossy(&buf);
+        if let Some(head_end) = text.find("\r\n\r\n") {
+            let length = text[..head_end]
+                .lines()
+                .find_map(|l| l.strip_prefix("Content-Length: "))
+                .and_then(|v| v.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            if buf.len() >= head_end + 4 + length {
+                let status = text[9..12].parse().unwrap_or(0);
+                return Ok(status);
+            }
+        }
+    }
+}
+
+struct Outcome {
+    idle_open: usize,
+    idle_rejected: usize,
+    requests: u64,
+    elapsed: Duration,
+}
+
+fn run(mode: &str, port: u16, idle: usize, clients: usize, seconds: u64) -> Outcome {
+    let mut server = start_server(mode, port);
+
+    // Park idle connections, each having served one request. Requests go out
+    // on all of them first so that queued connections don't serialize the
+    // setup behind their read timeouts.
+    let mut streams = Vec::new();
+    for _ in 0..idle {
+        if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)) {
+            if stream.write_all(REQUEST).is_ok() {
+                streams.push(stream);
+            }
+        }
+    }
+    thread::sleep(Duration::from_millis(500));
+    let mut parked = Vec::new();
+    for mut stream in streams {
+        stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        if let Ok(200) = read_response(&mut stream) {
+            parked.push(stream);
+        }
+    }
+    let idle_rejected = idle - parked.len();
+
+    // Then measure keep-alive throughput from a few busy clients.
+    let stop = Arc::new(AtomicBool::new(false));
+    let count = Arc::new(AtomicU64::new(0));
+    let start = Instant::now();
+    let handles: Vec<_> = (0..clients)
+        .map(|_| {
+            let stop = Arc::clone(&stop);
+            let count = Arc::clone(&count);
+            thread::spawn(move || {
+                let mut stream = match TcpStream::connect(("127.0.0.1", port)) {
+                    Ok(s) => s,
+                    Err(_) => return,
+                };
+                stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+                while !stop.load(Ordering::Relaxed) {
+                    match round_trip(&mut stream) {
+                        Ok(200) => count.fetch_add(1, Ordering::Relaxed),
+                        _ => return,
+                    };
+                }
+            })
+        })
+        .collect();
+    thread::sleep(Duration::from_secs(seconds));
+    stop.store(true, Ordering::Relaxed);
+    for h in handles {
+        let _ = h.join();
+    }
+    let elapsed = start.elapsed();
+
+    let idle_open = parked.len();
+    drop(parked);
+    let _ = server.kill();
+    let _ = server.wait();
+
+    Outcome {
+        idle_open,
+        idle_rejected,
+        requests: count.load(Ordering::Relaxed),
+        elapsed,
+    }
+}
+
+fn main() {
+    let idle = env_or("BENCH_IDLE_CONNECTIONS", 1000) as usize;
+    let clients = env_or("BENCH_CLIENTS", 8) as usize;
+    let seconds = env_or("BENCH_SECONDS", 3);
+
+    println!("{} busy clients, {}s per run", clients, seconds);
+    println!("{:<8} {:>10} {:>10} {:>10} {:>12}", "mode", "idle", "idle open", "rejected", "req/s");
+    for idle in [0, idle] {
+        for (mode, port) in [("threads", 18080), ("epoll", 18081)] {
+            let o = run(mode, port, idle, clients, seconds);
+            println!(
+                "{:<8} {:>10} {:>10} {:>10} {:>12.0}",
+                mode,
+                idle,
+                o.idle_open,
+                o.idle_rejected,
+                o.requests as f64 / o.elapsed.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// Compiles the static site into the binary. Every file under SITE_DIR
+// becomes an entry in $OUT_DIR/site.rs with its content type, modification
+// time, an ETag and gzip/brotli copies, which `src/embedded.rs` serves
+// without touching the filesystem at runtime. Without SITE_DIR nothing is
+// embedded and the stats page keeps `/`.
+//
+// With EMBED_TLS_CERT and EMBED_TLS_KEY set to PEM files, the certificate
+// chain and key are compiled in as well ($OUT_DIR/tls.rs, used by
+// `src/tls.rs`).
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+#[path = "src/mime.rs"]
+mod mime;
+
+// A compressed copy is only kept if it saves at least this share of the
+// original, so already-compressed formats (images, fonts) are sent as they are.
+const MIN_SAVING: f64 = 0.1;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=SITE_DIR");
+    let site = env::var_os("SITE_DIR").map(PathBuf::from);
+    if let Some(site) = &site {
+        println!("cargo:rerun-if-changed={}", site.display());
+    }
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    let mut files = Vec::new();
+    if let Some(site) = site.filter(|site| site.is_dir()) {
+        collect(&site, "", &mut files);
+    }
+    files.sort();
+
+    let mut table = String::from("// Generated by build.rs from the site directory.\nstatic ASSETS: &[Asset] = &[\n");
+    for (url, path) in files {
+        let path = path.canonicalize().unwrap();
+        let body = fs::read(&path).unwrap();
+        let modified = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut encodings = String::new();
+        for (coding, extension, compressed) in [("br", "br", brotli(&body)), ("gzip", "gz", gzip(&body))] {
+            if (compressed.len() as f64) > body.len() as f64 * (1.0 - MIN_SAVING) {
+                continue;
+            }
+            let out = out_dir.join("site").join(format!("{}.{}", &url[1..], extension));
+            fs::create_dir_all(out.parent().unwrap()).unwrap();
+            fs::write(&out, compressed).unwrap();
+            let _ = write!(encodings, "({:?}, include_bytes!({:?})), ", coding, out);
+        }
+
+        let _ = writeln!(
+            table,
+            "    Asset {{ path: {:?}, mime: {:?}, etag: \"{:016x}\", modified: {}, body: include_bytes!({:?}), encodings: &[{}] }},",
+            url,
+            mime::from_path(&path),
+            fnv1a(&body),
+            modified,
+            path,
+            encodings.trim_end()
+        );
+    }
+    table.push_str("];\n");
+    fs::write(out_dir.join("site.rs"), table).unwrap();
+
+    embed_tls(&out_dir);
+}
+
+fn embed_tls(out_dir: &Path) {
+    println!("cargo:rerun-if-env-changed=EMBED_TLS_CERT");
+    println!("cargo:rerun-if-env-changed=EMBED_TLS_KEY");
+    let pair = match (env::var_os("EMBED_TLS_CERT"), env::var_os("EMBED_TLS_KEY")) {
+        (Some(cert), Some(key)) => {
+            let cert = PathBuf::from(cert).canonicalize().expect("EMBED_TLS_CERT");
+            let key = PathBuf::from(key).canonicalize().expect("EMBED_TLS_KEY");
+            println!("cargo:rerun-if-changed={}", cert.display());
+            println!("cargo:rerun-if-changed={}", key.display());
+            format!("Some((include_bytes!({:?}), include_bytes!({:?})))", cert, key)
+        }
+        (None, None) => "None".to_string(),
+        _ => panic!("EMBED_TLS_CERT and EMBED_TLS_KEY have to be set together"),
+    };
+    let source = format!(
+        "// Generated by build.rs from EMBED_TLS_CERT and EMBED_TLS_KEY.\nstatic EMBEDDED_PEM: Option<(&[u8], &[u8])> = {};\n",
+        pair
+    );
+    fs::write(out_dir.join("tls.rs"), source).unwrap();
+}
+
+// Collects (URL path, file) pairs below `dir`, skipping dotfiles.
+fn collect(dir: &Path, prefix: &str, files: &mut Vec<(String, PathBuf)>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) if !name.starts_with('.') => name.to_string(),
+            Some(_) => continue,
+            None => {
+                println!("cargo:warning=skipping {}: file name is not UTF-8", path.display());
+                continue;
+            }
+        };
+        let url = format!("{}/{}", prefix, name);
+        if path.is_dir() {
+            collect(&path, &url, files);
+        } else {
+            files.push((url, path));
+        }
+    }
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn brotli(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    {
+        let mut encoder = brotli::CompressorWriter::new(&mut out, 4096, 11, 22);
+        encoder.write_all(data).unwrap();
+    }
+    out
+}
+
+// 64-bit FNV-1a; the ETag only has to change when the contents do.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3))
+}
@@ -0,0 +1,209 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::history::{History, Metric};
+use crate::http_date;
+use crate::request::Request;
+use crate::response::Response;
+use crate::stats::{Sampler, Snapshot};
+use crate::websocket::{Handler, Message};
+
+// Bumped whenever a field is removed or changes meaning. Adding fields does
+// not change the version.
+pub const STATS_VERSION: u32 = 1;
+
+// The `/api/stats` document. See the README for the field reference.
+#[derive(Serialize)]
+pub struct Stats<'a> {
+    pub version: u32,
+    // Unix time of the sample, in seconds.
+    pub timestamp: u64,
+    pub hostname: &'a str,
+    pub os_name: &'a str,
+    pub os_version: &'a str,
+    pub kernel_version: &'a str,
+    pub uptime_secs: u64,
+    pub boot_time: u64,
+    pub memory: Memory,
+    pub swap: Swap,
+    pub cpu: Cpu<'a>,
+    pub load_average: LoadAverage,
+}
+
+#[derive(Serialize)]
+pub struct Memory {
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    pub free_bytes: u64,
+    pub available_bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct Swap {
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    pub free_bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct Cpu<'a> {
+    pub count: usize,
+    pub brand: &'a str,
+    pub frequency_mhz: u64,
+    pub usage_percent: f32,
+    pub per_core_usage_percent: &'a [f32],
+}
+
+#[derive(Serialize)]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
+
+impl<'a> Stats<'a> {
+    pub fn from_snapshot(s: &'a Snapshot) -> Stats<'a> {
+        Stats {
+            version: STATS_VERSION,
+            timestamp: s.taken_at.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            hostname: &s.hostname,
+            os_name: &s.os_name,
+            os_version: &s.os_version,
+            kernel_version: &s.kernel_version,
+            uptime_secs: s.uptime_secs,
+            boot_time: s.boot_time,
+            memory: Memory {
+                total_bytes: s.total_memory,
+                used_bytes: s.used_memory,
+                free_bytes: s.free_memory,
+                available_bytes: s.available_memory,
+            },
+            swap: Swap {
+                total_bytes: s.total_swap,
+                used_bytes: s.used_swap,
+                free_bytes: s.free_swap,
+            },
+            cpu: Cpu {
+                count: s.cpu_count,
+                brand: &s.cpu_brand,
+                frequency_mhz: s.cpu_frequency_mhz,
+                usage_percent: s.cpu_usage,
+                per_core_usage_percent: &s.cpu_usage_per_core,
+            },
+            load_average: LoadAverage {
+                one: s.load_average[0],
+                five: s.load_average[1],
+                fifteen: s.load_average[2],
+            },
+        }
+    }
+}
+
+pub fn stats_json(snapshot: &Snapshot) -> Response {
+    let body = serde_json::to_vec_pretty(&Stats::from_snapshot(snapshot)).unwrap_or_default();
+    // Weak: the document is only rebuilt from a new sample, but nothing
+    // promises it is byte-for-byte stable across server versions.
+    let nanos = snapshot.taken_at.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    Response::new(200)
+        .with_header("Content-Type", "application/json")
+        .with_header("ETag", format!("W/\"{:x}\"", nanos))
+        .with_header("Last-Modified", http_date::format(http_date::unix_secs(snapshot.taken_at)))
+        .with_body(body)
+}
+
+// The `/api/stats/history` document: `points` are `[time, value]` pairs,
+// `step` seconds apart, times being Unix seconds at the start of each step.
+#[derive(Serialize)]
+pub struct HistoryDoc {
+    pub metric: &'static str,
+    pub unit: &'static str,
+    pub from: u64,
+    pub to: u64,
+    pub step: u64,
+    pub points: Vec<(u64, f64)>,
+}
+
+// Answers `/api/stats/history?metric=&from=&to=&step=`. Only `metric` is
+// required; see the README for the rest.
+pub fn stats_history(req: &Request, history: &History) -> Response {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (metric, from, to, step) = match history_query(req, now) {
+        Ok(query) => query,
+        Err(why) => return Response::text(400, format!("{}\n", why)),
+    };
+    let series = history.query(metric, from, to, step);
+    let doc = HistoryDoc {
+        metric: metric.name(),
+        unit: metric.unit(),
+        from,
+        to,
+        step: series.step,
+        points: series.points,
+    };
+    // Compact: a day of points is long enough as it is.
+    let body = serde_json::to_vec(&doc).unwrap_or_default();
+    Response::new(200).with_header("Content-Type", "application/json").with_body(body)
+}
+
+fn history_query(req: &Request, now: u64) -> Result<(Metric, u64, u64, u64), String> {
+    let metric = req.query("metric").unwrap_or_default();
+    let metric = metric.parse().map_err(|_| {
+        let names: Vec<&str> = Metric::ALL.iter().map(|m| m.name()).collect();
+        format!("metric has to be one of {}", names.join(", "))
+    })?;
+    // Unix times, or seconds before now when negative.
+    let time = |name: &str, default: u64| match req.query(name).as_deref() {
+        None | Some("") => Ok(default),
+        Some(value) => match value.parse::<i64>() {
+            Ok(t) if t < 0 => Ok(now.saturating_sub(t.unsigned_abs())),
+            Ok(t) => Ok(t as u64),
+            Err(_) => Err(format!("{} has to be a Unix time, or negative seconds from now", name)),
+        },
+    };
+    let (from, to) = (time("from", 0)?, time("to", now)?);
+    if from > to {
+        return Err("from has to be before to".to_string());
+    }
+    let step = match req.query("step").as_deref() {
+        None | Some("") => 1,
+        Some(value) => value.parse().ok().filter(|step| *step > 0).ok_or("step has to be a positive number of seconds")?,
+    };
+    // A step longer than the range would only give one point anyway.
+    let step = step.min((to - from).max(1));
+    Ok((metric, from, to, step))
+}
+
+// Pushes the `/api/stats` document over a WebSocket every `interval`,
+// starting straight away. A snapshot is only sent once; if the sampler hasn't
+// taken a new one when the next push is due, it goes out as soon as it has.
+pub struct StatsFeed {
+    sampler: Arc<Sampler>,
+    interval: Duration,
+    next: Instant,
+    last_sent: Option<SystemTime>,
+}
+
+impl StatsFeed {
+    pub fn new(sampler: Arc<Sampler>, interval: Duration) -> StatsFeed {
+        StatsFeed {
+            sampler,
+            interval,
+            next: Instant::now(),
+            last_sent: None,
+        }
+    }
+}
+
+impl Handler for StatsFeed {
+    fn poll(&mut self, now: Instant) -> Option<Message> {
+        let snapshot = self.sampler.latest();
+        if now < self.next || self.last_sent == Some(snapshot.taken_at) {
+            return None;
+        }
+        self.next = now + self.interval;
+        self.last_sent = Some(snapshot.taken_at);
+        serde_json::to_string(&Stats::from_snapshot(&snapshot)).ok().map(Message::Text)
+    }
+}
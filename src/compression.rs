@@ -0,0 +1,276 @@
+// On-the-fly response compression. `negotiate` picks a content coding from
+// `Accept-Encoding` and labels the response for it before preconditions are
+// checked, so a 304 or 412 never pays for compressing a body it won't send;
+// `encode` then compresses whatever is still going out.
+
+use std::io::{self, Write};
+use std::mem;
+use std::str::FromStr;
+
+use crate::config::Config;
+use crate::negotiate;
+use crate::request::Request;
+use crate::response::{Body, Response};
+
+// Bodies larger than this are sent as they are; big assets should be
+// precompressed (`.br`/`.gz` siblings, or the embedded site) instead.
+// Streamed bodies are compressed as they are produced, whatever their size.
+const MAX_DYNAMIC: u64 = 4 * 1024 * 1024;
+
+// Content types worth compressing, besides every `text/*` type. Most other
+// formats (images, fonts, archives, media) are already compressed.
+const COMPRESSIBLE: [&str; 8] = [
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/manifest+json",
+    "application/openmetrics-text",
+    "application/wasm",
+    "image/svg+xml",
+    "image/x-icon",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    // The content-coding token used in `Accept-Encoding`/`Content-Encoding`.
+    pub fn token(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.encode_stream(&mut out, |encoder| encoder.write_all(data))?;
+        Ok(out)
+    }
+
+    // Compresses whatever `produce` writes onto `w`. Flushing the encoder
+    // flushes `w` too, with everything written so far decodable.
+    fn encode_stream(self, w: &mut dyn Write, produce: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> io::Result<()> {
+        match self {
+            // Quality 5 rather than the maximum 11: similar ratio to gzip -9
+            // at a fraction of the CPU, which matters per request.
+            Encoding::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(w, 4096, 5, 22);
+                produce(&mut encoder)?;
+                encoder.into_inner();
+            }
+            Encoding::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(w, 3)?;
+                produce(&mut encoder)?;
+                encoder.finish()?;
+            }
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(w, flate2::Compression::default());
+                produce(&mut encoder)?;
+                encoder.finish()?;
+            }
+            // HTTP's "deflate" is the zlib format, not raw deflate.
+            Encoding::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(w, flate2::Compression::default());
+                produce(&mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Encoding, ()> {
+        match s {
+            "br" => Ok(Encoding::Brotli),
+            "zstd" => Ok(Encoding::Zstd),
+            "gzip" => Ok(Encoding::Gzip),
+            "deflate" => Ok(Encoding::Deflate),
+            _ => Err(()),
+        }
+    }
+}
+
+// Chooses how to compress `response`, if at all, and sets `Content-Encoding`,
+// `Vary` and a per-coding `ETag` to match. Responses that already carry an
+// encoding (precompressed files) are left alone, as are feeds, which have to
+// reach the client as they are polled, and requests for a range: those get
+// the identity body so the range refers to stable bytes.
+pub fn negotiate(req: &Request, response: Response, config: &Config) -> (Response, Option<Encoding>) {
+    let size_ok = match response.body.len() {
+        Some(len) => (config.compression_min_bytes..=MAX_DYNAMIC).contains(&len),
+        None => true,
+    };
+    let eligible = response.status == 200
+        && !config.compression.is_empty()
+        && response.header("Content-Encoding").is_none()
+        && !matches!(response.body, Body::Parts(_) | Body::Feed(_))
+        && size_ok
+        && response.header("Content-Type").is_some_and(compressible);
+    if !eligible {
+        return (response, None);
+    }
+    let response = add_vary(response, "Accept-Encoding");
+    if req.headers.get("Range").is_some() {
+        return (response, None);
+    }
+
+    let offered: Vec<&str> = config.compression.iter().map(|e| e.token()).collect();
+    let encoding = match negotiate::encoding(req.headers.get("Accept-Encoding"), &offered) {
+        Some(token) => config.compression.iter().copied().find(|e| e.token() == token),
+        None => None,
+    };
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return (response, None),
+    };
+
+    let mut response = response
+        .with_header("Content-Encoding", encoding.token())
+        // Compressed on every request, so a range of it isn't guaranteed
+        // to line up with an earlier response.
+        .without_header("Accept-Ranges");
+    if let Some(etag) = response.header("ETag").map(str::to_string) {
+        // Each coding is a different representation, so it gets its own tag.
+        let tagged = match etag.strip_suffix('"') {
+            Some(opaque) => format!("{}-{}\"", opaque, encoding.token()),
+            None => etag,
+        };
+        response = response.without_header("ETag").with_header("ETag", tagged);
+    }
+    (response, Some(encoding))
+}
+
+// Compresses the body chosen by `negotiate`, unless the response has since
+// become a 304 or 412. A streamed body stays streamed, compressed on the way
+// out.
+pub fn encode(mut response: Response, encoding: Option<Encoding>) -> Response {
+    let encoding = match encoding {
+        Some(encoding) if response.status == 200 => encoding,
+        _ => return response,
+    };
+    let body = mem::replace(&mut response.body, Body::Bytes(Vec::new()));
+    if let Body::Stream(_) = body {
+        return response.with_stream(move |w| encoding.encode_stream(w, |encoder| body.copy_to(encoder)));
+    }
+    match body.into_vec().and_then(|data| encoding.encode(&data)) {
+        Ok(compressed) => {
+            response.body = Body::Bytes(compressed);
+            response
+        }
+        Err(e) => {
+            println!("Unable to compress response: {}", e);
+            Response::status_page(500)
+        }
+    }
+}
+
+fn compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    essence.starts_with("text/") || COMPRESSIBLE.contains(&essence.as_str())
+}
+
+fn add_vary(response: Response, header: &str) -> Response {
+    match response.header("Vary").map(str::to_string) {
+        Some(vary) if vary.split(',').any(|v| v.trim().eq_ignore_ascii_case(header)) => response,
+        Some(vary) => response.without_header("Vary").with_header("Vary", format!("{}, {}", vary, header)),
+        None => response.with_header("Vary", header),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request;
+
+    fn req(accept_encoding: &str, range: bool) -> Request {
+        let mut head = format!("GET / HTTP/1.1\r\nHost: x\r\nAccept-Encoding: {}\r\n", accept_encoding);
+        if range {
+            head.push_str("Range: bytes=0-9\r\n");
+        }
+        head.push_str("\r\n");
+        request::Parser::default().parse(head.as_bytes()).unwrap().unwrap().0
+    }
+
+    fn config() -> Config {
+        let mut config = Config::from_env();
+        config.compression = vec![Encoding::Brotli, Encoding::Gzip];
+        config.compression_min_bytes = 1024;
+        config
+    }
+
+    fn response(content_type: &str, len: usize) -> Response {
+        Response::new(200)
+            .with_header("Content-Type", content_type)
+            .with_header("ETag", "\"abc\"")
+            .with_body(vec![b'a'; len])
+    }
+
+    fn chosen(accept_encoding: &str, response: Response) -> Option<Encoding> {
+        negotiate(&req(accept_encoding, false), response, &config()).1
+    }
+
+    #[test]
+    fn compresses_only_bodies_above_the_threshold() {
+        assert_eq!(chosen("gzip", response("text/plain", 1023)), None);
+        assert_eq!(chosen("gzip", response("text/plain", 1024)), Some(Encoding::Gzip));
+        assert_eq!(chosen("gzip", response("text/plain", MAX_DYNAMIC as usize + 1)), None);
+    }
+
+    #[test]
+    fn compresses_only_compressible_types() {
+        assert_eq!(chosen("gzip", response("text/html; charset=utf-8", 2048)), Some(Encoding::Gzip));
+        assert_eq!(chosen("gzip", response("Application/JSON", 2048)), Some(Encoding::Gzip));
+        assert_eq!(chosen("gzip", response("image/png", 2048)), None);
+        assert_eq!(chosen("gzip", response("application/zip", 2048)), None);
+    }
+
+    #[test]
+    fn respects_refused_codings() {
+        assert_eq!(chosen("br, gzip", response("text/plain", 2048)), Some(Encoding::Brotli));
+        assert_eq!(chosen("br;q=0, gzip", response("text/plain", 2048)), Some(Encoding::Gzip));
+        assert_eq!(chosen("br;q=0, gzip;q=0", response("text/plain", 2048)), None);
+        assert_eq!(chosen("zstd", response("text/plain", 2048)), None);
+    }
+
+    #[test]
+    fn labels_the_compressed_representation() {
+        let plain = response("text/plain", 2048).with_header("Vary", "Accept");
+        let (labelled, encoding) = negotiate(&req("gzip", false), plain, &config());
+        assert_eq!(encoding, Some(Encoding::Gzip));
+        assert_eq!(labelled.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(labelled.header("ETag"), Some("\"abc-gzip\""));
+        assert_eq!(labelled.header("Vary"), Some("Accept, Accept-Encoding"));
+        let compressed = encode(labelled, encoding);
+        assert!(compressed.body.len().unwrap() < 2048);
+
+        // Vary is set even when the identity body is chosen, and not twice.
+        let (identity, _) = negotiate(&req("identity", false), response("text/plain", 2048), &config());
+        assert_eq!(identity.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(identity.header("ETag"), Some("\"abc\""));
+        let again = response("text/plain", 2048).with_header("Vary", "accept-encoding");
+        assert_eq!(negotiate(&req("gzip", false), again, &config()).0.header("Vary"), Some("accept-encoding"));
+    }
+
+    #[test]
+    fn leaves_ranged_and_encoded_responses_alone() {
+        let (ranged, encoding) = negotiate(&req("gzip", true), response("text/plain", 2048), &config());
+        assert_eq!(encoding, None);
+        assert_eq!(ranged.header("Content-Encoding"), None);
+        let encoded = response("text/plain", 2048).with_header("Content-Encoding", "br");
+        let (encoded, encoding) = negotiate(&req("gzip", false), encoded, &config());
+        assert_eq!(encoding, None);
+        assert_eq!(encoded.header("Content-Encoding"), Some("br"));
+        assert_eq!(encoded.header("ETag"), Some("\"abc\""));
+    }
+}
@@ -0,0 +1,189 @@
+// Conditional requests (RFC 9110, section 13). Handlers only label their
+// responses with `ETag` and/or `Last-Modified`; `evaluate` then checks the
+// request's preconditions against those validators and turns the response
+// into a 304 or 412 where they say so.
+
+use crate::http_date;
+use crate::request::{Method, Request};
+use crate::response::Response;
+
+// Headers a 304 carries over from the response it replaces (section 15.4.5).
+const NOT_MODIFIED_HEADERS: [&str; 6] = ["ETag", "Last-Modified", "Vary", "Cache-Control", "Content-Location", "Expires"];
+
+// An entity-tag, e.g. `"abc"` or `W/"abc"`.
+struct EntityTag<'a> {
+    weak: bool,
+    opaque: &'a str,
+}
+
+// `If-Match` uses strong comparison: both tags strong and identical.
+fn strong_eq(a: &EntityTag, b: &EntityTag) -> bool {
+    !a.weak && !b.weak && a.opaque == b.opaque
+}
+
+// `If-None-Match` uses weak comparison, which ignores the `W/` prefix.
+fn weak_eq(a: &EntityTag, b: &EntityTag) -> bool {
+    a.opaque == b.opaque
+}
+
+// Applies the preconditions in the order section 13.2.2 gives them. Only
+// successful responses are subject to preconditions; anything else is
+// passed through unchanged.
+pub fn evaluate(req: &Request, response: Response) -> Response {
+    if !(200..300).contains(&response.status) {
+        return response;
+    }
+    let etag = response.header("ETag").and_then(parse_etag);
+    let last_modified = response.header("Last-Modified").and_then(http_date::parse);
+    let safe = matches!(req.method, Method::Get | Method::Head);
+
+    if let Some(if_match) = req.headers.get("If-Match") {
+        if !matches_any(if_match, etag.as_ref(), strong_eq) {
+            return Response::status_page(412);
+        }
+    } else if let (Some(since), Some(modified)) = (date_header(req, "If-Unmodified-Since"), last_modified) {
+        if modified > since {
+            return Response::status_page(412);
+        }
+    }
+
+    if let Some(if_none_match) = req.headers.get("If-None-Match") {
+        if matches_any(if_none_match, etag.as_ref(), weak_eq) {
+            return if safe { not_modified(&response) } else { Response::status_page(412) };
+        }
+    } else if let (true, Some(since), Some(modified)) = (safe, date_header(req, "If-Modified-Since"), last_modified) {
+        if modified <= since {
+            return not_modified(&response);
+        }
+    }
+    response
+}
+
+fn not_modified(response: &Response) -> Response {
+    NOT_MODIFIED_HEADERS.iter().fold(Response::new(304), |not_modified, name| match response.header(name) {
+        Some(value) => not_modified.with_header(name, value),
+        None => not_modified,
+    })
+}
+
+// An unparseable date makes the header count as absent.
+fn date_header(req: &Request, name: &str) -> Option<u64> {
+    req.headers.get(name).and_then(http_date::parse)
+}
+
+// Whether an `If-Match`/`If-None-Match` value matches the current
+// representation. `*` matches any representation at all; a list only
+// matches if the representation has a tag.
+fn matches_any(header: &str, current: Option<&EntityTag>, eq: fn(&EntityTag, &EntityTag) -> bool) -> bool {
+    if header.trim() == "*" {
+        return true;
+    }
+    match current {
+        Some(current) => etag_list(header).iter().any(|tag| eq(tag, current)),
+        None => false,
+    }
+}
+
+// Splits a comma-separated list of entity-tags. Commas are allowed inside
+// the quotes, so this can't just split on them.
+fn etag_list(header: &str) -> Vec<EntityTag<'_>> {
+    let mut tags = Vec::new();
+    let mut rest = header;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return tags;
+        }
+        let (weak, quoted) = match rest.strip_prefix("W/") {
+            Some(quoted) => (true, quoted),
+            None => (false, rest),
+        };
+        let end = match quoted.strip_prefix('"').and_then(|q| q.find('"')) {
+            Some(end) => end,
+            // Malformed; ignore what's left rather than guess.
+            None => return tags,
+        };
+        tags.push(EntityTag { weak, opaque: &quoted[1..end + 1] });
+        rest = &quoted[end + 2..];
+    }
+}
+
+fn parse_etag(value: &str) -> Option<EntityTag<'_>> {
+    etag_list(value).into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request;
+
+    const MODIFIED: u64 = 1_700_000_000;
+
+    fn req(method: &str, headers: &[(&str, String)]) -> Request {
+        let mut head = format!("{} / HTTP/1.1\r\nHost: x\r\n", method);
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        request::Parser::default().parse(head.as_bytes()).unwrap().unwrap().0
+    }
+
+    fn status(method: &str, headers: &[(&str, String)]) -> u16 {
+        let response = Response::text(200, "body")
+            .with_header("ETag", "\"v1\"")
+            .with_header("Last-Modified", http_date::format(MODIFIED));
+        evaluate(&req(method, headers), response).status
+    }
+
+    fn date(secs: u64) -> String {
+        http_date::format(secs)
+    }
+
+    #[test]
+    fn checks_if_match_before_if_unmodified_since() {
+        // A matching tag wins over a date the resource has changed since.
+        let stale = ("If-Unmodified-Since", date(MODIFIED - 60));
+        assert_eq!(status("PUT", &[("If-Match", "\"v1\"".into()), stale.clone()]), 200);
+        assert_eq!(status("PUT", &[("If-Match", "\"v0\"".into())]), 412);
+        assert_eq!(status("PUT", &[stale]), 412);
+        assert_eq!(status("PUT", &[("If-Unmodified-Since", date(MODIFIED))]), 200);
+    }
+
+    #[test]
+    fn checks_if_none_match_before_if_modified_since() {
+        // A different tag means modified, whatever the date says.
+        let fresh = ("If-Modified-Since", date(MODIFIED + 60));
+        assert_eq!(status("GET", &[("If-None-Match", "\"v0\"".into()), fresh.clone()]), 200);
+        assert_eq!(status("GET", &[("If-None-Match", "\"v1\"".into())]), 304);
+        assert_eq!(status("GET", &[fresh]), 304);
+        assert_eq!(status("GET", &[("If-Modified-Since", date(MODIFIED - 60))]), 200);
+        // If-Modified-Since only applies to GET and HEAD.
+        assert_eq!(status("POST", &[("If-Modified-Since", date(MODIFIED + 60))]), 200);
+    }
+
+    #[test]
+    fn compares_weak_tags_only_for_if_none_match() {
+        assert_eq!(status("GET", &[("If-None-Match", "W/\"v1\"".into())]), 304);
+        assert_eq!(status("GET", &[("If-Match", "W/\"v1\"".into())]), 412);
+        assert_eq!(status("GET", &[("If-Match", "\"v0\", \"v1\"".into())]), 200);
+    }
+
+    #[test]
+    fn matches_any_tag_with_a_star() {
+        assert_eq!(status("PUT", &[("If-Match", "*".into())]), 200);
+        assert_eq!(status("GET", &[("If-None-Match", "*".into())]), 304);
+        assert_eq!(status("PUT", &[("If-None-Match", "*".into())]), 412);
+    }
+
+    #[test]
+    fn answers_not_modified_only_for_safe_methods() {
+        let matching = [("If-None-Match", "\"v1\"".to_string())];
+        assert_eq!(status("GET", &matching), 304);
+        assert_eq!(status("HEAD", &matching), 304);
+        assert_eq!(status("DELETE", &matching), 412);
+        let not_modified = evaluate(&req("GET", &matching), Response::text(200, "body").with_header("ETag", "\"v1\""));
+        assert_eq!(not_modified.header("ETag"), Some("\"v1\""));
+        // Errors pass through untouched.
+        assert_eq!(evaluate(&req("GET", &matching), Response::status_page(404)).status, 404);
+    }
+}
@@ -0,0 +1,217 @@
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::acme::Challenge;
+use crate::compression::Encoding;
+use crate::history::Resolution;
+use crate::tls::{ClientAuthRule, SniCert};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoMode {
+    // One worker thread per open connection, from a bounded pool.
+    Threads,
+    // A single thread multiplexing every connection with epoll. Only
+    // available when built with `--features epoll`.
+    Epoll,
+}
+
+impl FromStr for IoMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<IoMode, ()> {
+        match s {
+            "threads" => Ok(IoMode::Threads),
+            "epoll" => Ok(IoMode::Epoll),
+            _ => Err(()),
+        }
+    }
+}
+
+// Runtime settings, read from environment variables so they can be set in the
+// OPS config (`"Env": {...}`) without rebuilding the image.
+pub struct Config {
+    pub port: u16,
+    pub io_mode: IoMode,
+    // How long a keep-alive connection may sit without a new request.
+    pub idle_timeout: Duration,
+    // How long sending a response may go without progress before the
+    // client is given up on.
+    pub write_timeout: Duration,
+    // Requests served on one connection before it is closed.
+    pub max_requests_per_connection: usize,
+    // Worker threads serving connections, and how many accepted connections
+    // may wait for a free worker before new ones are turned away with 503.
+    pub workers: usize,
+    pub queue_size: usize,
+    pub retry_after_secs: u64,
+    // WebSockets and event streams that may be open at once; more are
+    // turned away with 503. Unset, it is a quarter of the workers in
+    // threaded mode, which each of them occupies, and unlimited with epoll.
+    pub max_streams: Option<usize>,
+    // How often the background sampler refreshes system stats.
+    pub stats_interval: Duration,
+    // How often `/ws/stats` pushes a snapshot.
+    pub ws_stats_interval: Duration,
+    // Server events kept for `/events` clients resuming with
+    // `Last-Event-ID`, how often that stream sends a snapshot, and how long
+    // it may go quiet before a heartbeat comment.
+    pub sse_buffer_events: usize,
+    pub sse_stats_interval: Duration,
+    pub sse_heartbeat: Duration,
+    // Memory use, in percent, at which a `memory-warning` event is raised.
+    pub memory_warning_percent: f64,
+    // How long open connections get to finish after SIGTERM or SIGINT.
+    pub drain_timeout: Duration,
+    // Steps and spans of the stats history tiers.
+    pub history: Vec<Resolution>,
+    // Directory served as a static site. When set, the stats page moves from
+    // `/` to `/stats` and every path no other route claims is a file lookup.
+    pub doc_root: Option<PathBuf>,
+    // Content codings responses may be compressed with, in order of
+    // preference, and the smallest body worth compressing.
+    pub compression: Vec<Encoding>,
+    pub compression_min_bytes: u64,
+    // Port for HTTPS, if it is served. The certificate chain and key are
+    // read from these PEM files, or compiled in when they aren't set.
+    pub tls_port: Option<u16>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    // Certificates for particular host names, chosen by SNI.
+    pub tls_sni: Vec<SniCert>,
+    // How often certificate files are checked for changes; zero disables
+    // reloading them automatically.
+    pub tls_reload_interval: Duration,
+    // Answer every plain-HTTP request with a redirect to HTTPS.
+    pub https_redirect: bool,
+    // CA bundle client certificates are verified against; unset means
+    // clients aren't asked for one.
+    pub tls_client_ca: Option<PathBuf>,
+    // Per-path client certificate policies.
+    pub client_auth: Vec<ClientAuthRule>,
+    // Host names to get a certificate for from an ACME CA, which is then
+    // served by default. Needs HTTPS_PORT.
+    pub acme_domains: Vec<String>,
+    // The CA's directory URL, e.g. Pebble's in testing.
+    pub acme_directory: String,
+    pub acme_email: Option<String>,
+    // Where the account key and the issued certificate are kept.
+    pub acme_state_dir: PathBuf,
+    // Challenge types to answer, in order of preference.
+    pub acme_challenges: Vec<Challenge>,
+    // CA certificates the directory's HTTPS is verified against.
+    pub acme_ca_bundle: PathBuf,
+    // How long before expiry the certificate is renewed.
+    pub acme_renew_before: Duration,
+    // Speak HTTP/2: "h2" in ALPN, and h2c on the plain port for clients
+    // that open with the HTTP/2 preface.
+    pub http2: bool,
+    // Limits advertised in HTTP/2 SETTINGS and enforced on clients.
+    pub h2_max_concurrent_streams: u32,
+    pub h2_initial_window_size: u32,
+    pub h2_max_frame_size: u32,
+    pub h2_max_header_list_size: u32,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        Config {
+            port: env_or("PORT", 8080),
+            io_mode: env_or("IO_MODE", IoMode::Threads),
+            idle_timeout: Duration::from_secs(env_nonzero("IDLE_TIMEOUT_SECS", 5)),
+            write_timeout: Duration::from_secs(env_nonzero("WRITE_TIMEOUT_SECS", 10)),
+            max_requests_per_connection: env_or("MAX_REQUESTS_PER_CONNECTION", 100),
+            workers: env_nonzero("WORKERS", 16),
+            queue_size: env_or("QUEUE_SIZE", 64),
+            retry_after_secs: env_or("RETRY_AFTER_SECS", 1),
+            max_streams: env_opt("MAX_STREAMS"),
+            stats_interval: Duration::from_millis(env_or("STATS_INTERVAL_MS", 1000)),
+            ws_stats_interval: Duration::from_secs(env_or("WS_STATS_INTERVAL_SECS", 2)),
+            sse_buffer_events: env_or("SSE_BUFFER_EVENTS", 256),
+            sse_stats_interval: Duration::from_secs(env_or("SSE_STATS_INTERVAL_SECS", 2)),
+            sse_heartbeat: Duration::from_secs(env_or("SSE_HEARTBEAT_SECS", 15)),
+            memory_warning_percent: env_or("MEMORY_WARNING_PERCENT", 90.0),
+            drain_timeout: Duration::from_secs(env_or("DRAIN_TIMEOUT_SECS", 10)),
+            history: env_list("STATS_HISTORY", "1s:10m,1m:24h"),
+            doc_root: env::var_os("DOC_ROOT").filter(|v| !v.is_empty()).map(PathBuf::from),
+            compression: env_list("COMPRESSION", "br,zstd,gzip,deflate"),
+            compression_min_bytes: env_or("COMPRESSION_MIN_BYTES", 1024),
+            tls_port: env_opt("HTTPS_PORT"),
+            tls_cert: env::var_os("TLS_CERT").filter(|v| !v.is_empty()).map(PathBuf::from),
+            tls_key: env::var_os("TLS_KEY").filter(|v| !v.is_empty()).map(PathBuf::from),
+            tls_sni: env_list("TLS_SNI_CERTS", ""),
+            tls_reload_interval: Duration::from_secs(env_or("TLS_RELOAD_SECS", 10)),
+            https_redirect: env_or("HTTPS_REDIRECT", false),
+            tls_client_ca: env::var_os("TLS_CLIENT_CA").filter(|v| !v.is_empty()).map(PathBuf::from),
+            client_auth: env_list("CLIENT_AUTH", ""),
+            acme_domains: env_list::<String>("ACME_DOMAINS", "").into_iter().map(|d| d.to_ascii_lowercase()).collect(),
+            acme_directory: env_or("ACME_DIRECTORY", "https://acme-v02.api.letsencrypt.org/directory".to_string()),
+            acme_email: env_opt("ACME_EMAIL"),
+            acme_state_dir: env_or("ACME_STATE_DIR", PathBuf::from("acme")),
+            acme_challenges: env_list("ACME_CHALLENGES", "http-01,tls-alpn-01"),
+            acme_ca_bundle: env_or("ACME_CA_BUNDLE", PathBuf::from("/etc/ssl/certs/ca-certificates.crt")),
+            acme_renew_before: Duration::from_secs(env_or("ACME_RENEW_DAYS", 30) * 86_400),
+            http2: env_or("HTTP2", true),
+            h2_max_concurrent_streams: env_or("H2_MAX_CONCURRENT_STREAMS", 100),
+            h2_initial_window_size: env_or("H2_INITIAL_WINDOW_SIZE", 1024 * 1024),
+            h2_max_frame_size: env_or("H2_MAX_FRAME_SIZE", 16 * 1024),
+            h2_max_header_list_size: env_or("H2_MAX_HEADER_LIST_SIZE", 16 * 1024),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(v) => v,
+            Err(_) => {
+                println!("Ignoring invalid {}={:?}", name, value);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+// Like `env_or`, for settings that can't be zero: a socket timeout of zero
+// is refused by the OS, and a pool without workers never serves anything.
+fn env_nonzero<T: FromStr + Default + PartialEq>(name: &str, default: T) -> T {
+    match env_opt(name) {
+        Some(v) if v == T::default() => {
+            println!("Ignoring invalid {}=0", name);
+            default
+        }
+        Some(v) => v,
+        None => default,
+    }
+}
+
+// Like `env_or`, for settings that are off unless given.
+fn env_opt<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok().filter(|v| !v.is_empty())?;
+    match value.parse() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            println!("Ignoring invalid {}={:?}", name, value);
+            None
+        }
+    }
+}
+
+// A comma-separated list, e.g. "br,gzip". Unknown entries are skipped.
+fn env_list<T: FromStr>(name: &str, default: &str) -> Vec<T> {
+    let value = env::var(name).unwrap_or_else(|_| default.to_string());
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .filter_map(|item| match item.parse() {
+            Ok(v) => Some(v),
+            Err(_) => {
+                println!("Ignoring invalid {} entry {:?}", name, item);
+                None
+            }
+        })
+        .collect()
+}
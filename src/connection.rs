@@ -0,0 +1,347 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustls::ServerConfig;
+
+use crate::acme;
+use crate::compression;
+use crate::conditional;
+use crate::config::Config;
+use crate::http2;
+use crate::metrics::{Metered, Metrics};
+use crate::ranges;
+use crate::request::{self, Method, ParseError, ReadError, Request, Version};
+use crate::response::{Response, Transport};
+use crate::router::{Router, UNMATCHED};
+use crate::shutdown;
+use crate::tls::{self, ClientAuth, TlsInfo};
+use crate::websocket;
+
+// Route labels for requests answered with a redirect to HTTPS, and for ones
+// turned away for want of a client certificate.
+const HTTPS_REDIRECT: &str = "https-redirect";
+const CLIENT_CERT_REQUIRED: &str = "client-cert-required";
+
+// Serves requests on one connection until the client closes it, asks for it
+// to be closed, goes idle for longer than `config.idle_timeout`, or reaches
+// `config.max_requests_per_connection`. Pipelined requests are answered in
+// the order they arrived: anything read past the end of one request stays in
+// the buffer and is parsed next. With `tls`, the connection starts with a TLS
+// handshake. HTTP/2 connections are handed to `http2::serve`, and ones
+// switched to WebSocket to `websocket::serve`.
+pub fn handle_client(
+    stream: TcpStream,
+    tls: Option<&Arc<ServerConfig>>,
+    router: &Router,
+    config: &Config,
+    metrics: &Metrics,
+) {
+    let timeouts = stream
+        .set_read_timeout(Some(config.idle_timeout))
+        .and_then(|_| stream.set_write_timeout(Some(config.write_timeout)));
+    if let Err(e) = timeouts {
+        println!("Unable to set socket timeouts: {}", e);
+        return;
+    }
+    // File bodies go out as a separate sendfile after the head; don't let
+    // their tail wait on Nagle for the head's ACK.
+    let _ = stream.set_nodelay(true);
+
+    let _open = metrics.track_connection();
+    match tls {
+        None => serve(&mut Metered { inner: &stream, metrics }, &stream, None, router, config, metrics),
+        Some(tls) => match tls::accept(tls, &stream) {
+            Ok((tls_stream, info)) => {
+                log_tls(&info);
+                let mut tls_stream = Metered { inner: tls_stream, metrics };
+                // An ACME CA only wanted to see the challenge certificate.
+                if !info.is_acme_challenge() {
+                    serve(&mut tls_stream, &stream, Some(info), router, config, metrics);
+                }
+                tls_stream.inner.conn.send_close_notify();
+                let _ = tls_stream.inner.flush();
+            }
+            Err(e) => println!("TLS handshake failed: {}", e),
+        },
+    }
+}
+
+// `socket` is the connection under `stream`, for adjusting its timeouts.
+fn serve<S: Read + Transport>(
+    stream: &mut S,
+    socket: &TcpStream,
+    tls: Option<TlsInfo>,
+    router: &Router,
+    config: &Config,
+    metrics: &Metrics,
+) {
+    let mut buf = Vec::new();
+    // HTTP/2 is agreed in the TLS handshake, or opened with its preface on
+    // the plain port.
+    let http2 = match &tls {
+        Some(info) => info.is_h2(),
+        None if config.http2 => match read_preface(stream, &mut buf) {
+            Some(http2) => http2,
+            None => return,
+        },
+        None => false,
+    };
+    if http2 {
+        http2::serve(stream, socket, buf, tls.as_ref(), router, config, metrics);
+        return;
+    }
+    let mut served = 0;
+    loop {
+        let mut req = match handle_read(stream, &mut buf) {
+            Ok(Some(req)) => req,
+            Ok(None) => break,
+            Err(e) => {
+                metrics.count_request(UNMATCHED, e.status());
+                handle_error(stream, e);
+                break;
+            }
+        };
+        attach_tls(&mut req, tls.as_ref(), config);
+        log_request(&req);
+        let started = Instant::now();
+        served += 1;
+
+        let (mut response, keep_alive, route) = respond(router, &req, served, config);
+        let status = response.status;
+        let upgrade = response.upgrade.take();
+        let sent = handle_write(stream, &req, response);
+        metrics.observe_request(route, status, started.elapsed());
+        if !sent {
+            metrics.response_incomplete();
+        }
+        if let (true, Some(handler)) = (sent, upgrade) {
+            websocket::serve(stream, socket, buf, handler);
+            break;
+        }
+        if !sent || !keep_alive {
+            break;
+        }
+    }
+}
+
+// Routes a request, compresses the response and applies its preconditions
+// and `Range`, whatever the HTTP version. Plain-HTTP requests are redirected
+// to HTTPS instead when `config.https_redirect` is set, except ACME
+// challenges, and requests for paths that need a client certificate get 403
+// without one. Also returns the pattern of the route that answered.
+pub fn prepare<'r>(router: &'r Router, req: &Request, config: &Config) -> (Response, &'r str) {
+    let (response, route) = match config.tls_port {
+        // ACME CAs fetch HTTP-01 challenges over plain HTTP only.
+        Some(port) if config.https_redirect && req.tls.is_none() && !req.path().starts_with(acme::CHALLENGE_PATH) => {
+            (https_redirect(req, port), HTTPS_REDIRECT)
+        }
+        _ if req.client_cert().is_none() && tls::client_auth(&config.client_auth, req.path()) == ClientAuth::Required => {
+            (Response::status_page(403), CLIENT_CERT_REQUIRED)
+        }
+        _ => router.dispatch(req),
+    };
+    let (response, encoding) = compression::negotiate(req, response, config);
+    let response = compression::encode(conditional::evaluate(req, response), encoding);
+    (ranges::apply(req, response), route)
+}
+
+// Answers the `served`-th request on an HTTP/1 connection, and decides
+// whether the connection stays open afterwards, setting the `Connection`
+// header to match. Bodies of unknown length are sent chunked, or to HTTP/1.0
+// clients by closing the connection after them. Also returns the pattern of
+// the route that answered.
+pub fn respond<'r>(router: &'r Router, req: &Request, served: usize, config: &Config) -> (Response, bool, &'r str) {
+    let (mut response, route) = prepare(router, req, config);
+    // The connection is about to switch protocols; no more requests follow.
+    if response.upgrade.is_some() {
+        return (response, false, route);
+    }
+    let streamed = response.body.len().is_none();
+    if streamed && req.version == Version::Http11 {
+        response = response.with_header("Transfer-Encoding", "chunked");
+    }
+    let keep_alive = req.keep_alive()
+        && !shutdown::draining()
+        && served < config.max_requests_per_connection
+        && !(streamed && req.version == Version::Http10)
+        && !response.header("Connection").is_some_and(|v| v.eq_ignore_ascii_case("close"));
+    let response = if !keep_alive {
+        response.with_header("Connection", "close")
+    } else if req.version == Version::Http10 {
+        response.with_header("Connection", "keep-alive")
+    } else {
+        response
+    };
+    (response, keep_alive, route)
+}
+
+// `Location` for the same resource on the HTTPS port. Needs the client's
+// `Host` to know which name to send it to.
+fn https_redirect(req: &Request, port: u16) -> Response {
+    let host = match req.headers.get("Host") {
+        Some(host) => host,
+        None => return Response::status_page(400),
+    };
+    // Drop any port, keeping IPv6 literals like "[::1]:8080" intact.
+    let name = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let valid = !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-.[]:".contains(&b));
+    if !valid {
+        return Response::status_page(400);
+    }
+    let authority = if port == 443 { name.to_string() } else { format!("{}:{}", name, port) };
+    // Origin-form targets are kept as sent, query and all; absolute-form
+    // ones, which only proxies should get, are cut down to the path.
+    let target = if req.target.starts_with('/') { req.target.as_str() } else { req.path() };
+    Response::status_page(308).with_header("Location", format!("https://{}{}", authority, target))
+}
+
+// Gives a request the connection's TLS details, leaving out the client
+// certificate where the path's policy is to ignore it.
+pub fn attach_tls(req: &mut Request, tls: Option<&TlsInfo>, config: &Config) {
+    let ignore_cert = tls::client_auth(&config.client_auth, req.path()) == ClientAuth::None;
+    req.tls = tls.cloned();
+    if let Some(tls) = &mut req.tls {
+        if ignore_cert {
+            tls.client_cert = None;
+        }
+    }
+}
+
+pub fn log_tls(info: &TlsInfo) {
+    let client = match &info.client_cert {
+        Some(cert) => format!(", client \"{}\"", cert.subject),
+        None => String::new(),
+    };
+    println!(
+        "TLS {} {} (SNI {}, ALPN {}{})",
+        info.version,
+        info.cipher,
+        info.server_name.as_deref().unwrap_or("-"),
+        info.alpn.as_deref().unwrap_or("-"),
+        client
+    );
+}
+
+pub fn log_request(req: &Request) {
+    let trailers = match req.trailers.len() {
+        0 => String::new(),
+        n => format!(", {} trailers", n),
+    };
+    let client = match req.client_cert() {
+        // The subject is RFC 4514 escaped, so quotes in it are too.
+        Some(cert) => format!(" client \"{}\" SAN {}", cert.subject, cert.san.join(",")),
+        None => String::new(),
+    };
+    println!(
+        "{} {} {} ({} headers, {} byte body{}){}",
+        req.method,
+        req.path(),
+        req.version,
+        req.headers.len(),
+        req.body.len(),
+        trailers,
+        client
+    );
+}
+
+// Turns a connection away when every worker is busy and the queue is full.
+pub fn reject_overloaded(mut stream: TcpStream, retry_after_secs: u64) {
+    let response = Response::status_page(503)
+        .with_header("Retry-After", retry_after_secs.to_string())
+        .with_header("Connection", "close");
+    // The accept loop calls this, so don't let a slow client stall it.
+    let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+    if let Err(e) = response.write_to(&mut stream, true) {
+        println!("Failed sending response: {}", e);
+    }
+}
+
+// Reads until `buf` shows whether the client opened with the HTTP/2
+// preface. `None` if it went away or idle first.
+fn read_preface<S: Read>(stream: &mut S, buf: &mut Vec<u8>) -> Option<bool> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(http2) = http2::is_preface(buf) {
+            return Some(http2);
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => return None,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                if is_timeout(&e) {
+                    println!("Closing idle connection");
+                } else {
+                    println!("Unable to read stream: {}", e);
+                }
+                return None;
+            }
+        }
+    }
+}
+
+// Reads the next request. `Ok(None)` means the connection should be closed
+// quietly: the peer hung up, went idle, or the socket failed.
+fn handle_read<S: Read + Transport>(stream: &mut S, buf: &mut Vec<u8>) -> Result<Option<Request>, ParseError> {
+    match request::read_request(stream, buf) {
+        Ok(Some(req)) => Ok(Some(req)),
+        Ok(None) => Ok(None),
+        Err(ReadError::Parse(e)) => {
+            println!("Rejecting request: {}", e);
+            Err(e)
+        }
+        Err(ReadError::Io(e)) if is_timeout(&e) => {
+            if buf.is_empty() {
+                println!("Closing idle connection");
+            } else {
+                println!("Timed out reading request");
+                let response = Response::status_page(408).with_header("Connection", "close");
+                let _ = response.write_to(stream, true);
+            }
+            Ok(None)
+        }
+        Err(ReadError::Io(e)) => {
+            if e.kind() != io::ErrorKind::UnexpectedEof {
+                println!("Unable to read stream: {}", e);
+            }
+            Ok(None)
+        }
+    }
+}
+
+fn handle_error<S: Transport>(stream: &mut S, err: ParseError) {
+    let response = Response::status_page(err.status()).with_header("Connection", "close");
+    if let Err(e) = response.write_to(stream, true) {
+        println!("Failed sending response: {}", e);
+    }
+}
+
+// Returns false if the response could not be written in full and the
+// connection should be dropped.
+fn handle_write<S: Transport>(stream: &mut S, req: &Request, response: Response) -> bool {
+    let status = response.status;
+    match response.write_to(stream, req.method != Method::Head) {
+        Ok(sent) => {
+            println!("Response sent: {} ({} bytes)", status, sent);
+            true
+        }
+        Err(e) if is_timeout(&e.error) => {
+            println!("Timed out sending response: {} bytes sent", e.sent);
+            false
+        }
+        Err(e) => {
+            println!("Failed sending response: {}", e);
+            false
+        }
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
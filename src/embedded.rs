@@ -0,0 +1,75 @@
+use crate::http_date;
+use crate::negotiate;
+use crate::request::Request;
+use crate::response::Response;
+use crate::static_files;
+
+// A file compiled into the binary from the site directory by build.rs.
+struct Asset {
+    path: &'static str,
+    mime: &'static str,
+    // Hash of the uncompressed contents, unquoted.
+    etag: &'static str,
+    // Modification time of the source file at build time, Unix seconds.
+    modified: u64,
+    body: &'static [u8],
+    // Precompressed copies by content coding, in order of preference. Only
+    // present for files that compress well.
+    encodings: &'static [(&'static str, &'static [u8])],
+}
+
+include!(concat!(env!("OUT_DIR"), "/site.rs"));
+
+pub fn file_count() -> usize {
+    ASSETS.len()
+}
+
+// Serves the embedded site the same way `DocRoot` serves a directory: same
+// path checks, index.html for directories, and a redirect to add the
+// trailing slash.
+pub fn serve(req: &Request) -> Response {
+    let path = req.path();
+    let segments = match static_files::path_segments(path) {
+        Ok(segments) => segments,
+        Err(status) => return Response::status_page(status),
+    };
+    let name = format!("/{}", segments.join("/"));
+
+    let asset = match find(&name) {
+        Some(asset) => asset,
+        None => {
+            let index = format!("{}/index.html", name.trim_end_matches('/'));
+            match find(&index) {
+                Some(_) if !path.ends_with('/') => return static_files::directory_redirect(req, &segments),
+                Some(asset) => asset,
+                None => return Response::status_page(404),
+            }
+        }
+    };
+
+    let offered: Vec<&str> = asset.encodings.iter().map(|(coding, _)| *coding).collect();
+    let chosen = negotiate::encoding(req.headers.get("Accept-Encoding"), &offered)
+        .and_then(|c| asset.encodings.iter().find(|(coding, _)| *coding == c));
+    let mut response = Response::new(200)
+        .with_header("Content-Type", asset.mime)
+        .with_header("Last-Modified", http_date::format(asset.modified))
+        .with_header("Accept-Ranges", "bytes");
+    if !asset.encodings.is_empty() {
+        response = response.with_header("Vary", "Accept-Encoding");
+    }
+    match chosen {
+        // Each encoding is a different representation, so it gets its own tag.
+        Some((coding, body)) => response
+            .with_header("Content-Encoding", *coding)
+            .with_header("ETag", format!("\"{}-{}\"", asset.etag, coding))
+            .with_static_body(body),
+        None => response
+            .with_header("ETag", format!("\"{}\"", asset.etag))
+            .with_static_body(asset.body),
+    }
+}
+
+// `ASSETS` is sorted by path.
+fn find(path: &str) -> Option<&'static Asset> {
+    ASSETS.binary_search_by(|asset| asset.path.cmp(path)).ok().map(|i| &ASSETS[i])
+}
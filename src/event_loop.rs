@@ -0,0 +1,690 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Instant;
+
+use mio::net::{TcpListener as MioListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+use rustls::{ServerConfig, ServerConnection};
+
+use crate::config::Config;
+use crate::connection;
+use crate::http2;
+use crate::metrics::Metrics;
+use crate::request::{self, Method};
+use crate::response::{self, Body, Feed, Response};
+use crate::router::{Router, UNMATCHED};
+use crate::sendfile;
+use crate::shutdown;
+use crate::tls::TlsInfo;
+use crate::websocket;
+
+const LISTENER: Token = Token(0);
+const TLS_LISTENER: Token = Token(1);
+
+// Stop reading from a connection while this much response data is waiting to
+// be sent, so a client pipelining requests without reading can't grow the
+// buffer without bound.
+const MAX_PENDING_WRITE: usize = 1024 * 1024;
+
+// Likewise stop reading while this much request data is waiting to be
+// parsed, which is more than the largest request the parser accepts. Requests
+// queued behind a feed, or behind a full write buffer, would otherwise pile up
+// here.
+const MAX_PENDING_READ: usize = 2 * 1024 * 1024;
+
+struct Conn {
+    stream: TcpStream,
+    read_buf: Vec<u8>,
+    // Where it got to with a request that is still arriving.
+    parser: request::Parser,
+    write_buf: Vec<u8>,
+    served: usize,
+    last_active: Instant,
+    // Set once the last response has been queued; the connection is dropped
+    // as soon as `write_buf` drains.
+    closing: bool,
+    // Set once the client has finished sending. The requests already in
+    // `read_buf` are still answered, and the connection closes after that.
+    peer_done: bool,
+    // For HTTPS connections, the TLS state; `read_buf` and `write_buf` hold
+    // plaintext. `tls_info` is filled in once the handshake is done.
+    tls: Option<ServerConnection>,
+    tls_info: Option<TlsInfo>,
+    // Set once the connection turns out to speak HTTP/2; `read_buf` and
+    // `write_buf` then hold frames instead of HTTP/1 messages.
+    h2: Option<Box<http2::Connection>>,
+    // Set once the connection has switched to WebSocket; `read_buf` and
+    // `write_buf` then hold its frames.
+    ws: Option<Box<websocket::Connection>>,
+    // Set while the body of the last response is a feed; requests after it
+    // wait until it ends.
+    feed: Option<Feeding>,
+    // What is left of the last response's body once `write_buf` has drained,
+    // when that includes files: they go out a piece at a time as the socket
+    // takes them, rather than being read into memory whole. Requests after
+    // it wait until it has all been sent.
+    body: VecDeque<Body>,
+}
+
+struct Feeding {
+    feed: Box<dyn Feed>,
+    chunked: bool,
+    keep_alive: bool,
+}
+
+impl Conn {
+    // Reads request bytes like a plain socket read, decrypting them first on
+    // an HTTPS connection.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let tls = match &mut self.tls {
+            Some(tls) => tls,
+            None => return self.stream.read(buf),
+        };
+        loop {
+            match tls.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            if tls.read_tls(&mut self.stream)? == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = tls.process_new_packets() {
+                // Try to tell the client why before giving up on it.
+                let _ = tls.write_tls(&mut self.stream);
+                if tls.is_handshaking() {
+                    println!("TLS handshake failed: {}", e);
+                }
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            if self.tls_info.is_none() && !tls.is_handshaking() {
+                let info = TlsInfo::of(tls);
+                connection::log_tls(&info);
+                // An ACME CA only wanted to see the challenge certificate.
+                self.closing = info.is_acme_challenge();
+                self.tls_info = Some(info);
+            }
+        }
+    }
+
+    // Sends as much of `write_buf` and then `body` as the socket takes.
+    // Returns false if some of it has to wait for the socket to become
+    // writable. On a plain connection files are handed to sendfile(2); over
+    // TLS they are read into `write_buf` a buffer's worth at a time.
+    fn flush(&mut self, metrics: &Metrics) -> io::Result<bool> {
+        loop {
+            if !self.write_out(metrics)? {
+                return Ok(false);
+            }
+            let (file, offset, len) = match self.body.pop_front() {
+                None => return Ok(true),
+                Some(Body::File { file, offset, len }) => (file, offset, len),
+                Some(Body::Parts(parts)) => {
+                    for part in parts.into_iter().rev() {
+                        self.body.push_front(part);
+                    }
+                    continue;
+                }
+                Some(body) => {
+                    body.copy_to(&mut self.write_buf)?;
+                    continue;
+                }
+            };
+            if len == 0 {
+                continue;
+            }
+            let sent = match self.tls {
+                None => sendfile::send_file(&self.stream, &file, offset, len),
+                Some(_) => sendfile::copy(&mut self.write_buf, &file, offset, len),
+            };
+            let n = match sent {
+                // The file shrank after Content-Length was sent.
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.body.push_front(Body::File { file, offset, len });
+                    return Ok(false);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+                Err(e) => return Err(e),
+            };
+            if self.tls.is_none() {
+                metrics.add_bytes_out(n as usize);
+            }
+            if n < len {
+                self.body.push_front(Body::File { file, offset: offset + n, len: len - n });
+            }
+        }
+    }
+
+    // Sends as much of `write_buf` as the socket takes, encrypting it first
+    // on an HTTPS connection. Returns false if some of it has to wait for the
+    // socket to become writable.
+    fn write_out(&mut self, metrics: &Metrics) -> io::Result<bool> {
+        let tls = match &mut self.tls {
+            Some(tls) => tls,
+            None => {
+                while !self.write_buf.is_empty() {
+                    match self.stream.write(&self.write_buf) {
+                        Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                        Ok(n) => {
+                            metrics.add_bytes_out(n);
+                            self.write_buf.drain(..n);
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                return Ok(true);
+            }
+        };
+        loop {
+            // rustls takes plaintext up to its buffer limit at a time.
+            if !self.write_buf.is_empty() {
+                let n = tls.writer().write(&self.write_buf)?;
+                metrics.add_bytes_out(n);
+                self.write_buf.drain(..n);
+            }
+            if !tls.wants_write() {
+                return Ok(self.write_buf.is_empty());
+            }
+            match tls.write_tls(&mut self.stream) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Whether anything is waiting to go out, including TLS records rustls
+    // has yet to send.
+    fn has_output(&self) -> bool {
+        !self.write_buf.is_empty() || !self.body.is_empty() || self.tls.as_ref().is_some_and(|tls| tls.wants_write())
+    }
+
+    // Response bytes queued but not yet sent.
+    fn unsent(&self) -> u64 {
+        self.write_buf.len() as u64 + self.body.iter().filter_map(Body::len).sum::<u64>()
+    }
+
+    // What to wait for: more requests unless enough are already buffered,
+    // and the socket taking more output if there is any.
+    fn interest(&self) -> Interest {
+        match (self.read_buf.len() < MAX_PENDING_READ, self.has_output()) {
+            (true, true) => Interest::READABLE | Interest::WRITABLE,
+            (true, false) => Interest::READABLE,
+            // With nothing to send either, this fires once and then stays
+            // quiet until `push` registers again.
+            (false, _) => Interest::WRITABLE,
+        }
+    }
+}
+
+// Serves every connection from a single thread using non-blocking sockets.
+// Handlers are the same as in the threaded mode and run inline, so they must
+// not block. `tls` is the HTTPS listener, if any, and its TLS settings.
+pub fn run(
+    listener: TcpListener,
+    tls: Option<(TcpListener, Arc<ServerConfig>)>,
+    router: &Router,
+    config: &Config,
+    metrics: &Metrics,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut listener = MioListener::from_std(listener);
+    let mut poll = Poll::new()?;
+    poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+    let tls = match tls {
+        Some((tls_listener, tls)) => {
+            tls_listener.set_nonblocking(true)?;
+            let mut tls_listener = MioListener::from_std(tls_listener);
+            poll.registry().register(&mut tls_listener, TLS_LISTENER, Interest::READABLE)?;
+            Some((tls_listener, tls))
+        }
+        None => None,
+    };
+
+    let mut events = Events::with_capacity(1024);
+    let mut conns: HashMap<Token, Conn> = HashMap::new();
+    let mut next_token = 2;
+    // Idle connections are swept, and WebSocket handlers polled, at most
+    // this often.
+    let tick = config.idle_timeout.min(config.write_timeout).min(response::TICK);
+
+    loop {
+        if let Err(e) = poll.poll(&mut events, Some(tick)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        for event in events.iter() {
+            if event.token() == LISTENER {
+                accept(&listener, None, &poll, &mut conns, &mut next_token, metrics);
+                continue;
+            }
+            if event.token() == TLS_LISTENER {
+                if let Some((tls_listener, tls)) = &tls {
+                    accept(tls_listener, Some(tls), &poll, &mut conns, &mut next_token, metrics);
+                }
+                continue;
+            }
+            let token = event.token();
+            let done = match conns.get_mut(&token) {
+                Some(conn) => !service(conn, router, config, metrics),
+                None => continue,
+            };
+            if done {
+                close(&poll, &mut conns, token, metrics);
+            } else if let Some(conn) = conns.get_mut(&token) {
+                let interest = conn.interest();
+                if poll.registry().reregister(&mut conn.stream, token, interest).is_err() {
+                    close(&poll, &mut conns, token, metrics);
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let mut finished = Vec::new();
+        for (token, conn) in conns.iter_mut() {
+            if !push(conn, *token, &poll, now, router, config, metrics) {
+                finished.push(*token);
+            }
+        }
+        for token in finished {
+            close(&poll, &mut conns, token, metrics);
+        }
+
+        let expired: Vec<Token> = conns
+            .iter()
+            .filter(|(_, c)| {
+                // With output pending, it's the client that isn't reading.
+                // WebSockets ping idle clients themselves, and feeds send
+                // heartbeats.
+                let streaming = c.ws.is_some() || c.feed.is_some() || c.h2.as_ref().is_some_and(|h2| h2.has_feeds());
+                let timeout = match (c.has_output(), streaming) {
+                    (true, _) => config.write_timeout,
+                    (false, true) => return false,
+                    (false, false) => config.idle_timeout,
+                };
+                now.duration_since(c.last_active) >= timeout
+            })
+            .map(|(t, _)| *t)
+            .collect();
+        for token in expired {
+            let conn = match conns.get_mut(&token) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            if conn.has_output() {
+                println!("Timed out sending response: {} bytes unsent", conn.unsent());
+                metrics.response_incomplete();
+            } else if conn.read_buf.is_empty() || conn.h2.is_some() {
+                println!("Closing idle connection");
+            } else {
+                println!("Timed out reading request");
+                // Queued like any other response; the connection closes once
+                // it has been sent.
+                let response = Response::status_page(408).with_header("Connection", "close");
+                let _ = response.write_to(&mut conn.write_buf, true);
+                conn.read_buf.clear();
+                conn.closing = true;
+                conn.last_active = now;
+                if poll.registry().reregister(&mut conn.stream, token, Interest::WRITABLE).is_ok() {
+                    continue;
+                }
+            }
+            close(&poll, &mut conns, token, metrics);
+        }
+    }
+}
+
+fn accept(
+    listener: &MioListener,
+    tls: Option<&Arc<ServerConfig>>,
+    poll: &Poll,
+    conns: &mut HashMap<Token, Conn>,
+    next_token: &mut usize,
+    metrics: &Metrics,
+) {
+    loop {
+        match listener.accept() {
+            // Draining: new clients are just disconnected.
+            Ok(_) if shutdown::draining() => {}
+            Ok((mut stream, _)) => {
+                let tls = match tls.map(|tls| ServerConnection::new(Arc::clone(tls))).transpose() {
+                    Ok(tls) => tls,
+                    Err(e) => {
+                        println!("Unable to start TLS: {}", e);
+                        continue;
+                    }
+                };
+                let token = Token(*next_token);
+                *next_token += 1;
+                if let Err(e) = poll.registry().register(&mut stream, token, Interest::READABLE) {
+                    println!("Unable to register connection: {}", e);
+                    continue;
+                }
+                metrics.connection_opened();
+                conns.insert(
+                    token,
+                    Conn {
+                        stream,
+                        read_buf: Vec::new(),
+                        parser: request::Parser::default(),
+                        write_buf: Vec::new(),
+                        served: 0,
+                        last_active: Instant::now(),
+                        closing: false,
+                        peer_done: false,
+                        tls,
+                        tls_info: None,
+                        h2: None,
+                        ws: None,
+                        feed: None,
+                        body: VecDeque::new(),
+                    },
+                );
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                println!("Unable to connect: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn close(poll: &Poll, conns: &mut HashMap<Token, Conn>, token: Token, metrics: &Metrics) {
+    if let Some(mut conn) = conns.remove(&token) {
+        if let Some(tls) = &mut conn.tls {
+            tls.send_close_notify();
+            let _ = tls.write_tls(&mut conn.stream);
+        }
+        let _ = poll.registry().deregister(&mut conn.stream);
+        metrics.connection_closed();
+    }
+}
+
+// Sends whatever a WebSocket handler or a feed has due. Returns false when
+// the connection should be closed.
+fn push(conn: &mut Conn, token: Token, poll: &Poll, now: Instant, router: &Router, config: &Config, metrics: &Metrics) -> bool {
+    if let Some(ws) = &mut conn.ws {
+        ws.tick(now);
+        conn.write_buf.append(&mut ws.out);
+        conn.closing |= ws.is_done();
+    } else if conn.feed.is_some() {
+        pump(conn, now);
+    } else if !conn.h2.as_ref().is_some_and(|h2| h2.has_feeds()) || conn.has_output() {
+        return true;
+    }
+    if !conn.has_output() && !conn.closing && conn.h2.is_none() {
+        return true;
+    }
+    // Requests that arrived while the feed was running are answered once it
+    // has ended and been sent.
+    let open = answer(conn, router, config, metrics);
+    if conn.has_output() {
+        conn.last_active = now;
+    }
+    // Reading may have stopped while the feed ran.
+    let interest = conn.interest();
+    open && poll.registry().reregister(&mut conn.stream, token, interest).is_ok()
+}
+
+// Moves what the feed has produced to `write_buf`. Once it ends, so does
+// the response, and the connection too unless it is kept alive.
+fn pump(conn: &mut Conn, now: Instant) {
+    let feeding = match &mut conn.feed {
+        Some(feeding) => feeding,
+        None => return,
+    };
+    let mut data = Vec::new();
+    let more = feeding.feed.poll(now, &mut data);
+    if feeding.chunked && !data.is_empty() {
+        conn.write_buf.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+        conn.write_buf.append(&mut data);
+        conn.write_buf.extend_from_slice(b"\r\n");
+    } else {
+        conn.write_buf.append(&mut data);
+    }
+    if !more {
+        if feeding.chunked {
+            conn.write_buf.extend_from_slice(b"0\r\n\r\n");
+        }
+        conn.closing |= !feeding.keep_alive;
+        conn.feed = None;
+    }
+}
+
+// Reads whatever is available, answers every complete request in the buffer
+// and writes as much of the output as the socket accepts. Returns false when
+// the connection should be closed.
+fn service(conn: &mut Conn, router: &Router, config: &Config, metrics: &Metrics) -> bool {
+    conn.last_active = Instant::now();
+
+    if !conn.closing && conn.write_buf.len() < MAX_PENDING_WRITE {
+        let mut chunk = [0u8; 4096];
+        // The rest stays in the socket until the buffer has been worked down.
+        while !conn.peer_done && conn.read_buf.len() < MAX_PENDING_READ {
+            match conn.read(&mut chunk) {
+                Ok(0) => {
+                    // Peer finished sending; answer what we have, then close.
+                    // HTTP/2 and WebSocket peers are done with the connection.
+                    conn.peer_done = true;
+                    conn.closing |= conn.h2.is_some() || conn.ws.is_some();
+                    break;
+                }
+                Ok(n) => {
+                    metrics.add_bytes_in(n);
+                    conn.read_buf.extend_from_slice(&chunk[..n]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+        process_requests(conn, router, config, metrics);
+    }
+    answer(conn, router, config, metrics)
+}
+
+// Writes what is queued and, each time it has all gone out, answers the
+// requests held back behind it: by a full write buffer, a file body or a
+// feed. Returns false when the connection should be closed.
+fn answer(conn: &mut Conn, router: &Router, config: &Config, metrics: &Metrics) -> bool {
+    loop {
+        match conn.flush(metrics) {
+            Ok(true) => {}
+            Ok(false) => return true,
+            Err(e) => {
+                println!("Failed sending response: {} ({} bytes unsent)", e, conn.unsent());
+                metrics.response_incomplete();
+                return false;
+            }
+        }
+        if conn.closing || (conn.read_buf.is_empty() && conn.h2.is_none()) {
+            break;
+        }
+        process_requests(conn, router, config, metrics);
+        if !conn.has_output() {
+            break;
+        }
+        // HTTP/2 responses send more data once the socket is writable again.
+        if conn.h2.is_some() {
+            return true;
+        }
+    }
+    // A client that has stopped sending has had every answer it will get,
+    // unless a feed is still running.
+    !(conn.closing || (conn.peer_done && conn.feed.is_none()))
+}
+
+// Responses are only queued here, not sent, so the latency recorded in this
+// mode covers parsing and routing but not the network write. Bodies with files
+// in them are left to `flush`; streamed bodies run to completion in the write
+// buffer.
+fn process_requests(conn: &mut Conn, router: &Router, config: &Config, metrics: &Metrics) {
+    if conn.h2.is_none() {
+        let h2 = match &conn.tls_info {
+            Some(info) => info.is_h2(),
+            None if conn.tls.is_none() && config.http2 && conn.served == 0 => {
+                match http2::is_preface(&conn.read_buf) {
+                    Some(h2) => h2,
+                    None => return,
+                }
+            }
+            None => false,
+        };
+        if h2 {
+            conn.h2 = Some(Box::new(http2::Connection::new(http2::Settings::new(config))));
+        }
+    }
+    if let Some(ws) = &mut conn.ws {
+        ws.receive(&mut conn.read_buf);
+        ws.tick(Instant::now());
+        conn.write_buf.append(&mut ws.out);
+        conn.closing |= ws.is_done();
+        return;
+    }
+    if let Some(h2) = &mut conn.h2 {
+        let tls = conn.tls_info.as_ref();
+        if !http2::process(h2, &mut conn.read_buf, &mut conn.write_buf, MAX_PENDING_WRITE, tls, router, config, metrics) {
+            conn.closing = true;
+        }
+        return;
+    }
+    while conn.write_buf.len() < MAX_PENDING_WRITE && conn.feed.is_none() && conn.body.is_empty() {
+        let mut req = match conn.parser.parse(&conn.read_buf) {
+            Ok(Some((req, used))) => {
+                conn.read_buf.drain(..used);
+                req
+            }
+            Ok(None) => return,
+            Err(e) => {
+                println!("Rejecting request: {}", e);
+                metrics.count_request(UNMATCHED, e.status());
+                let response = Response::status_page(e.status()).with_header("Connection", "close");
+                let _ = response.write_to(&mut conn.write_buf, true);
+                conn.closing = true;
+                return;
+            }
+        };
+        connection::attach_tls(&mut req, conn.tls_info.as_ref(), config);
+        connection::log_request(&req);
+        let started = Instant::now();
+        conn.served += 1;
+
+        let (mut response, keep_alive, route) = connection::respond(router, &req, conn.served, config);
+        let status = response.status;
+        let upgrade = response.upgrade.take();
+        if let (Body::Feed(_), true) = (&response.body, req.method != Method::Head) {
+            // Polled from here on, a piece at a time, like a WebSocket.
+            conn.write_buf.extend_from_slice(response.head().as_bytes());
+            let chunked = response.is_chunked();
+            if let Body::Feed(feed) = response.body {
+                conn.feed = Some(Feeding { feed, chunked, keep_alive });
+            }
+            metrics.observe_request(route, status, started.elapsed());
+            println!("Response queued: {} (feed)", status);
+            pump(conn, Instant::now());
+            if conn.closing {
+                return;
+            }
+            continue;
+        }
+        let include_body = req.method != Method::Head;
+        let queued = if include_body && has_file(&response.body) {
+            // Only the head for now; `flush` sends the body.
+            let head = response.head();
+            conn.write_buf.extend_from_slice(head.as_bytes());
+            let len = response.body.len().unwrap_or(0);
+            conn.body.push_back(response.body);
+            Ok(head.len() as u64 + len)
+        } else {
+            response.write_to(&mut conn.write_buf, include_body)
+        };
+        metrics.observe_request(route, status, started.elapsed());
+        match queued {
+            Ok(queued) => println!("Response queued: {} ({} bytes)", status, queued),
+            // A body that failed part way (a file read error) can't be
+            // finished, so the client only gets what is already queued.
+            Err(e) => {
+                println!("Failed sending response: {}", e);
+                metrics.response_incomplete();
+                conn.closing = true;
+                return;
+            }
+        }
+        if let Some(handler) = upgrade {
+            // Anything after the handshake is already WebSocket frames.
+            conn.ws = Some(Box::new(websocket::Connection::new(handler)));
+            process_requests(conn, router, config, metrics);
+            return;
+        }
+        if !keep_alive {
+            conn.closing = true;
+            return;
+        }
+    }
+}
+
+// Whether a body includes a file, which is then left for `flush` to send.
+fn has_file(body: &Body) -> bool {
+    match body {
+        Body::File { .. } => true,
+        Body::Parts(parts) => parts.iter().any(has_file),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::process;
+    use std::thread;
+
+    use crate::response::Response;
+
+    const BIG: usize = 4 * 1024 * 1024;
+
+    #[test]
+    fn answers_pipelined_requests_after_the_client_half_closes() {
+        let path = std::env::temp_dir().join(format!("event-loop-{}.bin", process::id()));
+        fs::write(&path, vec![b'x'; BIG]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut router = Router::new();
+        {
+            let path = path.clone();
+            router.get("/big.bin", move |_, _| {
+                Response::new(200).with_file(File::open(&path).unwrap(), BIG as u64)
+            });
+        }
+        router.get("/small.txt", |_, _| Response::text(200, "small"));
+        thread::spawn(move || run(listener, None, &router, &Config::from_env(), &Metrics::new()));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /big.bin HTTP/1.1\r\nHost: x\r\n\r\nGET /small.txt HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let text = String::from_utf8_lossy(&received);
+        assert_eq!(text.matches("HTTP/1.1 200").count(), 2);
+        assert!(received.len() > BIG);
+        assert!(text.ends_with("small"));
+    }
+}
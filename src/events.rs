@@ -0,0 +1,226 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde_json::{json, Value};
+
+use crate::api::Stats;
+use crate::response::Feed;
+use crate::shutdown;
+use crate::stats::Sampler;
+
+// Server lifecycle events, and the `/events` Server-Sent Events stream that
+// carries them alongside stats snapshots. Events are numbered and kept in a
+// bounded ring so a client that reconnects with `Last-Event-ID` gets the ones
+// it missed; snapshots carry no ID and are never replayed.
+
+// How long clients wait before reconnecting, in milliseconds.
+const RETRY_MS: u64 = 3000;
+
+// Memory use has to drop this many points below the warning threshold
+// before `memory-ok` is raised, so usage hovering around it doesn't flap.
+const MEMORY_HYSTERESIS: f64 = 5.0;
+
+#[derive(Debug)]
+pub struct Event {
+    pub id: u64,
+    pub kind: &'static str,
+    // JSON, on one line.
+    pub data: String,
+}
+
+#[derive(Debug)]
+pub struct EventLog {
+    capacity: usize,
+    // Oldest first; IDs count up from 1 without gaps.
+    ring: Mutex<VecDeque<Arc<Event>>>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Arc<EventLog> {
+        Arc::new(EventLog {
+            capacity: capacity.max(1),
+            ring: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    // Records an event, evicting the oldest once the ring is full.
+    pub fn publish(&self, kind: &'static str, data: Value) {
+        let mut ring = self.ring.lock().unwrap();
+        let id = ring.back().map_or(1, |e| e.id + 1);
+        println!("Event {}: {} {}", id, kind, data);
+        if ring.len() == self.capacity {
+            ring.pop_front();
+        }
+        ring.push_back(Arc::new(Event { id, kind, data: data.to_string() }));
+    }
+
+    // The ID of the newest event, or 0 before the first.
+    pub fn latest_id(&self) -> u64 {
+        self.ring.lock().unwrap().back().map_or(0, |e| e.id)
+    }
+
+    // Events newer than `last_id`, oldest first. An ID from the future can
+    // only have come from before a restart, so the client gets everything
+    // still buffered. Events evicted since `last_id` are lost.
+    pub fn since(&self, last_id: u64) -> Vec<Arc<Event>> {
+        let ring = self.ring.lock().unwrap();
+        let newest = ring.back().map_or(0, |e| e.id);
+        let last_id = if last_id > newest { 0 } else { last_id };
+        ring.iter().filter(|e| e.id > last_id).cloned().collect()
+    }
+}
+
+// Raises `memory-warning` when memory use reaches `percent`, and
+// `memory-ok` once it has come back down, checking every `interval`.
+pub fn watch_memory(events: Arc<EventLog>, sampler: Arc<Sampler>, percent: f64, interval: Duration) {
+    let mut warned = false;
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let snapshot = sampler.latest();
+        if snapshot.total_memory == 0 {
+            continue;
+        }
+        let used = snapshot.used_memory as f64 / snapshot.total_memory as f64 * 100.0;
+        let data = json!({
+            "used_percent": (used * 10.0).round() / 10.0,
+            "threshold_percent": percent,
+        });
+        if !warned && used >= percent {
+            warned = true;
+            events.publish("memory-warning", data);
+        } else if warned && used < percent - MEMORY_HYSTERESIS {
+            warned = false;
+            events.publish("memory-ok", data);
+        }
+    });
+}
+
+// One `/events` client. Starts with the events after `last_id` and then
+// sends new ones as they are published, a snapshot every `stats_interval`
+// when the sampler has a new one, and a comment whenever `heartbeat` passes
+// without anything else. Ends once the server starts draining, after the
+// `draining` event has gone out.
+pub struct EventStream {
+    events: Arc<EventLog>,
+    sampler: Arc<Sampler>,
+    last_id: u64,
+    stats_interval: Duration,
+    heartbeat: Duration,
+    next_stats: Instant,
+    last_stats: Option<SystemTime>,
+    last_sent: Option<Instant>,
+}
+
+impl EventStream {
+    // `last_id` is the client's `Last-Event-ID`; without one it only gets
+    // events published from now on.
+    pub fn new(
+        events: Arc<EventLog>,
+        sampler: Arc<Sampler>,
+        last_id: Option<u64>,
+        stats_interval: Duration,
+        heartbeat: Duration,
+    ) -> EventStream {
+        let last_id = last_id.unwrap_or_else(|| events.latest_id());
+        EventStream {
+            events,
+            sampler,
+            last_id,
+            stats_interval,
+            heartbeat,
+            next_stats: Instant::now(),
+            last_stats: None,
+            last_sent: None,
+        }
+    }
+}
+
+impl Feed for EventStream {
+    fn poll(&mut self, now: Instant, out: &mut Vec<u8>) -> bool {
+        // Checked first: `draining` is published before the flag is set, so
+        // it is in the ring by the time the stream sees the flag.
+        let ending = shutdown::draining();
+        let start = out.len();
+        if self.last_sent.is_none() {
+            out.extend_from_slice(format!("retry: {}\n\n", RETRY_MS).as_bytes());
+        }
+        for event in self.events.since(self.last_id) {
+            write_event(out, Some(event.id), event.kind, &event.data);
+            self.last_id = event.id;
+        }
+        let snapshot = self.sampler.latest();
+        if now >= self.next_stats && self.last_stats != Some(snapshot.taken_at) {
+            self.next_stats = now + self.stats_interval;
+            self.last_stats = Some(snapshot.taken_at);
+            if let Ok(data) = serde_json::to_string(&Stats::from_snapshot(&snapshot)) {
+                write_event(out, None, "stats", &data);
+            }
+        }
+        if out.len() == start && self.last_sent.is_some_and(|at| now.duration_since(at) >= self.heartbeat) {
+            out.extend_from_slice(b": heartbeat\n\n");
+        }
+        if out.len() > start {
+            self.last_sent = Some(now);
+        }
+        !ending
+    }
+}
+
+// One event in `text/event-stream` form. Each line of `data` becomes a
+// `data:` field, which the client joins back up with newlines.
+fn write_event(out: &mut Vec<u8>, id: Option<u64>, kind: &str, data: &str) {
+    if let Some(id) = id {
+        out.extend_from_slice(format!("id: {}\n", id).as_bytes());
+    }
+    out.extend_from_slice(format!("event: {}\n", kind).as_bytes());
+    for line in data.split('\n') {
+        out.extend_from_slice(format!("data: {}\n", line.strip_suffix('\r').unwrap_or(line)).as_bytes());
+    }
+    out.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(events: &[Arc<Event>]) -> Vec<u64> {
+        events.iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn keeps_the_newest_events() {
+        let log = EventLog::new(3);
+        assert_eq!(log.latest_id(), 0);
+        for n in 0..5 {
+            log.publish("test", json!({ "n": n }));
+        }
+        assert_eq!(log.latest_id(), 5);
+        assert_eq!(ids(&log.since(0)), [3, 4, 5]);
+        assert_eq!(log.since(4)[0].data, r#"{"n":4}"#);
+    }
+
+    #[test]
+    fn resumes_after_the_last_event_seen() {
+        let log = EventLog::new(10);
+        for _ in 0..4 {
+            log.publish("test", Value::Null);
+        }
+        assert_eq!(ids(&log.since(2)), [3, 4]);
+        assert!(log.since(4).is_empty());
+        // From before a restart.
+        assert_eq!(ids(&log.since(9)), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn formats_events() {
+        let mut out = Vec::new();
+        write_event(&mut out, Some(7), "reload", r#"{"certificates":2}"#);
+        write_event(&mut out, None, "note", "one\r\ntwo");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "id: 7\nevent: reload\ndata: {\"certificates\":2}\n\nevent: note\ndata: one\ndata: two\n\n"
+        );
+    }
+}
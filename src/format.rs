@@ -0,0 +1,148 @@
+// Human-readable rendering of the numbers shown on the stats page and in logs.
+// sysinfo reports memory in bytes; sizes are shown in IEC units (KiB = 1024),
+// counts and rates of anything other than bytes in SI units (k = 1000).
+
+const IEC_UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+const SI_PREFIXES: [&str; 5] = ["", "k", "M", "G", "T"];
+
+// 1536 -> "1.5 KiB". Values below 1 KiB are shown exactly.
+pub fn bytes(n: u64) -> String {
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let (value, unit) = scale(n as f64, 1024.0, &IEC_UNITS);
+    format!("{:.1} {}", value, unit)
+}
+
+// 1536.0 -> "1.5 KiB/s".
+pub fn byte_rate(bytes_per_sec: f64) -> String {
+    if bytes_per_sec < 1024.0 {
+        return format!("{:.0} B/s", bytes_per_sec);
+    }
+    let (value, unit) = scale(bytes_per_sec, 1024.0, &IEC_UNITS);
+    format!("{:.1} {}/s", value, unit)
+}
+
+// 1500.0, "req" -> "1.5k req/s".
+pub fn rate(per_sec: f64, unit: &str) -> String {
+    let (value, prefix) = scale(per_sec, 1000.0, &SI_PREFIXES);
+    format!("{:.1}{} {}/s", value, prefix, unit)
+}
+
+// 12.345 -> "12.3%".
+pub fn percent(value: f64) -> String {
+    format!("{:.1}%", value)
+}
+
+// Shows the two most significant units: 90061 -> "1d 1h", 61 -> "1m 1s".
+pub fn duration(secs: u64) -> String {
+    let parts = [
+        (secs / 86_400, "d"),
+        (secs / 3_600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+        (secs % 60, "s"),
+    ];
+    match parts.iter().position(|(n, _)| *n > 0) {
+        Some(i) if i < parts.len() - 1 => {
+            format!("{}{} {}{}", parts[i].0, parts[i].1, parts[i + 1].0, parts[i + 1].1)
+        }
+        _ => format!("{}s", secs),
+    }
+}
+
+// Unix seconds -> "2024-01-03 09:30:00 UTC".
+pub fn timestamp(unix_secs: u64) -> String {
+    let (days, secs) = (unix_secs / 86_400, unix_secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3_600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian (year, month, day), after
+// Howard Hinnant's `civil_from_days`.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn scale<'a>(mut value: f64, step: f64, units: &[&'a str]) -> (f64, &'a str) {
+    let mut i = 0;
+    while value >= step && i < units.len() - 1 {
+        value /= step;
+        i += 1;
+    }
+    (value, units[i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_use_iec_units() {
+        assert_eq!(bytes(0), "0 B");
+        assert_eq!(bytes(1023), "1023 B");
+        assert_eq!(bytes(1024), "1.0 KiB");
+        assert_eq!(bytes(1536), "1.5 KiB");
+        assert_eq!(bytes(1024 * 1024), "1.0 MiB");
+        assert_eq!(bytes(6_294_937_600), "5.9 GiB");
+        assert_eq!(bytes(u64::MAX), "16.0 EiB");
+    }
+
+    #[test]
+    fn byte_rates_use_iec_units() {
+        assert_eq!(byte_rate(0.0), "0 B/s");
+        assert_eq!(byte_rate(512.4), "512 B/s");
+        assert_eq!(byte_rate(1536.0), "1.5 KiB/s");
+        assert_eq!(byte_rate(3.0 * 1024.0 * 1024.0), "3.0 MiB/s");
+    }
+
+    #[test]
+    fn rates_use_si_prefixes() {
+        assert_eq!(rate(0.0, "req"), "0.0 req/s");
+        assert_eq!(rate(999.0, "req"), "999.0 req/s");
+        assert_eq!(rate(1000.0, "req"), "1.0k req/s");
+        assert_eq!(rate(1_500_000.0, "req"), "1.5M req/s");
+    }
+
+    #[test]
+    fn percentages_have_one_decimal() {
+        assert_eq!(percent(0.0), "0.0%");
+        assert_eq!(percent(12.345), "12.3%");
+        assert_eq!(percent(100.0), "100.0%");
+    }
+
+    #[test]
+    fn durations_show_two_units() {
+        assert_eq!(duration(0), "0s");
+        assert_eq!(duration(59), "59s");
+        assert_eq!(duration(60), "1m 0s");
+        assert_eq!(duration(61), "1m 1s");
+        assert_eq!(duration(3_600), "1h 0m");
+        assert_eq!(duration(3_661), "1h 1m");
+        assert_eq!(duration(86_400), "1d 0h");
+        assert_eq!(duration(90_061), "1d 1h");
+    }
+
+    #[test]
+    fn timestamps_are_utc() {
+        assert_eq!(timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(timestamp(1_704_274_200), "2024-01-03 09:30:00 UTC");
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::metrics::Metrics;
+use crate::stats::Sampler;
+
+// A fixed-size, in-memory time series of a few figures, kept at several
+// resolutions: every second for the last few minutes, say, and every minute
+// for the last day. Each tier averages the samples that fall into one of its
+// steps and drops its oldest point once full, so memory use never grows.
+
+// How often a sample is recorded; the finest step there can be.
+const SAMPLE_EVERY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    // Used memory, in bytes.
+    Memory,
+    // CPU usage averaged over all cores, in percent.
+    Cpu,
+    // One-minute load average.
+    Load,
+    // Bytes this server's own sockets received and sent, per second; not
+    // the host's network traffic.
+    ServerBytesIn,
+    ServerBytesOut,
+    // Requests answered per second.
+    Requests,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 6] = [
+        Metric::Memory,
+        Metric::Cpu,
+        Metric::Load,
+        Metric::ServerBytesIn,
+        Metric::ServerBytesOut,
+        Metric::Requests,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::Memory => "memory",
+            Metric::Cpu => "cpu",
+            Metric::Load => "load",
+            Metric::ServerBytesIn => "server_bytes_in",
+            Metric::ServerBytesOut => "server_bytes_out",
+            Metric::Requests => "requests",
+        }
+    }
+
+    pub fn unit(self) -> &'static str {
+        match self {
+            Metric::Memory => "bytes",
+            Metric::Cpu => "%",
+            Metric::Load => "",
+            Metric::ServerBytesIn | Metric::ServerBytesOut => "bytes/s",
+            Metric::Requests => "req/s",
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Metric, ()> {
+        Metric::ALL.into_iter().find(|m| m.name() == s).ok_or(())
+    }
+}
+
+// One tier: points `step` seconds apart, as many as cover `span` seconds.
+// Written as "1s:10m", with s, m, h or d for units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    pub step: u64,
+    pub span: u64,
+}
+
+impl FromStr for Resolution {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Resolution, ()> {
+        let (step, span) = s.split_once(':').ok_or(())?;
+        let (step, span) = (parse_secs(step)?, parse_secs(span)?);
+        if step == 0 || span < step {
+            return Err(());
+        }
+        Ok(Resolution { step, span })
+    }
+}
+
+fn parse_secs(s: &str) -> Result<u64, ()> {
+    let s = s.trim();
+    let unit = match s.chars().last().ok_or(())? {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86_400,
+        _ => return Err(()),
+    };
+    let n: u64 = s[..s.len() - 1].parse().map_err(|_| ())?;
+    n.checked_mul(unit).ok_or(())
+}
+
+// The average of each metric over the step starting at `time` (Unix
+// seconds), indexed by `Metric`.
+#[derive(Debug, Clone, Copy)]
+struct Point {
+    time: u64,
+    values: [f64; Metric::ALL.len()],
+}
+
+struct Tier {
+    step: u64,
+    capacity: usize,
+    points: VecDeque<Point>,
+    // The step still being filled, as sums and a count of samples.
+    open: Option<(Point, u32)>,
+}
+
+impl Tier {
+    fn record(&mut self, time: u64, values: [f64; Metric::ALL.len()]) {
+        let start = time - time % self.step;
+        match &mut self.open {
+            Some((sums, count)) if sums.time == start => {
+                for (sum, value) in sums.values.iter_mut().zip(values) {
+                    *sum += value;
+                }
+                *count += 1;
+                return;
+            }
+            _ => {}
+        }
+        if let Some((mut sums, count)) = self.open.take() {
+            for sum in &mut sums.values {
+                *sum /= count as f64;
+            }
+            if self.points.len() == self.capacity {
+                self.points.pop_front();
+            }
+            self.points.push_back(sums);
+        }
+        self.open = Some((Point { time: start, values }, 1));
+    }
+}
+
+// A metric's points between two times, `step` seconds apart.
+pub struct Series {
+    pub step: u64,
+    pub points: Vec<(u64, f64)>,
+}
+
+pub struct History {
+    // Finest first.
+    tiers: Mutex<Vec<Tier>>,
+}
+
+impl History {
+    pub fn new(resolutions: &[Resolution]) -> Arc<History> {
+        let mut tiers: Vec<Tier> = resolutions
+            .iter()
+            .map(|r| Tier {
+                step: r.step,
+                capacity: (r.span / r.step) as usize,
+                points: VecDeque::new(),
+                open: None,
+            })
+            .collect();
+        tiers.sort_by_key(|t| t.step);
+        Arc::new(History { tiers: Mutex::new(tiers) })
+    }
+
+    // Adds a sample taken at `time` (Unix seconds) to every tier. A step
+    // only shows up in queries once a sample from a later one arrives.
+    pub fn record(&self, time: u64, values: [f64; Metric::ALL.len()]) {
+        for tier in self.tiers.lock().unwrap().iter_mut() {
+            tier.record(time, values);
+        }
+    }
+
+    // The points of `metric` from `from` to `to` (Unix seconds, inclusive),
+    // taken from the finest tier that reaches back to `from`, or else the
+    // one that reaches back furthest. Points are averaged into steps of
+    // `step` seconds, rounded up to a multiple of the tier's own but no more
+    // than the tier spans.
+    pub fn query(&self, metric: Metric, from: u64, to: u64, step: u64) -> Series {
+        let tiers = self.tiers.lock().unwrap();
+        let reaches = |t: &&Tier| t.points.front().is_some_and(|p| p.time <= from);
+        let oldest = |t: &&Tier| t.points.front().map_or(u64::MAX, |p| p.time);
+        let tier = match tiers.iter().find(reaches).or_else(|| tiers.iter().min_by_key(oldest)) {
+            Some(tier) => tier,
+            None => return Series { step, points: Vec::new() },
+        };
+        let step = step.clamp(tier.step, tier.step * tier.capacity.max(1) as u64).div_ceil(tier.step) * tier.step;
+
+        let mut points = Vec::new();
+        let mut bucket: Option<(u64, f64, u32)> = None;
+        for point in tier.points.iter().filter(|p| p.time >= from && p.time <= to) {
+            let start = point.time - point.time % step;
+            let value = point.values[metric as usize];
+            match &mut bucket {
+                Some((time, sum, count)) if *time == start => {
+                    *sum += value;
+                    *count += 1;
+                }
+                _ => {
+                    if let Some((time, sum, count)) = bucket {
+                        points.push((time, sum / count as f64));
+                    }
+                    bucket = Some((start, value, 1));
+                }
+            }
+        }
+        if let Some((time, sum, count)) = bucket {
+            points.push((time, sum / count as f64));
+        }
+        Series { step, points }
+    }
+
+    // Everything the finest tier holds for `metric`, and its step.
+    pub fn recent(&self, metric: Metric) -> Series {
+        self.query(metric, 0, u64::MAX, 1)
+    }
+
+    // Records a sample every second from a background thread: the latest
+    // snapshot, and the server's traffic and requests since the last one.
+    pub fn start(self: &Arc<History>, sampler: Arc<Sampler>, metrics: Arc<Metrics>) {
+        let history = Arc::clone(self);
+        thread::Builder::new()
+            .name("stats-history".to_string())
+            .spawn(move || {
+                let counters = |m: &Metrics| [m.bytes_in(), m.bytes_out(), m.requests_total()];
+                let mut last = (Instant::now(), counters(&metrics));
+                loop {
+                    thread::sleep(SAMPLE_EVERY);
+                    let now = (Instant::now(), counters(&metrics));
+                    let secs = now.0.duration_since(last.0).as_secs_f64();
+                    let rate = |i: usize| now.1[i].saturating_sub(last.1[i]) as f64 / secs;
+                    let snapshot = sampler.latest();
+                    let values = [
+                        snapshot.used_memory as f64,
+                        snapshot.cpu_usage as f64,
+                        snapshot.load_average[0],
+                        rate(0),
+                        rate(1),
+                        rate(2),
+                    ];
+                    last = now;
+                    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                    history.record(time, values);
+                }
+            })
+            .expect("failed to spawn stats history");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(resolutions: &str) -> Arc<History> {
+        let resolutions: Vec<Resolution> = resolutions.split(',').map(|r| r.parse().unwrap()).collect();
+        History::new(&resolutions)
+    }
+
+    // Records `cpu` at each second from `start`, one sample per value.
+    fn record(history: &History, start: u64, cpu: &[f64]) {
+        for (i, value) in cpu.iter().enumerate() {
+            let mut values = [0.0; Metric::ALL.len()];
+            values[Metric::Cpu as usize] = *value;
+            history.record(start + i as u64, values);
+        }
+    }
+
+    #[test]
+    fn parses_resolutions() {
+        assert_eq!("1s:10m".parse(), Ok(Resolution { step: 1, span: 600 }));
+        assert_eq!("1m:24h".parse(), Ok(Resolution { step: 60, span: 86_400 }));
+        assert_eq!("5m:7d".parse(), Ok(Resolution { step: 300, span: 604_800 }));
+        for bad in ["1s", "0s:1m", "1m:1s", "1x:1m", "s:1m", "1s:"] {
+            assert_eq!(bad.parse::<Resolution>(), Err(()), "{}", bad);
+        }
+        assert_eq!("server_bytes_in".parse(), Ok(Metric::ServerBytesIn));
+        assert_eq!("disk".parse::<Metric>(), Err(()));
+    }
+
+    #[test]
+    fn averages_samples_into_steps() {
+        let history = history("1s:5s,2s:10s");
+        record(&history, 100, &[1.0, 3.0, 5.0, 7.0, 9.0]);
+        let fine = history.recent(Metric::Cpu);
+        // The last second is still open.
+        assert_eq!(fine.step, 1);
+        assert_eq!(fine.points, [(100, 1.0), (101, 3.0), (102, 5.0), (103, 7.0)]);
+        let coarse = history.query(Metric::Cpu, 0, u64::MAX, 2);
+        assert_eq!(coarse.step, 2);
+        assert_eq!(coarse.points, [(100, 2.0), (102, 6.0)]);
+    }
+
+    #[test]
+    fn drops_the_oldest_points() {
+        let history = history("1s:3s");
+        record(&history, 0, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(history.recent(Metric::Cpu).points, [(2, 3.0), (3, 4.0), (4, 5.0)]);
+    }
+
+    #[test]
+    fn limits_steps_to_the_tier_span() {
+        let history = history("1s:1m,1m:1h");
+        record(&history, 0, &vec![1.0; 200]);
+        let all = history.query(Metric::Cpu, 0, u64::MAX, u64::MAX);
+        assert_eq!(all.step, 3600);
+        assert_eq!(all.points, [(0, 1.0)]);
+    }
+
+    #[test]
+    fn picks_the_tier_that_reaches_back_far_enough() {
+        let history = history("1m:1h,1s:1m");
+        record(&history, 0, &vec![1.0; 200]);
+        // The last minute is still in the 1s tier.
+        let recent = history.query(Metric::Cpu, 150, 170, 5);
+        assert_eq!(recent.step, 5);
+        assert_eq!(recent.points.len(), 5);
+        // Only the 1m tier goes back to the start.
+        let all = history.query(Metric::Cpu, 0, u64::MAX, 1);
+        assert_eq!(all.step, 60);
+        assert_eq!(all.points, [(0, 1.0), (60, 1.0), (120, 1.0)]);
+    }
+}
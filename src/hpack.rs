@@ -0,0 +1,504 @@
+// HPACK (RFC 7541), the header compression of HTTP/2. The decoder keeps the
+// dynamic table the client's encoder builds up, within the size we allow.
+// The encoder never adds to a table of its own: response headers are sent as
+// static-table references and literals, which costs a few bytes per response
+// but leaves no state to keep in step with the client.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::OnceLock;
+
+// Header fields as octets; HTTP/2 checks them before they become strings.
+pub type Field = (Vec<u8>, Vec<u8>);
+
+// A header block that can't be decoded. The dynamic table can no longer be
+// trusted after one, so it ends the connection (COMPRESSION_ERROR).
+#[derive(Debug, PartialEq, Eq)]
+pub struct Error(&'static str);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+// Each table entry is charged its name and value plus this much.
+const ENTRY_OVERHEAD: usize = 32;
+
+pub struct Decoder {
+    // Newest entry first, as indices count from it.
+    table: VecDeque<Field>,
+    size: usize,
+    // The size the encoder has chosen, and the most it may choose
+    // (SETTINGS_HEADER_TABLE_SIZE).
+    max_size: usize,
+    limit: usize,
+}
+
+impl Decoder {
+    pub fn new(limit: usize) -> Decoder {
+        Decoder { table: VecDeque::new(), size: 0, max_size: limit, limit }
+    }
+
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<Field>, Error> {
+        let mut input = Input { data: block, pos: 0 };
+        let mut fields = Vec::new();
+        while let Some(first) = input.peek() {
+            if first & 0x80 != 0 {
+                let index = input.integer(7)?;
+                fields.push(self.get(index)?);
+            } else if first & 0x40 != 0 {
+                let field = self.literal(&mut input, 6)?;
+                self.insert(field.clone());
+                fields.push(field);
+            } else if first & 0x20 != 0 {
+                // Size updates only come before the first field.
+                if !fields.is_empty() {
+                    return Err(Error("table size update after a header field"));
+                }
+                let size = input.integer(5)?;
+                if size > self.limit {
+                    return Err(Error("table size update above the limit"));
+                }
+                self.max_size = size;
+                self.evict(0);
+            } else {
+                // Without indexing (0000) or never indexed (0001); neither
+                // touches the table.
+                fields.push(self.literal(&mut input, 4)?);
+            }
+        }
+        Ok(fields)
+    }
+
+    fn literal(&self, input: &mut Input, prefix: u8) -> Result<Field, Error> {
+        let index = input.integer(prefix)?;
+        let name = match index {
+            0 => input.string()?,
+            index => self.get(index)?.0,
+        };
+        Ok((name, input.string()?))
+    }
+
+    fn get(&self, index: usize) -> Result<Field, Error> {
+        match index {
+            0 => Err(Error("index 0")),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            index => self.table.get(index - 62).cloned().ok_or(Error("index past the dynamic table")),
+        }
+    }
+
+    fn insert(&mut self, field: Field) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        // An entry larger than the whole table empties it and isn't added.
+        self.evict(size);
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    // Drops the oldest entries until `room` more octets fit.
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Input<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        let b = self.peek().ok_or(Error("truncated header block"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    // An integer in the low `prefix` bits of the current octet, continued in
+    // 7-bit groups while the prefix is all ones (section 5.1).
+    fn integer(&mut self, prefix: u8) -> Result<usize, Error> {
+        let max = (1usize << prefix) - 1;
+        let mut value = usize::from(self.byte()?) & max;
+        if value < max {
+            return Ok(value);
+        }
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            // Nothing legitimate needs more than 28 bits.
+            if shift > 21 {
+                return Err(Error("integer too large"));
+            }
+            value += usize::from(b & 0x7f) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, Error> {
+        let huffman = self.peek().ok_or(Error("truncated header block"))? & 0x80 != 0;
+        let len = self.integer(7)?;
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len()).ok_or(Error("truncated string"))?;
+        let raw = &self.data[self.pos..end];
+        self.pos = end;
+        if huffman {
+            huffman_decode(raw)
+        } else {
+            Ok(raw.to_vec())
+        }
+    }
+}
+
+// Appends the header block for `fields`, whose names have to be lowercase
+// already.
+pub fn encode(fields: &[(&str, &str)], out: &mut Vec<u8>) {
+    for (name, value) in fields {
+        if let Some(i) = STATIC_TABLE.iter().position(|(n, v)| n == name && v == value) {
+            integer(i + 1, 7, 0x80, out);
+            continue;
+        }
+        // Literal without indexing, naming a static entry where there is one.
+        let name_index = STATIC_TABLE.iter().position(|(n, _)| n == name).map_or(0, |i| i + 1);
+        integer(name_index, 4, 0x00, out);
+        if name_index == 0 {
+            string(name.as_bytes(), out);
+        }
+        string(value.as_bytes(), out);
+    }
+}
+
+fn integer(value: usize, prefix: u8, flags: u8, out: &mut Vec<u8>) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        out.push(0x80 | (rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    out.push(rest as u8);
+}
+
+// Huffman-coded when that is shorter, as it is for most text.
+fn string(s: &[u8], out: &mut Vec<u8>) {
+    let bits: usize = s.iter().map(|b| usize::from(HUFFMAN[usize::from(*b)].1)).sum();
+    let len = bits.div_ceil(8);
+    if len >= s.len() {
+        integer(s.len(), 7, 0x00, out);
+        out.extend_from_slice(s);
+        return;
+    }
+    integer(len, 7, 0x80, out);
+    let (mut acc, mut n) = (0u64, 0u32);
+    for b in s {
+        let (code, code_len) = HUFFMAN[usize::from(*b)];
+        acc = acc << code_len | u64::from(code);
+        n += u32::from(code_len);
+        while n >= 8 {
+            n -= 8;
+            out.push((acc >> n) as u8);
+        }
+    }
+    // Padded with the most significant bits of EOS, which are all ones.
+    if n > 0 {
+        out.push((acc << (8 - n)) as u8 | (0xff >> n));
+    }
+}
+
+fn huffman_decode(input: &[u8]) -> Result<Vec<u8>, Error> {
+    let tree = huffman_tree();
+    let mut out = Vec::with_capacity(input.len() * 8 / 5);
+    let mut node = 0;
+    // Bits read since the last symbol, and whether they were all ones.
+    let mut depth = 0;
+    let mut ones = true;
+    for byte in input {
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 1;
+            match tree[node][usize::from(bit)] {
+                Node::Branch(next) => {
+                    node = next;
+                    depth += 1;
+                    ones &= bit == 1;
+                }
+                Node::Leaf(256) => return Err(Error("EOS in a Huffman string")),
+                Node::Leaf(symbol) => {
+                    out.push(symbol as u8);
+                    node = 0;
+                    depth = 0;
+                    ones = true;
+                }
+                Node::Missing => return Err(Error("invalid Huffman code")),
+            }
+        }
+    }
+    // Padding is a prefix of EOS, so all ones, and shorter than a byte.
+    if depth > 7 || !ones {
+        return Err(Error("invalid Huffman padding"));
+    }
+    Ok(out)
+}
+
+#[derive(Clone, Copy)]
+enum Node {
+    Branch(usize),
+    Leaf(u16),
+    Missing,
+}
+
+// The code as a binary tree: each node's children for a 0 and a 1 bit.
+fn huffman_tree() -> &'static [[Node; 2]] {
+    static TREE: OnceLock<Vec<[Node; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[Node::Missing; 2]];
+        for (symbol, (code, len)) in HUFFMAN.iter().enumerate() {
+            let mut node = 0;
+            for i in (0..*len).rev() {
+                let bit = ((code >> i) & 1) as usize;
+                if i == 0 {
+                    tree[node][bit] = Node::Leaf(symbol as u16);
+                } else {
+                    node = match tree[node][bit] {
+                        Node::Branch(next) => next,
+                        _ => {
+                            tree.push([Node::Missing; 2]);
+                            tree[node][bit] = Node::Branch(tree.len() - 1);
+                            tree.len() - 1
+                        }
+                    };
+                }
+            }
+        }
+        tree
+    })
+}
+
+// Appendix A.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// Appendix B: the code for each octet, and for EOS (256), with its length
+// in bits.
+const HUFFMAN: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28), (0xfffffe5, 28),
+    (0xfffffe6, 28), (0xfffffe7, 28), (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28), (0xfffffed, 28), (0xfffffee, 28),
+    (0xfffffef, 28), (0xffffff0, 28), (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28),
+    (0xffffffa, 28), (0xffffffb, 28), (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12), (0x1ff9, 13), (0x15, 6),
+    (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6),
+    (0x18, 6), (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6), (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6), (0x1e, 6),
+    (0x1f, 6), (0x5c, 7), (0xfb, 8), (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10), (0x1ffa, 13), (0x21, 6),
+    (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7), (0x63, 7), (0x64, 7), (0x65, 7),
+    (0x66, 7), (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19),
+    (0x1ffc, 13), (0x3ffc, 14), (0x22, 6), (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5),
+    (0x25, 6), (0x26, 6), (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7), (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7), (0x79, 7), (0x7a, 7),
+    (0x7b, 7), (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28), (0xfffe6, 20),
+    (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20), (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22),
+    (0x7fffd9, 23), (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23),
+    (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23), (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22),
+    (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23), (0x7fffe4, 23),
+    (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23), (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23),
+    (0xffffef, 24), (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22),
+    (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21), (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22),
+    (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23), (0x1fffe0, 21),
+    (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21), (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23),
+    (0x7fffef, 23), (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23),
+    (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23), (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20),
+    (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25), (0x3ffffe2, 26),
+    (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27), (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24),
+    (0x1ffffed, 25), (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27), (0x7ffffe1, 27),
+    (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24), (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26),
+    (0x3ffffe9, 26), (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27), (0xfffec, 20),
+    (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21), (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21),
+    (0x7ffff3, 23), (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24),
+    (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23), (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26),
+    (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27), (0x7ffffeb, 27),
+    (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27), (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27),
+    (0x3ffffee, 26), (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn strings(fields: Vec<Field>) -> Vec<(String, String)> {
+        fields
+            .into_iter()
+            .map(|(n, v)| (String::from_utf8(n).unwrap(), String::from_utf8(v).unwrap()))
+            .collect()
+    }
+
+    fn pairs(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    // Appendix C.3 and C.4: the same three requests, without and with
+    // Huffman coding, sharing one dynamic table per connection.
+    #[test]
+    fn decodes_request_examples() {
+        let first = [(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")];
+        let second = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+            ("cache-control", "no-cache"),
+        ];
+        let third = [
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ];
+        let plain = [
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ];
+        let huffman = [
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ];
+        for blocks in [plain, huffman] {
+            let mut decoder = Decoder::new(4096);
+            assert_eq!(strings(decoder.decode(&hex(blocks[0])).unwrap()), pairs(&first));
+            assert_eq!(strings(decoder.decode(&hex(blocks[1])).unwrap()), pairs(&second));
+            assert_eq!(strings(decoder.decode(&hex(blocks[2])).unwrap()), pairs(&third));
+            assert_eq!(decoder.size, 164);
+        }
+    }
+
+    #[test]
+    fn evicts_to_the_table_size() {
+        let mut decoder = Decoder::new(4096);
+        // Size update to 64 octets, then two literals with indexing.
+        let block = hex("3f21 4003 6f6e 6503 6161 6140 0374 776f 0362 6262");
+        decoder.decode(&block).unwrap();
+        assert_eq!(decoder.table.len(), 1);
+        assert_eq!(decoder.get(62).unwrap(), (b"two".to_vec(), b"bbb".to_vec()));
+        assert!(decoder.get(63).is_err());
+        // Above the limit, and after a field.
+        assert!(Decoder::new(4096).decode(&hex("3fe2 1f")).is_err());
+        assert!(Decoder::new(4096).decode(&hex("8220")).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let mut decoder = Decoder::new(4096);
+        assert!(decoder.decode(&hex("80")).is_err());
+        assert!(decoder.decode(&hex("ff00")).is_err());
+        assert!(decoder.decode(&hex("0003 6162")).is_err());
+        assert!(decoder.decode(&hex("ffff ffff ff0f")).is_err());
+        // Padding longer than 7 bits, and padding that isn't all ones.
+        assert!(huffman_decode(&hex("1fff")).is_err());
+        assert!(huffman_decode(&hex("1e")).is_err());
+        assert_eq!(huffman_decode(&hex("1f")).unwrap(), b"a");
+    }
+
+    #[test]
+    fn round_trips_encoded_fields() {
+        let fields = [
+            (":status", "200"),
+            (":status", "418"),
+            ("content-type", "text/plain; charset=UTF-8"),
+            ("x-empty", ""),
+            ("x-binary", "\u{1}\u{7f}"),
+        ];
+        let mut block = Vec::new();
+        encode(&fields, &mut block);
+        assert_eq!(block[0], 0x88);
+        let mut decoder = Decoder::new(4096);
+        assert_eq!(strings(decoder.decode(&block).unwrap()), pairs(&fields));
+        assert!(decoder.table.is_empty());
+    }
+}
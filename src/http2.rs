@@ -0,0 +1,1261 @@
+// HTTP/2 (RFC 9113), negotiated with ALPN "h2" over TLS or started with the
+// connection preface on the plain port (h2c with prior knowledge). Requests
+// go through the same router and response pipeline as HTTP/1; only the
+// framing differs.
+//
+// `Connection` is the protocol state for one connection and does no IO: it
+// takes the bytes read from the socket and produces the bytes to send, so
+// the worker threads and the event loop drive it the same way, through
+// `process`. Handlers run one at a time as their requests complete, and the
+// responses' DATA frames are interleaved as the flow-control windows allow.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::connection;
+use crate::hpack::{self, Field};
+use crate::metrics::Metrics;
+use crate::request::{HeaderMap, Method, ParseError, Request, Version, MAX_BODY, MAX_HEADERS};
+use crate::response::{Body, Feed, Response, TICK};
+use crate::router::{Router, UNMATCHED};
+use crate::shutdown;
+use crate::tls::TlsInfo;
+
+// What a client sends first, before its SETTINGS frame.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types.
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags.
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Setting identifiers.
+const HEADER_TABLE_SIZE: u16 = 0x1;
+const ENABLE_PUSH: u16 = 0x2;
+const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const INITIAL_WINDOW_SIZE: u16 = 0x4;
+const MAX_FRAME_SIZE: u16 = 0x5;
+const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+const FRAME_HEADER: usize = 9;
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+const MIN_FRAME_SIZE: u32 = 16_384;
+const MAX_FRAME_SIZE_LIMIT: u32 = (1 << 24) - 1;
+// The dynamic table size the client's encoder may use.
+const DECODER_TABLE_SIZE: usize = 4_096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    NoError = 0x0,
+    Protocol = 0x1,
+    Internal = 0x2,
+    FlowControl = 0x3,
+    StreamClosed = 0x5,
+    FrameSize = 0x6,
+    RefusedStream = 0x7,
+    Compression = 0x9,
+    EnhanceYourCalm = 0xb,
+}
+
+// A connection error: what is sent in the GOAWAY, and why.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({:?})", self.reason, self.code)
+    }
+}
+
+fn error(code: ErrorCode, reason: &'static str) -> Error {
+    Error { code, reason }
+}
+
+// What we advertise in our SETTINGS frame, and enforce.
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub max_concurrent_streams: u32,
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+    pub max_header_list_size: u32,
+    // Streams accepted before the connection is wound down with GOAWAY.
+    pub max_requests: usize,
+}
+
+impl Settings {
+    pub fn new(config: &Config) -> Settings {
+        Settings {
+            max_concurrent_streams: config.h2_max_concurrent_streams,
+            // Below the default, a client could overrun the window before it
+            // has seen our SETTINGS.
+            initial_window_size: config.h2_initial_window_size.clamp(DEFAULT_WINDOW as u32, MAX_WINDOW as u32),
+            max_frame_size: config.h2_max_frame_size.clamp(MIN_FRAME_SIZE, MAX_FRAME_SIZE_LIMIT),
+            max_header_list_size: config.h2_max_header_list_size,
+            max_requests: config.max_requests_per_connection,
+        }
+    }
+}
+
+// `Some(true)` if `buf` starts with the HTTP/2 preface, `Some(false)` if it
+// can't, and `None` while it is too short to tell.
+pub fn is_preface(buf: &[u8]) -> Option<bool> {
+    let n = buf.len().min(PREFACE.len());
+    if buf[..n] != PREFACE[..n] {
+        Some(false)
+    } else if n == PREFACE.len() {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+struct Stream {
+    // The request while its body arrives; taken once it is complete.
+    request: Option<Request>,
+    content_length: Option<usize>,
+    // The client has sent END_STREAM.
+    remote_closed: bool,
+    // The request was answered with an error before its body was read; any
+    // more DATA is dropped.
+    rejected: bool,
+    recv_window: i64,
+    send_window: i64,
+    // Response body still to go out in DATA frames.
+    pending: Option<Pending>,
+}
+
+struct Pending {
+    body: Body,
+    sent: u64,
+    len: u64,
+    // For a feed, `body` holds what it has produced and not yet sent.
+    feed: Option<Box<dyn Feed>>,
+}
+
+impl Pending {
+    // Whether there is DATA to send: more of the body, or the end of a feed.
+    fn ready(&self) -> bool {
+        self.sent < self.len || self.feed.is_none()
+    }
+}
+
+// A header block whose CONTINUATION frames are still to come.
+struct PartialBlock {
+    stream: u32,
+    block: Vec<u8>,
+    end_stream: bool,
+}
+
+pub struct Connection {
+    settings: Settings,
+    preface_received: bool,
+    decoder: hpack::Decoder,
+    peer_max_frame_size: usize,
+    peer_initial_window: i64,
+    send_window: i64,
+    recv_window: i64,
+    streams: BTreeMap<u32, Stream>,
+    last_stream_id: u32,
+    // Where the last DATA frame went, so streams take turns.
+    last_sent: u32,
+    partial: Option<PartialBlock>,
+    // Complete requests, or why one was rejected, waiting for a handler.
+    ready: VecDeque<(u32, Result<Request, ParseError>)>,
+    accepted: usize,
+    // Either side has sent GOAWAY: no new streams, and the connection ends
+    // once the open ones are done.
+    going_away: bool,
+    failed: bool,
+    out: Vec<u8>,
+}
+
+impl Connection {
+    // Starts with our SETTINGS, which is the server's half of the preface.
+    pub fn new(settings: Settings) -> Connection {
+        let mut conn = Connection {
+            settings,
+            preface_received: false,
+            decoder: hpack::Decoder::new(DECODER_TABLE_SIZE),
+            peer_max_frame_size: MIN_FRAME_SIZE as usize,
+            peer_initial_window: DEFAULT_WINDOW,
+            send_window: DEFAULT_WINDOW,
+            recv_window: DEFAULT_WINDOW,
+            streams: BTreeMap::new(),
+            last_stream_id: 0,
+            last_sent: 0,
+            partial: None,
+            ready: VecDeque::new(),
+            accepted: 0,
+            going_away: false,
+            failed: false,
+            out: Vec::new(),
+        };
+        let mut payload = Vec::new();
+        for (id, value) in [
+            (MAX_CONCURRENT_STREAMS, settings.max_concurrent_streams),
+            (INITIAL_WINDOW_SIZE, settings.initial_window_size),
+            (MAX_FRAME_SIZE, settings.max_frame_size),
+            (MAX_HEADER_LIST_SIZE, settings.max_header_list_size),
+        ] {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        conn.frame(SETTINGS, 0, 0, &payload);
+        // The connection window isn't covered by SETTINGS; widen it to match.
+        let increase = i64::from(settings.initial_window_size) - DEFAULT_WINDOW;
+        if increase > 0 {
+            conn.window_update(0, increase as u32);
+            conn.recv_window += increase;
+        }
+        conn
+    }
+
+    // Whether the connection has ended, or is winding down and has nothing
+    // left to do.
+    pub fn is_done(&self) -> bool {
+        self.failed || (self.going_away && self.streams.is_empty() && self.ready.is_empty())
+    }
+
+    // Whether a response is waiting to send DATA that the windows allow.
+    pub fn can_send(&self) -> bool {
+        self.send_window > 0
+            && self.streams.values().any(|s| s.pending.as_ref().is_some_and(Pending::ready) && s.send_window > 0)
+    }
+
+    // Whether any response is a feed that is still producing.
+    pub fn has_feeds(&self) -> bool {
+        self.streams.values().any(|s| s.pending.as_ref().is_some_and(|p| p.feed.is_some()))
+    }
+
+    // Collects what each feed has produced since the last poll.
+    pub fn poll_feeds(&mut self, now: Instant) {
+        for stream in self.streams.values_mut() {
+            let pending = match &mut stream.pending {
+                Some(pending) => pending,
+                None => continue,
+            };
+            if let (Some(feed), Body::Bytes(buf)) = (&mut pending.feed, &mut pending.body) {
+                buf.drain(..pending.sent as usize);
+                if !feed.poll(now, buf) {
+                    pending.feed = None;
+                }
+                pending.sent = 0;
+                pending.len = buf.len() as u64;
+            }
+        }
+    }
+
+    // Responses that haven't been sent in full.
+    pub fn unfinished(&self) -> usize {
+        self.streams.values().filter(|s| s.pending.is_some()).count()
+    }
+
+    // Parses and acts on every complete frame at the start of `input`, then
+    // removes them. A connection error queues GOAWAY and is returned; the
+    // connection should be closed once that is sent.
+    pub fn receive(&mut self, input: &mut Vec<u8>, metrics: &Metrics) -> Result<(), Error> {
+        let mut used = 0;
+        let result = self.receive_frames(input, &mut used, metrics);
+        input.drain(..used);
+        if let Err(e) = &result {
+            self.goaway(e.code);
+            self.failed = true;
+        }
+        result
+    }
+
+    fn receive_frames(&mut self, input: &[u8], used: &mut usize, metrics: &Metrics) -> Result<(), Error> {
+        if self.failed {
+            *used = input.len();
+            return Ok(());
+        }
+        if !self.preface_received {
+            match is_preface(input) {
+                None => return Ok(()),
+                Some(false) => return Err(error(ErrorCode::Protocol, "invalid connection preface")),
+                Some(true) => {
+                    *used = PREFACE.len();
+                    self.preface_received = true;
+                }
+            }
+        }
+        loop {
+            let rest = &input[*used..];
+            if rest.len() < FRAME_HEADER {
+                return Ok(());
+            }
+            let len = usize::from(rest[0]) << 16 | usize::from(rest[1]) << 8 | usize::from(rest[2]);
+            if len > self.settings.max_frame_size as usize {
+                return Err(error(ErrorCode::FrameSize, "frame larger than SETTINGS_MAX_FRAME_SIZE"));
+            }
+            if rest.len() < FRAME_HEADER + len {
+                return Ok(());
+            }
+            let (kind, flags) = (rest[3], rest[4]);
+            let stream = u32::from_be_bytes([rest[5], rest[6], rest[7], rest[8]]) & 0x7fff_ffff;
+            let payload = &rest[FRAME_HEADER..FRAME_HEADER + len];
+            *used += FRAME_HEADER + len;
+
+            if let Some(partial) = &self.partial {
+                if kind != CONTINUATION || stream != partial.stream {
+                    return Err(error(ErrorCode::Protocol, "header block interrupted"));
+                }
+            }
+            match kind {
+                DATA => self.on_data(stream, flags, payload, metrics)?,
+                HEADERS => self.on_headers(stream, flags, payload)?,
+                PRIORITY => self.on_priority(stream, payload)?,
+                RST_STREAM => self.on_rst_stream(stream, payload, metrics)?,
+                SETTINGS => self.on_settings(stream, flags, payload)?,
+                PUSH_PROMISE => return Err(error(ErrorCode::Protocol, "PUSH_PROMISE from a client")),
+                PING => self.on_ping(stream, flags, payload)?,
+                GOAWAY => self.on_goaway(stream)?,
+                WINDOW_UPDATE => self.on_window_update(stream, payload)?,
+                CONTINUATION => self.on_continuation(stream, flags, payload)?,
+                // Unknown frame types are ignored.
+                _ => {}
+            }
+        }
+    }
+
+    fn on_data(&mut self, id: u32, flags: u8, payload: &[u8], metrics: &Metrics) -> Result<(), Error> {
+        if id == 0 {
+            return Err(error(ErrorCode::Protocol, "DATA on stream 0"));
+        }
+        let data = unpad(flags, payload)?;
+        // Padding counts against the windows too.
+        let len = payload.len() as i64;
+        if len > self.recv_window {
+            return Err(error(ErrorCode::FlowControl, "DATA beyond the connection window"));
+        }
+        // Bodies are buffered whole, up to MAX_BODY, so the connection
+        // window is given back straight away.
+        if len > 0 {
+            self.window_update(0, len as u32);
+        }
+        if id > self.last_stream_id {
+            return Err(error(ErrorCode::Protocol, "DATA on an idle stream"));
+        }
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            // Reset or finished; the client may not have heard yet.
+            None => return Ok(()),
+        };
+        if stream.remote_closed {
+            self.reset(id, ErrorCode::StreamClosed, metrics);
+            return Ok(());
+        }
+        if len > stream.recv_window {
+            self.reset(id, ErrorCode::FlowControl, metrics);
+            return Ok(());
+        }
+        stream.recv_window -= len;
+        let end_stream = flags & END_STREAM != 0;
+        if !stream.rejected {
+            let request = stream.request.as_mut().expect("open streams keep their request until it is complete");
+            if request.body.len() + data.len() > MAX_BODY {
+                stream.request = None;
+                stream.rejected = true;
+                self.ready.push_back((id, Err(ParseError::PayloadTooLarge)));
+            } else {
+                request.body.extend_from_slice(data);
+                if !end_stream && len > 0 {
+                    stream.recv_window += len;
+                    self.window_update(id, len as u32);
+                }
+            }
+        }
+        if end_stream {
+            self.end_of_request(id);
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, id: u32, flags: u8, payload: &[u8]) -> Result<(), Error> {
+        if id == 0 {
+            return Err(error(ErrorCode::Protocol, "HEADERS on stream 0"));
+        }
+        let mut block = unpad(flags, payload)?;
+        if flags & PRIORITY_FLAG != 0 {
+            // Dependency and weight; priorities are not acted on.
+            block = block.get(5..).ok_or(error(ErrorCode::FrameSize, "HEADERS too short for its priority"))?;
+        }
+        let partial = PartialBlock { stream: id, block: block.to_vec(), end_stream: flags & END_STREAM != 0 };
+        if flags & END_HEADERS != 0 {
+            self.on_header_block(partial)
+        } else {
+            self.partial = Some(partial);
+            Ok(())
+        }
+    }
+
+    fn on_continuation(&mut self, id: u32, flags: u8, payload: &[u8]) -> Result<(), Error> {
+        let mut partial = match self.partial.take() {
+            Some(partial) => partial,
+            None => return Err(error(ErrorCode::Protocol, "CONTINUATION without HEADERS")),
+        };
+        debug_assert_eq!(partial.stream, id);
+        partial.block.extend_from_slice(payload);
+        // The whole block has to be decoded to keep the HPACK table in step,
+        // so one far past the header list limit ends the connection.
+        if partial.block.len() > 4 * self.settings.max_header_list_size as usize {
+            return Err(error(ErrorCode::EnhanceYourCalm, "header block too large"));
+        }
+        if flags & END_HEADERS != 0 {
+            self.on_header_block(partial)
+        } else {
+            self.partial = Some(partial);
+            Ok(())
+        }
+    }
+
+    fn on_header_block(&mut self, partial: PartialBlock) -> Result<(), Error> {
+        let PartialBlock { stream: id, block, end_stream } = partial;
+        let fields = self.decoder.decode(&block).map_err(|_| error(ErrorCode::Compression, "invalid header block"))?;
+
+        // Trailers, closing a request whose body has arrived.
+        if let Some(stream) = self.streams.get_mut(&id) {
+            if stream.remote_closed || !end_stream {
+                return Err(error(ErrorCode::Protocol, "HEADERS in the middle of a stream"));
+            }
+            if let Some(request) = &mut stream.request {
+                match trailers(fields) {
+                    Ok(trailers) => request.trailers = trailers,
+                    Err(e) => {
+                        stream.request = None;
+                        stream.rejected = true;
+                        self.ready.push_back((id, Err(e)));
+                    }
+                }
+            }
+            self.end_of_request(id);
+            return Ok(());
+        }
+        if id <= self.last_stream_id {
+            // A stream that has been reset; the client may not have heard yet.
+            return Ok(());
+        }
+        if id.is_multiple_of(2) {
+            return Err(error(ErrorCode::Protocol, "even stream ID from a client"));
+        }
+        self.last_stream_id = id;
+        if self.going_away {
+            return Ok(());
+        }
+        if self.streams.len() >= self.settings.max_concurrent_streams as usize {
+            self.frame(RST_STREAM, 0, id, &(ErrorCode::RefusedStream as u32).to_be_bytes());
+            return Ok(());
+        }
+
+        let size: usize = fields.iter().map(|(n, v)| n.len() + v.len() + 32).sum();
+        let request = if size > self.settings.max_header_list_size as usize {
+            Err(ParseError::HeadersTooLarge)
+        } else {
+            request(fields)
+        };
+        let mut stream = Stream {
+            request: None,
+            content_length: None,
+            remote_closed: false,
+            rejected: false,
+            recv_window: i64::from(self.settings.initial_window_size),
+            send_window: self.peer_initial_window,
+            pending: None,
+        };
+        match request.and_then(|request| Ok((content_length(&request.headers)?, request))) {
+            Ok((content_length, request)) => {
+                stream.content_length = content_length;
+                stream.request = Some(request);
+            }
+            Err(e) => {
+                stream.rejected = true;
+                self.ready.push_back((id, Err(e)));
+            }
+        }
+        self.streams.insert(id, stream);
+        if end_stream {
+            self.end_of_request(id);
+        }
+
+        self.accepted += 1;
+        if self.accepted >= self.settings.max_requests {
+            self.goaway(ErrorCode::NoError);
+        }
+        Ok(())
+    }
+
+    // The client has finished sending on `id`; queues its request.
+    fn end_of_request(&mut self, id: u32) {
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None => return,
+        };
+        stream.remote_closed = true;
+        if let Some(request) = stream.request.take() {
+            match stream.content_length {
+                Some(len) if len != request.body.len() => {
+                    self.ready.push_back((id, Err(ParseError::BadRequest("body length differs from Content-Length"))));
+                }
+                _ => self.ready.push_back((id, Ok(request))),
+            }
+        }
+    }
+
+    fn on_priority(&mut self, id: u32, payload: &[u8]) -> Result<(), Error> {
+        if id == 0 {
+            return Err(error(ErrorCode::Protocol, "PRIORITY on stream 0"));
+        }
+        if payload.len() != 5 {
+            return Err(error(ErrorCode::FrameSize, "PRIORITY of the wrong size"));
+        }
+        Ok(())
+    }
+
+    fn on_rst_stream(&mut self, id: u32, payload: &[u8], metrics: &Metrics) -> Result<(), Error> {
+        if id == 0 {
+            return Err(error(ErrorCode::Protocol, "RST_STREAM on stream 0"));
+        }
+        if payload.len() != 4 {
+            return Err(error(ErrorCode::FrameSize, "RST_STREAM of the wrong size"));
+        }
+        if id > self.last_stream_id {
+            return Err(error(ErrorCode::Protocol, "RST_STREAM on an idle stream"));
+        }
+        if let Some(stream) = self.streams.remove(&id) {
+            if stream.pending.is_some() {
+                metrics.response_incomplete();
+            }
+        }
+        self.ready.retain(|(ready, _)| *ready != id);
+        Ok(())
+    }
+
+    fn on_settings(&mut self, id: u32, flags: u8, payload: &[u8]) -> Result<(), Error> {
+        if id != 0 {
+            return Err(error(ErrorCode::Protocol, "SETTINGS on a stream"));
+        }
+        if flags & ACK != 0 {
+            if !payload.is_empty() {
+                return Err(error(ErrorCode::FrameSize, "SETTINGS acknowledgement with a payload"));
+            }
+            return Ok(());
+        }
+        if !payload.len().is_multiple_of(6) {
+            return Err(error(ErrorCode::FrameSize, "SETTINGS of the wrong size"));
+        }
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                ENABLE_PUSH if value > 1 => return Err(error(ErrorCode::Protocol, "invalid SETTINGS_ENABLE_PUSH")),
+                INITIAL_WINDOW_SIZE => {
+                    let value = i64::from(value);
+                    if value > MAX_WINDOW {
+                        return Err(error(ErrorCode::FlowControl, "invalid SETTINGS_INITIAL_WINDOW_SIZE"));
+                    }
+                    let delta = value - self.peer_initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW {
+                            return Err(error(ErrorCode::FlowControl, "stream window overflow"));
+                        }
+                    }
+                    self.peer_initial_window = value;
+                }
+                MAX_FRAME_SIZE => {
+                    if !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE_LIMIT).contains(&value) {
+                        return Err(error(ErrorCode::Protocol, "invalid SETTINGS_MAX_FRAME_SIZE"));
+                    }
+                    self.peer_max_frame_size = value as usize;
+                }
+                // Responses use no dynamic table and are never pushed, and
+                // their headers are small.
+                HEADER_TABLE_SIZE | ENABLE_PUSH | MAX_CONCURRENT_STREAMS | MAX_HEADER_LIST_SIZE => {}
+                _ => {}
+            }
+        }
+        self.frame(SETTINGS, ACK, 0, &[]);
+        Ok(())
+    }
+
+    fn on_ping(&mut self, id: u32, flags: u8, payload: &[u8]) -> Result<(), Error> {
+        if id != 0 {
+            return Err(error(ErrorCode::Protocol, "PING on a stream"));
+        }
+        if payload.len() != 8 {
+            return Err(error(ErrorCode::FrameSize, "PING of the wrong size"));
+        }
+        if flags & ACK == 0 {
+            self.frame(PING, ACK, 0, payload);
+        }
+        Ok(())
+    }
+
+    fn on_goaway(&mut self, id: u32) -> Result<(), Error> {
+        if id != 0 {
+            return Err(error(ErrorCode::Protocol, "GOAWAY on a stream"));
+        }
+        self.going_away = true;
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, id: u32, payload: &[u8]) -> Result<(), Error> {
+        if payload.len() != 4 {
+            return Err(error(ErrorCode::FrameSize, "WINDOW_UPDATE of the wrong size"));
+        }
+        let increment = i64::from(u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & 0x7fff_ffff);
+        if id == 0 {
+            if increment == 0 {
+                return Err(error(ErrorCode::Protocol, "WINDOW_UPDATE of zero"));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW {
+                return Err(error(ErrorCode::FlowControl, "connection window overflow"));
+            }
+            return Ok(());
+        }
+        if id > self.last_stream_id {
+            return Err(error(ErrorCode::Protocol, "WINDOW_UPDATE on an idle stream"));
+        }
+        let code = match self.streams.get_mut(&id) {
+            None => return Ok(()),
+            Some(_) if increment == 0 => ErrorCode::Protocol,
+            Some(stream) => {
+                stream.send_window += increment;
+                if stream.send_window <= MAX_WINDOW {
+                    return Ok(());
+                }
+                ErrorCode::FlowControl
+            }
+        };
+        self.frame(RST_STREAM, 0, id, &(code as u32).to_be_bytes());
+        self.streams.remove(&id);
+        Ok(())
+    }
+
+    // The next complete request, or a request to reject with the error's
+    // status, in the order they completed.
+    pub fn next_request(&mut self) -> Option<(u32, Result<Request, ParseError>)> {
+        self.ready.pop_front()
+    }
+
+    // Queues the response's HEADERS, and its body for `send_data`. Bodies
+    // produced by a stream are run to completion first.
+    pub fn respond(&mut self, id: u32, response: Response, include_body: bool, metrics: &Metrics) {
+        if !self.streams.contains_key(&id) {
+            return;
+        }
+        let status = response.status;
+        let mut fields = vec![(":status".to_string(), status.to_string())];
+        for (name, value) in response_headers(&response) {
+            fields.push((name.to_ascii_lowercase(), value.to_string()));
+        }
+        let body = match response.body {
+            body @ Body::Stream(_) if include_body => match body.into_vec() {
+                Ok(bytes) => Body::Bytes(bytes),
+                Err(e) => {
+                    println!("Failed producing response: {}", e);
+                    metrics.response_incomplete();
+                    self.reset(id, ErrorCode::Internal, metrics);
+                    return;
+                }
+            },
+            body => body,
+        };
+        let len = body.len();
+        let bodiless = status < 200 || status == 204 || status == 304;
+        if let (false, Some(len)) = (bodiless, len) {
+            fields.push(("content-length".to_string(), len.to_string()));
+        }
+        let fields: Vec<(&str, &str)> = fields.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect();
+        let mut block = Vec::new();
+        hpack::encode(&fields, &mut block);
+
+        let empty = !include_body || bodiless || len == Some(0);
+        let mut chunks = block.chunks(self.peer_max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if empty { END_STREAM } else { 0 };
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            self.frame(kind, flags, id, chunk);
+            kind = CONTINUATION;
+            flags = 0;
+        }
+        if empty {
+            self.end_of_response(id);
+        } else if let Some(stream) = self.streams.get_mut(&id) {
+            stream.pending = Some(match body {
+                // Collected by `poll_feeds`.
+                Body::Feed(feed) => Pending { body: Body::Bytes(Vec::new()), sent: 0, len: 0, feed: Some(feed) },
+                body => Pending { body, sent: 0, len: len.unwrap_or(0), feed: None },
+            });
+        }
+    }
+
+    // Queues DATA frames for pending responses, as far as the flow-control
+    // windows allow, until about `limit` bytes are queued. Streams take
+    // turns, a frame at a time.
+    pub fn send_data(&mut self, limit: usize, metrics: &Metrics) {
+        while self.out.len() < limit && self.send_window > 0 {
+            let next = {
+                let ready = |(id, s): (&u32, &Stream)| {
+                    (s.pending.as_ref().is_some_and(Pending::ready) && s.send_window > 0).then_some(*id)
+                };
+                let after = self.streams.range(self.last_sent + 1..).find_map(ready);
+                after.or_else(|| self.streams.range(..=self.last_sent).find_map(ready))
+            };
+            let id = match next {
+                Some(id) => id,
+                None => return,
+            };
+            self.last_sent = id;
+            let stream = self.streams.get_mut(&id).expect("picked from the map");
+            let pending = stream.pending.as_mut().expect("picked for its pending body");
+            let n = (pending.len - pending.sent)
+                .min(self.send_window as u64)
+                .min(stream.send_window as u64)
+                .min(self.peer_max_frame_size as u64);
+            let data = match pending.body.slice(pending.sent, pending.sent + n).into_vec() {
+                Ok(data) => data,
+                Err(e) => {
+                    println!("Failed sending response: {}", e);
+                    metrics.response_incomplete();
+                    self.reset(id, ErrorCode::Internal, metrics);
+                    continue;
+                }
+            };
+            pending.sent += n;
+            stream.send_window -= n as i64;
+            self.send_window -= n as i64;
+            let last = pending.sent == pending.len && pending.feed.is_none();
+            if last {
+                stream.pending = None;
+            }
+            self.frame(DATA, if last { END_STREAM } else { 0 }, id, &data);
+            if last {
+                self.end_of_response(id);
+            }
+        }
+    }
+
+    // We have sent END_STREAM on `id`. A client still sending a request body
+    // is told to stop.
+    fn end_of_response(&mut self, id: u32) {
+        if let Some(stream) = self.streams.remove(&id) {
+            if !stream.remote_closed {
+                self.frame(RST_STREAM, 0, id, &(ErrorCode::NoError as u32).to_be_bytes());
+            }
+        }
+    }
+
+    fn reset(&mut self, id: u32, code: ErrorCode, metrics: &Metrics) {
+        if let Some(stream) = self.streams.remove(&id) {
+            if stream.pending.is_some() {
+                metrics.response_incomplete();
+            }
+        }
+        self.ready.retain(|(ready, _)| *ready != id);
+        self.frame(RST_STREAM, 0, id, &(code as u32).to_be_bytes());
+    }
+
+    // Tells the client no streams after the last one will be served.
+    pub fn goaway(&mut self, code: ErrorCode) {
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&(code as u32).to_be_bytes());
+        self.frame(GOAWAY, 0, 0, &payload);
+        self.going_away = true;
+    }
+
+    fn window_update(&mut self, id: u32, increment: u32) {
+        self.frame(WINDOW_UPDATE, 0, id, &increment.to_be_bytes());
+    }
+
+    fn frame(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+        let len = payload.len() as u32;
+        self.out.extend_from_slice(&len.to_be_bytes()[1..]);
+        self.out.push(kind);
+        self.out.push(flags);
+        self.out.extend_from_slice(&stream.to_be_bytes());
+        self.out.extend_from_slice(payload);
+    }
+}
+
+// The payload without its padding, for DATA and HEADERS.
+fn unpad(flags: u8, payload: &[u8]) -> Result<&[u8], Error> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let (&pad, rest) = payload.split_first().ok_or(error(ErrorCode::FrameSize, "padded frame without a pad length"))?;
+    rest.len()
+        .checked_sub(usize::from(pad))
+        .map(|end| &rest[..end])
+        .ok_or(error(ErrorCode::Protocol, "padding longer than the frame"))
+}
+
+// Headers HTTP/2 does without: the connection itself is managed by frames.
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+fn response_headers(response: &Response) -> impl Iterator<Item = (&str, &str)> {
+    response.headers().filter(|(name, _)| !CONNECTION_HEADERS.iter().any(|c| name.eq_ignore_ascii_case(c)))
+}
+
+// Builds a request from its decoded header fields (section 8.3.1). Pseudo-
+// header fields come first; `:authority` stands in for `Host`.
+fn request(fields: Vec<Field>) -> Result<Request, ParseError> {
+    let (mut method, mut scheme, mut path, mut authority) = (None, None, None, None);
+    let mut headers = HeaderMap::default();
+    let mut cookies = Vec::new();
+    let mut regular = false;
+    for (name, value) in fields {
+        let (name, value) = field(name, value)?;
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if regular {
+                return Err(ParseError::BadRequest("pseudo-header field after a regular one"));
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                _ => return Err(ParseError::BadRequest("unknown pseudo-header field")),
+            };
+            if slot.replace(value).is_some() {
+                return Err(ParseError::BadRequest("repeated pseudo-header field"));
+            }
+            continue;
+        }
+        regular = true;
+        if CONNECTION_HEADERS.contains(&name.as_str()) || (name == "te" && value != "trailers") {
+            return Err(ParseError::BadRequest("connection-specific header field"));
+        }
+        if headers.len() + cookies.len() == MAX_HEADERS {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        // Cookies may be split into one field per pair for better compression.
+        if name == "cookie" {
+            cookies.push(value);
+        } else {
+            headers.push(name, value);
+        }
+    }
+    if !cookies.is_empty() {
+        headers.push("cookie".to_string(), cookies.join("; "));
+    }
+
+    let method = Method::parse(&method.ok_or(ParseError::BadRequest("missing :method"))?);
+    let target = if method == Method::Connect {
+        authority.clone().ok_or(ParseError::BadRequest("CONNECT without :authority"))?
+    } else {
+        scheme.ok_or(ParseError::BadRequest("missing :scheme"))?;
+        let path = path.ok_or(ParseError::BadRequest("missing :path"))?;
+        if !(path.starts_with('/') || (path == "*" && method == Method::Options)) {
+            return Err(ParseError::BadRequest("invalid :path"));
+        }
+        path
+    };
+    if let Some(authority) = authority {
+        if headers.get("Host").is_none() {
+            headers.push("host".to_string(), authority);
+        }
+    }
+    Ok(Request {
+        method,
+        target,
+        version: Version::Http2,
+        headers,
+        body: Vec::new(),
+        trailers: HeaderMap::default(),
+        tls: None,
+    })
+}
+
+fn trailers(fields: Vec<Field>) -> Result<HeaderMap, ParseError> {
+    let mut trailers = HeaderMap::default();
+    for (name, value) in fields {
+        let (name, value) = field(name, value)?;
+        if name.starts_with(':') {
+            return Err(ParseError::BadRequest("pseudo-header field in trailers"));
+        }
+        if trailers.len() == MAX_HEADERS {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        trailers.push(name, value);
+    }
+    Ok(trailers)
+}
+
+// Checks a field the way HTTP/1 header lines are checked, and that its
+// name is lowercase as HTTP/2 requires.
+fn field(name: Vec<u8>, value: Vec<u8>) -> Result<(String, String), ParseError> {
+    let bare = name.strip_prefix(b":").unwrap_or(&name);
+    let valid_name = !bare.is_empty()
+        && bare.iter().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"!#$%&'*+-.^_`|~".contains(b));
+    if !valid_name {
+        return Err(ParseError::BadRequest("invalid header name"));
+    }
+    let valid_value = !value.starts_with(b" ")
+        && !value.starts_with(b"\t")
+        && !value.ends_with(b" ")
+        && !value.ends_with(b"\t")
+        && !value.iter().any(|b| (*b < b' ' && *b != b'\t') || *b == 0x7f);
+    match (valid_value, String::from_utf8(name), String::from_utf8(value)) {
+        (true, Ok(name), Ok(value)) => Ok((name, value)),
+        _ => Err(ParseError::BadRequest("invalid header value")),
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Result<Option<usize>, ParseError> {
+    match headers.get("Content-Length") {
+        None => Ok(None),
+        Some(value) if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+            let len: usize = value.parse().map_err(|_| ParseError::PayloadTooLarge)?;
+            if len > MAX_BODY {
+                return Err(ParseError::PayloadTooLarge);
+            }
+            Ok(Some(len))
+        }
+        Some(_) => Err(ParseError::BadRequest("invalid Content-Length")),
+    }
+}
+
+// Feeds what has been read to the connection, answers the requests that
+// completes, and appends what is to be sent to `out`, with response data up
+// to about `limit` bytes of it. Returns false once the connection is over.
+#[allow(clippy::too_many_arguments)]
+pub fn process(
+    conn: &mut Connection,
+    input: &mut Vec<u8>,
+    out: &mut Vec<u8>,
+    limit: usize,
+    tls: Option<&TlsInfo>,
+    router: &Router,
+    config: &Config,
+    metrics: &Metrics,
+) -> bool {
+    let received = conn.receive(input, metrics);
+    // Streams already open are finished; new ones are refused.
+    if shutdown::draining() && !conn.going_away {
+        conn.goaway(ErrorCode::NoError);
+    }
+    while let Some((id, request)) = conn.next_request() {
+        match request {
+            Ok(mut req) => {
+                connection::attach_tls(&mut req, tls, config);
+                connection::log_request(&req);
+                let started = Instant::now();
+                let (response, route) = connection::prepare(router, &req, config);
+                let status = response.status;
+                conn.respond(id, response, req.method != Method::Head, metrics);
+                metrics.observe_request(route, status, started.elapsed());
+                println!("Response queued: {} (stream {})", status, id);
+            }
+            Err(e) => {
+                println!("Rejecting request: {}", e);
+                metrics.count_request(UNMATCHED, e.status());
+                conn.respond(id, Response::status_page(e.status()), true, metrics);
+            }
+        }
+    }
+    conn.poll_feeds(Instant::now());
+    conn.send_data(limit.saturating_sub(out.len()), metrics);
+    out.append(&mut conn.out);
+    match received {
+        Ok(()) => !conn.is_done(),
+        Err(e) => {
+            println!("HTTP/2 connection error: {}", e);
+            false
+        }
+    }
+}
+
+// Response data is written to the socket in pieces of about this size.
+const SEND_CHUNK: usize = 64 * 1024;
+
+// Serves an HTTP/2 connection from a worker thread until either side ends
+// it or it goes idle. `buf` holds anything already read, such as the
+// preface that identified it. `socket` is the connection under `stream`:
+// while a feed is producing, reads on it wait at most a `TICK`.
+#[allow(clippy::too_many_arguments)]
+pub fn serve<S: Read + Write>(
+    stream: &mut S,
+    socket: &TcpStream,
+    mut buf: Vec<u8>,
+    tls: Option<&TlsInfo>,
+    router: &Router,
+    config: &Config,
+    metrics: &Metrics,
+) {
+    let mut conn = Connection::new(Settings::new(config));
+    let mut out = Vec::new();
+    let mut chunk = [0u8; 16 * 1024];
+    let mut ticking = false;
+    loop {
+        let open = process(&mut conn, &mut buf, &mut out, SEND_CHUNK, tls, router, config, metrics);
+        if let Err(e) = stream.write_all(&out).and_then(|_| stream.flush()) {
+            println!("Failed sending response: {}", e);
+            for _ in 0..conn.unfinished() {
+                metrics.response_incomplete();
+            }
+            return;
+        }
+        out.clear();
+        if !open {
+            return;
+        }
+        // Keep sending while the windows allow, before waiting on the client.
+        if conn.can_send() {
+            continue;
+        }
+        if conn.has_feeds() != ticking {
+            ticking = !ticking;
+            let timeout = if ticking { TICK } else { config.idle_timeout };
+            if let Err(e) = socket.set_read_timeout(Some(timeout)) {
+                println!("Unable to set socket timeouts: {}", e);
+                break;
+            }
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            // Time to poll the feeds again.
+            Err(e) if ticking && matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                println!("Closing idle connection");
+                conn.goaway(ErrorCode::NoError);
+                out.append(&mut conn.out);
+                let _ = stream.write_all(&out).and_then(|_| stream.flush());
+                break;
+            }
+            Err(e) => {
+                println!("Unable to read stream: {}", e);
+                break;
+            }
+        }
+    }
+    // Responses still waiting on a window the client never opened.
+    for _ in 0..conn.unfinished() {
+        metrics.response_incomplete();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            max_concurrent_streams: 2,
+            initial_window_size: 65_535,
+            max_frame_size: 16_384,
+            max_header_list_size: 16_384,
+            max_requests: 100,
+        }
+    }
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        out.extend_from_slice(&[kind, flags]);
+        out.extend_from_slice(&stream.to_be_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn headers(stream: u32, flags: u8, fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut block = Vec::new();
+        hpack::encode(fields, &mut block);
+        frame(HEADERS, flags | END_HEADERS, stream, &block)
+    }
+
+    const GET: [(&str, &str); 4] = [(":method", "GET"), (":scheme", "https"), (":path", "/a?b"), (":authority", "example.com")];
+
+    // Splits output into (type, flags, stream, payload).
+    fn frames(mut out: &[u8]) -> Vec<(u8, u8, u32, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while !out.is_empty() {
+            let len = usize::from(out[0]) << 16 | usize::from(out[1]) << 8 | usize::from(out[2]);
+            let stream = u32::from_be_bytes([out[5], out[6], out[7], out[8]]);
+            frames.push((out[3], out[4], stream, out[9..9 + len].to_vec()));
+            out = &out[9 + len..];
+        }
+        frames
+    }
+
+    fn started() -> Connection {
+        let mut conn = Connection::new(settings());
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(SETTINGS, 0, 0, &[]));
+        conn.receive(&mut input, &Metrics::new()).unwrap();
+        assert!(input.is_empty());
+        conn.out.clear();
+        conn
+    }
+
+    #[test]
+    fn recognises_the_preface() {
+        assert_eq!(is_preface(b""), None);
+        assert_eq!(is_preface(b"PRI * HTTP/2"), None);
+        assert_eq!(is_preface(b"GET / HTTP/1.1\r\n"), Some(false));
+        assert_eq!(is_preface(b"PRI * HTTP/1.1\r\n"), Some(false));
+        assert_eq!(is_preface(PREFACE), Some(true));
+    }
+
+    #[test]
+    fn starts_with_settings_and_acknowledges_the_clients() {
+        let mut conn = Connection::new(settings());
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(SETTINGS, 0, 0, &[0, 4, 0, 0, 0, 10]));
+        input.extend(frame(PING, 0, 0, b"12345678"));
+        conn.receive(&mut input, &Metrics::new()).unwrap();
+        let out = frames(&conn.out);
+        assert_eq!(out[0].0, SETTINGS);
+        assert_eq!(out[0].3.len(), 24);
+        assert_eq!(out[1], (SETTINGS, ACK, 0, vec![]));
+        assert_eq!(out[2], (PING, ACK, 0, b"12345678".to_vec()));
+        assert_eq!(conn.peer_initial_window, 10);
+    }
+
+    #[test]
+    fn builds_requests_from_header_blocks() {
+        let mut conn = started();
+        let mut fields = GET.to_vec();
+        fields.extend([("cookie", "a=1"), ("accept", "*/*"), ("cookie", "b=2")]);
+        let mut input = headers(1, 0, &fields);
+        input.extend(frame(DATA, 0, 1, b"hello "));
+        // Padded, with one byte of padding.
+        input.extend(frame(DATA, PADDED | END_STREAM, 1, b"\x01world\x00"));
+        conn.receive(&mut input, &Metrics::new()).unwrap();
+        let (id, req) = conn.next_request().unwrap();
+        let req = req.unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(req.method, Method::Get);
+        assert_eq!(req.path(), "/a");
+        assert_eq!(req.version, Version::Http2);
+        assert_eq!(req.headers.get("Host"), Some("example.com"));
+        assert_eq!(req.headers.get("Cookie"), Some("a=1; b=2"));
+        assert_eq!(req.body, b"hello world");
+        assert!(conn.next_request().is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let cases: [&[(&str, &str)]; 5] = [
+            &[(":method", "GET"), (":path", "/")],
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/"), ("Accept", "*/*")],
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/"), ("connection", "close")],
+            &[(":method", "GET"), ("accept", "*/*"), (":scheme", "https"), (":path", "/")],
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/"), (":path", "/")],
+        ];
+        for fields in cases {
+            let mut conn = started();
+            conn.receive(&mut headers(1, END_STREAM, fields), &Metrics::new()).unwrap();
+            assert!(matches!(conn.next_request(), Some((1, Err(ParseError::BadRequest(_))))), "{:?}", fields);
+        }
+    }
+
+    #[test]
+    fn sends_responses_within_the_windows() {
+        let metrics = Metrics::new();
+        let mut conn = started();
+        let mut input = frame(SETTINGS, 0, 0, &[0, 4, 0, 0, 0, 10]);
+        input.extend(headers(1, END_STREAM, &GET));
+        conn.receive(&mut input, &metrics).unwrap();
+        assert!(conn.next_request().unwrap().1.is_ok());
+        conn.out.clear();
+
+        let response = Response::new(200).with_header("Connection", "close").with_body(b"0123456789abcdef".to_vec());
+        conn.respond(1, response, true, &metrics);
+        conn.send_data(usize::MAX, &metrics);
+        let out = frames(&conn.out);
+        assert_eq!(out.len(), 2);
+        assert_eq!((out[0].0, out[0].1, out[0].2), (HEADERS, END_HEADERS, 1));
+        let fields = hpack::Decoder::new(4096).decode(&out[0].3).unwrap();
+        assert_eq!(fields[0], (b":status".to_vec(), b"200".to_vec()));
+        assert!(fields.contains(&(b"content-length".to_vec(), b"16".to_vec())));
+        assert!(!fields.iter().any(|(n, _)| n == b"connection"));
+        assert_eq!(out[1], (DATA, 0, 1, b"0123456789".to_vec()));
+        assert!(!conn.can_send());
+
+        conn.out.clear();
+        conn.receive(&mut frame(WINDOW_UPDATE, 0, 1, &100u32.to_be_bytes()), &metrics).unwrap();
+        conn.send_data(usize::MAX, &metrics);
+        assert_eq!(frames(&conn.out), vec![(DATA, END_STREAM, 1, b"abcdef".to_vec())]);
+        assert!(conn.streams.is_empty());
+    }
+
+    #[test]
+    fn enforces_settings_limits() {
+        let metrics = Metrics::new();
+        // More streams than SETTINGS_MAX_CONCURRENT_STREAMS.
+        let mut conn = started();
+        let mut input = headers(1, 0, &GET);
+        input.extend(headers(3, 0, &GET));
+        input.extend(headers(5, 0, &GET));
+        conn.receive(&mut input, &metrics).unwrap();
+        assert_eq!(frames(&conn.out), vec![(RST_STREAM, 0, 5, vec![0, 0, 0, 7])]);
+
+        // A frame larger than SETTINGS_MAX_FRAME_SIZE.
+        let mut conn = started();
+        let error = conn.receive(&mut frame(DATA, 0, 1, &[0; 16_385]), &metrics).unwrap_err();
+        assert_eq!(error.code, ErrorCode::FrameSize);
+        assert_eq!(frames(&conn.out)[0].0, GOAWAY);
+        assert!(conn.is_done());
+
+        // A header list over SETTINGS_MAX_HEADER_LIST_SIZE, and a body
+        // over the limit.
+        let mut conn = started();
+        let long = "x".repeat(16_384);
+        let mut fields = GET.to_vec();
+        fields.push(("x-long", &long));
+        conn.receive(&mut headers(1, END_STREAM, &fields), &metrics).unwrap();
+        assert!(matches!(conn.next_request(), Some((1, Err(ParseError::HeadersTooLarge)))));
+        let mut fields = GET.to_vec();
+        fields.push(("content-length", "2000000"));
+        conn.receive(&mut headers(3, 0, &fields), &metrics).unwrap();
+        assert!(matches!(conn.next_request(), Some((3, Err(ParseError::PayloadTooLarge)))));
+    }
+
+    #[test]
+    fn rejects_protocol_violations() {
+        let metrics = Metrics::new();
+        let cases = [
+            frame(HEADERS, END_HEADERS, 2, &[0x82]),
+            frame(DATA, 0, 7, b"x"),
+            frame(PING, 0, 0, b"1234"),
+            frame(WINDOW_UPDATE, 0, 0, &[0, 0, 0, 0]),
+            frame(SETTINGS, 0, 0, &[0, 5, 0, 0, 0, 1]),
+            frame(HEADERS, END_HEADERS, 1, &[0x80]),
+            [frame(HEADERS, 0, 1, &[0x82]), frame(PING, 0, 0, b"12345678")].concat(),
+        ];
+        for mut input in cases {
+            let mut conn = started();
+            assert!(conn.receive(&mut input, &metrics).is_err());
+        }
+        let mut conn = Connection::new(settings());
+        assert!(conn.receive(&mut b"GET / HTTP/1.1\r\n\r\n.......".to_vec(), &metrics).is_err());
+    }
+}
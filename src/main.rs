@@ -1,88 +1,350 @@
 use std::net::{TcpListener, TcpStream};
-use std::io::{Read, Write};
+use std::process;
+use std::sync::Arc;
 use std::thread;
 
-// Add the following to gather system statistics:
-use sysinfo::{System, SystemExt};
+use rustls::ServerConfig;
 
-fn handle_read(mut stream: &TcpStream) {
-    let mut buf = [0u8; 4096];
-    match stream.read(&mut buf) {
-        Ok(_) => {
-            let req_str = String::from_utf8_lossy(&buf);
-            println!("{}", req_str);
+mod acme;
+mod api;
+mod compression;
+mod conditional;
+mod config;
+mod connection;
+mod embedded;
+mod events;
+#[cfg(feature = "epoll")]
+mod event_loop;
+mod format;
+mod history;
+mod hpack;
+mod http_client;
+mod http2;
+mod http_date;
+mod metrics;
+mod mime;
+mod negotiate;
+mod pages;
+mod pool;
+mod ranges;
+mod request;
+mod response;
+mod router;
+mod sendfile;
+mod shutdown;
+mod static_files;
+mod stats;
+mod tls;
+mod websocket;
+mod x509;
+
+use acme::Acme;
+use config::{Config, IoMode};
+use events::{EventLog, EventStream};
+use history::History;
+use metrics::Metrics;
+use pool::{PoolStats, StreamLimit, WorkerPool};
+use response::Response;
+use router::Router;
+use static_files::DocRoot;
+use stats::Sampler;
+use tls::{Certificates, ClientAuth};
+
+const TLS_RELOAD_PATH: &str = "/admin/tls/reload";
+
+#[allow(clippy::too_many_arguments)]
+fn build_router(
+    config: &Config,
+    sampler: Arc<Sampler>,
+    history: Arc<History>,
+    metrics: Arc<Metrics>,
+    pool: Option<Arc<PoolStats>>,
+    streams: Arc<StreamLimit>,
+    events: Arc<EventLog>,
+    certs: Option<Arc<Certificates>>,
+    acme: Option<Arc<Acme>>,
+) -> Router {
+    let docs = config.doc_root.as_deref().map(|root| match DocRoot::open(root) {
+        Ok(docs) => {
+            println!("Serving files from {}", docs.root().display());
+            docs
         }
-        Err(e) => println!("Unable to read stream: {}", e),
+        Err(e) => {
+            println!("Unable to serve DOC_ROOT {}: {}", root.display(), e);
+            process::exit(1);
+        }
+    });
+    // A document root on disk takes precedence over the embedded site.
+    let embedded_site = docs.is_none() && embedded::file_count() > 0;
+    if embedded_site {
+        println!("Serving {} embedded files", embedded::file_count());
     }
-}
 
-fn handle_write(mut stream: TcpStream) {
-    // Gather some system stats using sysinfo
-    let mut sys = System::new_all();
-    sys.refresh_all();
-
-    // For demonstration, gather total memory, used memory, number of CPUs, and average CPU usage
-    let total_mem = sys.total_memory();
-    let used_mem = sys.used_memory();
-    
-
-    // Build an HTML response string that includes the stats
-    let response_body = format!(
-        r#"
-            <html>
-                <head>
-                    <meta charset="UTF-8">
-                    <title>Unikernel Stats</title>
-                </head>
-                <body>
-                    <h1>Hello, Unikernel World!</h1>
-                    <p>Here are some system stats:</p>
-                    <ul>
-                        <li><strong>Total Memory:</strong> {} kB</li>
-                        <li><strong>Used Memory:</strong> {} kB</li>                        
-                    </ul>
-                </body>
-            </html>
-        "#,
-        total_mem, used_mem
-    );
-
-    let response = format!(
-        "HTTP/1.1 200 OK\r\n\
-         Content-Type: text/html; charset=UTF-8\r\n\
-         Content-Length: {}\r\n\
-         \r\n\
-         {}",
-        response_body.len(),
-        response_body
-    );
-
-    match stream.write(response.as_bytes()) {
-        Ok(_) => println!("Response sent"),
-        Err(e) => println!("Failed sending response: {}", e),
+    let mut router = Router::new();
+    {
+        let sampler = Arc::clone(&sampler);
+        let metrics = Arc::clone(&metrics);
+        let history = Arc::clone(&history);
+        let pool = pool.clone();
+        let stats_path = if docs.is_some() || embedded_site { "/stats" } else { "/" };
+        router.get(stats_path, move |req, _| {
+            let snapshot = sampler.latest();
+            let offered = ["text/html", "application/json"];
+            let response = match negotiate::media_type(req.headers.get("Accept"), &offered) {
+                Some("application/json") => api::stats_json(&snapshot),
+                Some(_) => pages::stats_page(&snapshot, &history, &metrics, pool.as_deref()),
+                None => Response::status_page(406),
+            };
+            response.with_header("Vary", "Accept")
+        });
+    }
+    {
+        let sampler = Arc::clone(&sampler);
+        router.get("/api/stats", move |_, _| api::stats_json(&sampler.latest()));
+    }
+    router.get("/api/stats/history", move |req, _| api::stats_history(req, &history));
+    {
+        let sampler = Arc::clone(&sampler);
+        let streams = Arc::clone(&streams);
+        let (interval, retry_after) = (config.ws_stats_interval, config.retry_after_secs);
+        router.get("/ws/stats", move |req, _| {
+            let slot = match streams.acquire() {
+                Some(slot) => slot,
+                None => return streams_busy(retry_after),
+            };
+            websocket::accept(req, slot.hold(api::StatsFeed::new(Arc::clone(&sampler), interval)))
+        });
+    }
+    {
+        let sampler = Arc::clone(&sampler);
+        let (stats_interval, heartbeat) = (config.sse_stats_interval, config.sse_heartbeat);
+        let retry_after = config.retry_after_secs;
+        router.get("/events", move |req, _| {
+            let slot = match streams.acquire() {
+                Some(slot) => slot,
+                None => return streams_busy(retry_after),
+            };
+            let last_id = req.headers.get("Last-Event-ID").and_then(|id| id.trim().parse().ok());
+            let stream = EventStream::new(Arc::clone(&events), Arc::clone(&sampler), last_id, stats_interval, heartbeat);
+            Response::new(200)
+                .with_header("Content-Type", "text/event-stream")
+                .with_header("Cache-Control", "no-cache")
+                .with_feed(slot.hold(stream))
+        });
+    }
+    router.get("/metrics", move |req, _| {
+        let offered = ["text/plain", "application/openmetrics-text"];
+        let format = match negotiate::media_type(req.headers.get("Accept"), &offered) {
+            Some("application/openmetrics-text") => metrics::Format::OpenMetrics,
+            _ => metrics::Format::Prometheus,
+        };
+        let (metrics, snapshot, pool) = (Arc::clone(&metrics), sampler.latest(), pool.clone());
+        // Rendered straight onto the connection as it is sent.
+        Response::new(200)
+            .with_header("Content-Type", format.content_type())
+            .with_header("Vary", "Accept")
+            .with_stream(move |w| metrics::render(w, format, &metrics, &snapshot, pool.as_deref()))
+    });
+    router.get("/healthz", |_, _| Response::text(200, "ok\n"));
+    // Only for operators: the route exists when client certificates are
+    // verified and CLIENT_AUTH requires one for it, and then only answers
+    // over HTTPS to a client that sent one.
+    let operator_only = config.tls_client_ca.is_some()
+        && tls::client_auth(&config.client_auth, TLS_RELOAD_PATH) == ClientAuth::Required;
+    match certs {
+        Some(certs) if operator_only => {
+            router.post(TLS_RELOAD_PATH, move |req, _| {
+                if req.tls.is_none() || req.client_cert().is_none() {
+                    return Response::status_page(403);
+                }
+                match certs.reload() {
+                    Ok(count) => Response::text(200, format!("Loaded {} TLS certificates\n", count)),
+                    // The details, paths and all, are only logged.
+                    Err(_) => Response::text(500, "Kept the current TLS certificates; the server log says why\n"),
+                }
+            });
+        }
+        Some(_) => println!("Not serving {}: it needs TLS_CLIENT_CA and a CLIENT_AUTH rule requiring a certificate for it", TLS_RELOAD_PATH),
+        None => {}
     }
+    if let Some(acme) = acme {
+        router.get("/.well-known/acme-challenge/:token", move |_, params| {
+            match acme.key_authorization(params.get("token").unwrap_or_default()) {
+                Some(key_authorization) => Response::text(200, key_authorization),
+                None => Response::status_page(404),
+            }
+        });
+    }
+    // Registered last so that it only sees paths no other route claims.
+    if let Some(docs) = docs {
+        router.get("/*path", move |req, _| docs.serve(req));
+    } else if embedded_site {
+        router.get("/*path", |req, _| embedded::serve(req));
+    }
+    router
 }
 
-fn handle_client(stream: TcpStream) {
-    handle_read(&stream);
-    handle_write(stream);
+// Turns away a stream beyond MAX_STREAMS, like a connection the pool has no
+// room for.
+fn streams_busy(retry_after_secs: u64) -> Response {
+    Response::status_page(503).with_header("Retry-After", retry_after_secs.to_string())
 }
 
 fn main() {
-    let listener = TcpListener::bind("0.0.0.0:8080").unwrap();
+    let config = Arc::new(Config::from_env());
+    let events = EventLog::new(config.sse_buffer_events);
+    let metrics = Arc::new(Metrics::new());
+    // Before anything starts a thread.
+    shutdown::handle_signals(Arc::clone(&events), Arc::clone(&metrics), config.drain_timeout);
+    let listener = TcpListener::bind(("0.0.0.0", config.port)).unwrap();
     println!("Welcome to the ADGSTUDIOS - Unikernel World!");
-    println!("Listening for connections on port 8080");
+    println!("Listening for connections on port {}", config.port);
+    if !config.acme_domains.is_empty() && config.tls_port.is_none() {
+        println!("ACME_DOMAINS needs HTTPS_PORT");
+        process::exit(1);
+    }
+    let https = config.tls_port.map(|port| {
+        let certs = match Certificates::new(&config, Arc::clone(&events)) {
+            Ok(certs) => certs,
+            Err(e) => {
+                println!("Unable to set up HTTPS: {}", e);
+                process::exit(1);
+            }
+        };
+        // `reload` says what went wrong itself.
+        if certs.reload().is_err() {
+            process::exit(1);
+        }
+        let tls = match tls::server_config(&certs, &config) {
+            Ok(tls) => tls,
+            Err(e) => {
+                println!("Unable to set up HTTPS: {}", e);
+                process::exit(1);
+            }
+        };
+        if !config.tls_reload_interval.is_zero() {
+            certs.watch(config.tls_reload_interval);
+        }
+        let acme = (!config.acme_domains.is_empty()).then(|| match Acme::new(&config, Arc::clone(&certs)) {
+            Ok(acme) => acme,
+            Err(e) => {
+                println!("Unable to set up ACME: {}", e);
+                process::exit(1);
+            }
+        });
+        let listener = TcpListener::bind(("0.0.0.0", port)).unwrap();
+        println!("Listening for HTTPS connections on port {}", port);
+        Https { listener, tls, certs, acme }
+    });
+
+    let sampler = Sampler::start(config.stats_interval);
+    let history = History::new(&config.history);
+    history.start(Arc::clone(&sampler), Arc::clone(&metrics));
+    events::watch_memory(Arc::clone(&events), Arc::clone(&sampler), config.memory_warning_percent, config.stats_interval);
+    // Challenges are answered through the listeners bound above, which
+    // queue connections until the server is accepting them.
+    if let Some(acme) = https.as_ref().and_then(|https| https.acme.as_ref()) {
+        acme.start();
+    }
+
+    match config.io_mode {
+        IoMode::Threads => serve_threads(listener, https, config, sampler, history, events, metrics),
+        IoMode::Epoll => serve_epoll(listener, https, config, sampler, history, events, metrics),
+    }
+}
+
+// The HTTPS listener, its TLS settings and the certificates behind them.
+struct Https {
+    listener: TcpListener,
+    tls: Arc<ServerConfig>,
+    certs: Arc<Certificates>,
+    // Keeps a certificate from an ACME CA, when ACME_DOMAINS is set.
+    acme: Option<Arc<Acme>>,
+}
+
+fn serve_threads(
+    listener: TcpListener,
+    https: Option<Https>,
+    config: Arc<Config>,
+    sampler: Arc<Sampler>,
+    history: Arc<History>,
+    events: Arc<EventLog>,
+    metrics: Arc<Metrics>,
+) {
+    let pool_stats = Arc::new(PoolStats::new(config.workers, config.queue_size));
+    let certs = https.as_ref().map(|https| Arc::clone(&https.certs));
+    let acme = https.as_ref().and_then(|https| https.acme.clone());
+    let stats = Some(Arc::clone(&pool_stats));
+    // Each stream keeps a worker, so most of them stay free for requests.
+    let streams = StreamLimit::new(config.max_streams.unwrap_or((config.workers / 4).max(1)));
+    let router = Arc::new(build_router(&config, sampler, history, Arc::clone(&metrics), stats, streams, events, certs, acme));
+    let (tls_listener, tls) = https.map(|https| (https.listener, https.tls)).unzip();
+    // Both listeners feed the same pool; jobs say which one they came from.
+    let pool = {
+        let config = Arc::clone(&config);
+        Arc::new(WorkerPool::new(pool_stats, move |(stream, secure): (TcpStream, bool)| {
+            let tls = if secure { tls.as_ref() } else { None };
+            connection::handle_client(stream, tls, &router, &config, &metrics);
+        }))
+    };
 
+    if let Some(tls_listener) = tls_listener {
+        let pool = Arc::clone(&pool);
+        let config = Arc::clone(&config);
+        thread::spawn(move || accept_loop(tls_listener, true, &pool, &config));
+    }
+    accept_loop(listener, false, &pool, &config);
+}
+
+fn accept_loop(listener: TcpListener, secure: bool, pool: &WorkerPool<(TcpStream, bool)>, config: &Config) {
     for stream in listener.incoming() {
         match stream {
-            Ok(stream) => {
-                thread::spawn(|| {
-                    handle_client(stream);
-                });
-            }
+            // Draining: new clients are just disconnected.
+            Ok(_) if shutdown::draining() => {}
+            Ok(stream) => match pool.submit((stream, secure)) {
+                Ok(()) => {}
+                // A TLS client can't be told anything before the handshake,
+                // so it is just disconnected.
+                Err((_, true)) => {}
+                Err((stream, false)) => connection::reject_overloaded(stream, config.retry_after_secs),
+            },
             Err(e) => {
                 println!("Unable to connect: {}", e);
             }
         }
     }
 }
+
+#[cfg(feature = "epoll")]
+fn serve_epoll(
+    listener: TcpListener,
+    https: Option<Https>,
+    config: Arc<Config>,
+    sampler: Arc<Sampler>,
+    history: Arc<History>,
+    events: Arc<EventLog>,
+    metrics: Arc<Metrics>,
+) {
+    let certs = https.as_ref().map(|https| Arc::clone(&https.certs));
+    let acme = https.as_ref().and_then(|https| https.acme.clone());
+    let streams = StreamLimit::new(config.max_streams.unwrap_or(usize::MAX));
+    let router = build_router(&config, sampler, history, Arc::clone(&metrics), None, streams, events, certs, acme);
+    let tls = https.map(|https| (https.listener, https.tls));
+    if let Err(e) = event_loop::run(listener, tls, &router, &config, &metrics) {
+        println!("Event loop failed: {}", e);
+    }
+}
+
+#[cfg(not(feature = "epoll"))]
+fn serve_epoll(
+    listener: TcpListener,
+    https: Option<Https>,
+    config: Arc<Config>,
+    sampler: Arc<Sampler>,
+    history: Arc<History>,
+    events: Arc<EventLog>,
+    metrics: Arc<Metrics>,
+) {
+    println!("IO_MODE=epoll needs a build with --features epoll; using threads");
+    serve_threads(listener, https, config, sampler, history, events, metrics);
+}
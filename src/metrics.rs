@@ -0,0 +1,284 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::pool::PoolStats;
+use crate::response::Transport;
+use crate::sendfile;
+use crate::stats::Snapshot;
+
+// Upper bounds of the request latency histogram, in seconds.
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Server-side counters, shared by every connection.
+pub struct Metrics {
+    started: Instant,
+    // Keyed by (route pattern, status code).
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    // Responses that failed or timed out part way through.
+    responses_incomplete: AtomicU64,
+    active_connections: AtomicI64,
+    // Per-bucket (not cumulative) counts; the last slot is +Inf.
+    latency_counts: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_nanos: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            started: Instant::now(),
+            requests: Mutex::new(BTreeMap::new()),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            responses_incomplete: AtomicU64::new(0),
+            active_connections: AtomicI64::new(0),
+            latency_counts: Default::default(),
+            latency_sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    // How long the server has been running.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn requests_total(&self) -> u64 {
+        self.requests.lock().unwrap().values().sum()
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    pub fn active_connections(&self) -> i64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn count_request(&self, route: &str, status: u16) {
+        *self.requests.lock().unwrap().entry((route.to_string(), status)).or_insert(0) += 1;
+    }
+
+    // Counts a request and records how long it took to answer.
+    pub fn observe_request(&self, route: &str, status: u16, elapsed: Duration) {
+        self.count_request(route, status);
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|b| secs <= *b).unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn response_incomplete(&self) {
+        self.responses_incomplete.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Counts a connection as active until the guard is dropped, which also
+    // happens when its handler panics.
+    pub fn track_connection(&self) -> OpenConnection<'_> {
+        self.connection_opened();
+        OpenConnection(self)
+    }
+}
+
+pub struct OpenConnection<'a>(&'a Metrics);
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.0.connection_closed();
+    }
+}
+
+// Wraps a connection's stream so every byte read or written is counted.
+pub struct Metered<'a, S> {
+    pub inner: S,
+    pub metrics: &'a Metrics,
+}
+
+impl<S: Read> Read for Metered<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.metrics.add_bytes_in(n);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Metered<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.metrics.add_bytes_out(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for Metered<'_, &TcpStream> {
+    fn send_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<u64> {
+        let n = sendfile::send_file(self.inner, file, offset, len)?;
+        self.metrics.add_bytes_out(n as usize);
+        Ok(n)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // Prometheus text exposition format 0.0.4.
+    Prometheus,
+    // OpenMetrics 1.0 text format.
+    OpenMetrics,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+}
+
+// Renders the system gauges from `snapshot`, the server counters and, in
+// threaded mode, the worker pool occupancy, writing each line as it goes.
+pub fn render(w: &mut dyn Write, format: Format, metrics: &Metrics, snapshot: &Snapshot, pool: Option<&PoolStats>) -> io::Result<()> {
+    let mut out = Exposition { format, out: w };
+
+    out.gauge("unikernel_memory_total_bytes", "Total RAM.", snapshot.total_memory as f64)?;
+    out.gauge("unikernel_memory_used_bytes", "RAM in use.", snapshot.used_memory as f64)?;
+    out.gauge("unikernel_memory_free_bytes", "Unused RAM.", snapshot.free_memory as f64)?;
+    out.gauge(
+        "unikernel_memory_available_bytes",
+        "RAM available for new allocations.",
+        snapshot.available_memory as f64,
+    )?;
+    out.gauge("unikernel_swap_total_bytes", "Total swap.", snapshot.total_swap as f64)?;
+    out.gauge("unikernel_swap_used_bytes", "Swap in use.", snapshot.used_swap as f64)?;
+    out.gauge("unikernel_cpu_count", "Number of logical CPUs.", snapshot.cpu_count as f64)?;
+    out.gauge(
+        "unikernel_cpu_usage_ratio",
+        "CPU usage since the previous sample, averaged over all CPUs.",
+        snapshot.cpu_usage as f64 / 100.0,
+    )?;
+    out.header("unikernel_cpu_core_usage_ratio", "gauge", "CPU usage of each CPU since the previous sample.")?;
+    for (i, usage) in snapshot.cpu_usage_per_core.iter().enumerate() {
+        out.sample("unikernel_cpu_core_usage_ratio", &[("cpu", &i.to_string())], *usage as f64 / 100.0)?;
+    }
+    out.header("unikernel_load_average", "gauge", "System load average.")?;
+    for (period, value) in ["1m", "5m", "15m"].iter().zip(snapshot.load_average) {
+        out.sample("unikernel_load_average", &[("period", period)], value)?;
+    }
+    out.gauge("unikernel_uptime_seconds", "Seconds since boot.", snapshot.uptime_secs as f64)?;
+
+    out.header("http_requests", "counter", "Requests answered, by route pattern and status code.")?;
+    // Copied so the lock isn't held while writing to the client.
+    let requests = metrics.requests.lock().unwrap().clone();
+    for ((route, status), count) in &requests {
+        out.sample("http_requests_total", &[("route", route), ("status", &status.to_string())], *count as f64)?;
+    }
+    out.counter("http_request_bytes", "Bytes received from clients.", metrics.bytes_in())?;
+    out.counter("http_response_bytes", "Bytes sent to clients.", metrics.bytes_out())?;
+    out.counter(
+        "http_responses_incomplete",
+        "Responses cut short by a write error or timeout.",
+        metrics.responses_incomplete.load(Ordering::Relaxed),
+    )?;
+    out.gauge(
+        "http_active_connections",
+        "Currently open client connections.",
+        metrics.active_connections() as f64,
+    )?;
+
+    out.header("http_request_duration_seconds", "histogram", "Time from a request being read to its response being sent.")?;
+    let mut cumulative = 0;
+    for (i, count) in metrics.latency_counts.iter().enumerate() {
+        cumulative += count.load(Ordering::Relaxed);
+        let le = LATENCY_BUCKETS.get(i).map(|b| format!("{:?}", b)).unwrap_or_else(|| "+Inf".to_string());
+        out.sample("http_request_duration_seconds_bucket", &[("le", &le)], cumulative as f64)?;
+    }
+    let sum = metrics.latency_sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+    out.sample("http_request_duration_seconds_sum", &[], sum)?;
+    out.sample("http_request_duration_seconds_count", &[], cumulative as f64)?;
+
+    if let Some(pool) = pool {
+        out.gauge("http_workers", "Worker threads in the pool.", pool.workers as f64)?;
+        out.gauge("http_workers_busy", "Worker threads serving a connection.", pool.busy() as f64)?;
+        out.gauge("http_queue_capacity", "Accepted connections that may wait for a worker.", pool.queue_capacity as f64)?;
+        out.gauge("http_queue_length", "Accepted connections waiting for a worker.", pool.queued() as f64)?;
+        out.counter("http_connections_rejected", "Connections turned away with 503 because the queue was full.", pool.rejected())?;
+    }
+
+    if format == Format::OpenMetrics {
+        out.out.write_all(b"# EOF\n")?;
+    }
+    Ok(())
+}
+
+struct Exposition<'a> {
+    format: Format,
+    out: &'a mut dyn Write,
+}
+
+impl Exposition<'_> {
+    // Counter families are named without `_total` in OpenMetrics metadata but
+    // with it in Prometheus text; samples always carry the suffix.
+    fn header(&mut self, family: &str, kind: &str, help: &str) -> io::Result<()> {
+        let name = if kind == "counter" && self.format == Format::Prometheus {
+            format!("{}_total", family)
+        } else {
+            family.to_string()
+        };
+        writeln!(self.out, "# HELP {} {}", name, help)?;
+        writeln!(self.out, "# TYPE {} {}", name, kind)
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) -> io::Result<()> {
+        self.out.write_all(name.as_bytes())?;
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape(v))).collect();
+            write!(self.out, "{{{}}}", labels.join(","))?;
+        }
+        writeln!(self.out, " {}", value)
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: f64) -> io::Result<()> {
+        self.header(name, "gauge", help)?;
+        self.sample(name, &[], value)
+    }
+
+    fn counter(&mut self, family: &str, help: &str, value: u64) -> io::Result<()> {
+        self.header(family, "counter", help)?;
+        self.sample(&format!("{}_total", family), &[], value as f64)
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,94 @@
+// `Accept`-style header negotiation (RFC 9110, section 12.5).
+
+// Picks the entry of `offered` the client prefers, by q-value and then by the
+// order of `offered`. A missing header accepts anything. Returns `None` when
+// nothing offered is acceptable.
+pub fn media_type<'a>(accept: Option<&str>, offered: &[&'a str]) -> Option<&'a str> {
+    let accept = match accept {
+        Some(a) if !a.trim().is_empty() => a,
+        _ => return offered.first().copied(),
+    };
+    let ranges: Vec<(&str, f32)> = accept.split(',').filter_map(parse_item).collect();
+
+    let mut best: Option<(&str, f32)> = None;
+    for &candidate in offered {
+        // The most specific matching range decides the quality.
+        let q = ranges
+            .iter()
+            .filter(|(range, _)| media_range_matches(range, candidate))
+            .max_by_key(|(range, _)| specificity(range))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((candidate, q));
+        }
+    }
+    best.map(|(c, _)| c)
+}
+
+// Picks the content coding of `offered` the client prefers, by q-value and
+// then by the order of `offered`. `None` means send the body as it is: the
+// header is missing, nothing offered is acceptable, or the client ranks
+// `identity` above every coding on offer.
+pub fn encoding<'a>(accept_encoding: Option<&str>, offered: &[&'a str]) -> Option<&'a str> {
+    let codings: Vec<(&str, f32)> = accept_encoding?.split(',').filter_map(parse_item).collect();
+    let quality = |coding: &str| {
+        codings
+            .iter()
+            .find(|(c, _)| c.eq_ignore_ascii_case(coding))
+            .or_else(|| codings.iter().find(|(c, _)| *c == "*"))
+            .map(|(_, q)| *q)
+    };
+
+    let mut best: Option<(&str, f32)> = None;
+    for &candidate in offered {
+        let q = quality(candidate).unwrap_or(0.0);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((candidate, q));
+        }
+    }
+    let identity = codings.iter().find(|(c, _)| c.eq_ignore_ascii_case("identity")).map(|(_, q)| *q);
+    match (best, identity) {
+        (Some((_, q)), Some(identity_q)) if identity_q > q => None,
+        (best, _) => best.map(|(c, _)| c),
+    }
+}
+
+// Splits "text/html;level=1;q=0.5" into ("text/html", 0.5). Parameters other
+// than q are ignored.
+fn parse_item(item: &str) -> Option<(&str, f32)> {
+    let mut parts = item.split(';');
+    let value = parts.next()?.trim();
+    if value.is_empty() {
+        return None;
+    }
+    let mut q = 1.0;
+    for param in parts {
+        if let Some((name, v)) = param.split_once('=') {
+            if name.trim().eq_ignore_ascii_case("q") {
+                q = v.trim().parse().unwrap_or(0.0);
+            }
+        }
+    }
+    Some((value, q))
+}
+
+fn media_range_matches(range: &str, media_type: &str) -> bool {
+    if range == "*/*" {
+        return true;
+    }
+    match range.strip_suffix("/*") {
+        Some(kind) => media_type.split('/').next().is_some_and(|t| t.eq_ignore_ascii_case(kind)),
+        None => range.eq_ignore_ascii_case(media_type),
+    }
+}
+
+fn specificity(range: &str) -> u8 {
+    if range == "*/*" {
+        0
+    } else if range.ends_with("/*") {
+        1
+    } else {
+        2
+    }
+}
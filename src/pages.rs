@@ -0,0 +1,275 @@
+use std::fmt::Display;
+
+use crate::format;
+use crate::history::{History, Metric};
+use crate::metrics::Metrics;
+use crate::pool::PoolStats;
+use crate::response::Response;
+use crate::stats::Snapshot;
+
+// Sparklines are drawn this size, in pixels.
+const SPARK_WIDTH: f64 = 160.0;
+const SPARK_HEIGHT: f64 = 24.0;
+
+pub fn stats_page(snapshot: &Snapshot, history: &History, metrics: &Metrics, pool: Option<&PoolStats>) -> Response {
+    let pool_items = match pool {
+        Some(pool) => format!(
+            r#"<li><strong>Busy Workers:</strong> {} / {}</li>
+                        <li><strong>Queued Connections:</strong> {} / {}</li>
+                        <li><strong>Rejected Connections:</strong> {}</li>"#,
+            pool.busy(),
+            pool.workers,
+            pool.queued(),
+            pool.queue_capacity,
+            pool.rejected()
+        ),
+        None => "<li><strong>I/O Mode:</strong> epoll</li>".to_string(),
+    };
+
+    let core_items: Vec<String> = snapshot
+        .cpu_usage_per_core
+        .iter()
+        .enumerate()
+        .map(|(i, usage)| {
+            format!(
+                "<li><strong>CPU {}:</strong> {}</li>",
+                i,
+                live(&format!("cpu.per_core_usage_percent.{}", i), usage, "%", format::percent(*usage as f64))
+            )
+        })
+        .collect();
+
+    let server_secs = metrics.uptime().as_secs_f64().max(1.0);
+    let requests = metrics.requests_total();
+    let (bytes_in, bytes_out) = (metrics.bytes_in(), metrics.bytes_out());
+    let [load1, load5, load15] = snapshot.load_average;
+
+    let trends = [
+        (Metric::Memory, "Memory Used"),
+        (Metric::Cpu, "CPU Usage"),
+        (Metric::Load, "Load Average"),
+        (Metric::ServerBytesIn, "Server Received"),
+        (Metric::ServerBytesOut, "Server Sent"),
+        (Metric::Requests, "Requests"),
+    ];
+    let trend_items: Vec<String> = trends.iter().map(|(metric, label)| trend(history, *metric, label)).collect();
+
+    // Build an HTML response string that includes the stats
+    let response_body = format!(
+        r#"
+            <html>
+                <head>
+                    <meta charset="UTF-8">
+                    <title>Unikernel Stats</title>
+                </head>
+                <body>
+                    <h1>Hello, Unikernel World!</h1>
+                    <p>Here are some system stats: <em id="live">as of page load</em></p>
+                    <h2>System</h2>
+                    <ul>
+                        <li><strong>Hostname:</strong> {}</li>
+                        <li><strong>OS:</strong> {} {}</li>
+                        <li><strong>Kernel:</strong> {}</li>
+                        <li><strong>Uptime:</strong> {}</li>
+                        <li><strong>Booted:</strong> {}</li>
+                    </ul>
+                    <h2>CPU</h2>
+                    <ul>
+                        <li><strong>Model:</strong> {}</li>
+                        <li><strong>CPUs:</strong> {} at {} MHz</li>
+                        <li><strong>CPU Usage:</strong> {}</li>
+                        {}
+                        <li><strong>Load Average:</strong> {}, {}, {}</li>
+                    </ul>
+                    <h2>Memory</h2>
+                    <ul>
+                        <li><strong>Total Memory:</strong> {}</li>
+                        <li><strong>Used Memory:</strong> {} (<span id="memory-used-percent">{}</span>)</li>
+                        <li><strong>Available Memory:</strong> {}</li>
+                        <li><strong>Swap:</strong> {} of {} used</li>
+                    </ul>
+                    <p>And some server stats:</p>
+                    <ul>
+                        <li><strong>Requests Served:</strong> {} ({} average)</li>
+                        <li><strong>Received:</strong> {} ({} average)</li>
+                        <li><strong>Sent:</strong> {} ({} average)</li>
+                        {}
+                    </ul>
+                    <h2>Trends</h2>
+                    <ul>
+                        {}
+                    </ul>
+                    <script>{}</script>
+                </body>
+            </html>
+        "#,
+        escape(&snapshot.hostname),
+        escape(&snapshot.os_name),
+        escape(&snapshot.os_version),
+        escape(&snapshot.kernel_version),
+        live("uptime_secs", snapshot.uptime_secs, "seconds", format::duration(snapshot.uptime_secs)),
+        live("boot_time", snapshot.boot_time, "unix time", format::timestamp(snapshot.boot_time)),
+        escape(&snapshot.cpu_brand),
+        snapshot.cpu_count,
+        live("cpu.frequency_mhz", snapshot.cpu_frequency_mhz, "MHz", snapshot.cpu_frequency_mhz.to_string()),
+        live("cpu.usage_percent", snapshot.cpu_usage, "%", format::percent(snapshot.cpu_usage as f64)),
+        core_items.join("\n                        "),
+        live("load_average.one", load1, "", format!("{:.2}", load1)),
+        live("load_average.five", load5, "", format!("{:.2}", load5)),
+        live("load_average.fifteen", load15, "", format!("{:.2}", load15)),
+        live("memory.total_bytes", snapshot.total_memory, "bytes", format::bytes(snapshot.total_memory)),
+        live("memory.used_bytes", snapshot.used_memory, "bytes", format::bytes(snapshot.used_memory)),
+        format::percent(ratio(snapshot.used_memory, snapshot.total_memory)),
+        live("memory.available_bytes", snapshot.available_memory, "bytes", format::bytes(snapshot.available_memory)),
+        live("swap.used_bytes", snapshot.used_swap, "bytes", format::bytes(snapshot.used_swap)),
+        live("swap.total_bytes", snapshot.total_swap, "bytes", format::bytes(snapshot.total_swap)),
+        requests,
+        format::rate(requests as f64 / server_secs, "req"),
+        value(bytes_in, "bytes", format::bytes(bytes_in)),
+        format::byte_rate(bytes_in as f64 / server_secs),
+        value(bytes_out, "bytes", format::bytes(bytes_out)),
+        format::byte_rate(bytes_out as f64 / server_secs),
+        pool_items,
+        trend_items.join("\n                        "),
+        LIVE_SCRIPT
+    );
+
+    Response::html(response_body)
+}
+
+// A formatted value with the exact figure kept in a tooltip and a data
+// attribute, so nothing is lost to rounding.
+fn value(raw: impl Display, unit: &str, text: String) -> String {
+    format!(r#"<span title="{raw} {unit}" data-value="{raw}">{text}</span>"#)
+}
+
+// A `value` the page keeps up to date from `/ws/stats`. `field` is its path
+// in the `/api/stats` document; its unit picks how the script formats it.
+fn live(field: &str, raw: impl Display, unit: &str, text: String) -> String {
+    let title = format!("{} {}", raw, unit);
+    format!(
+        r#"<span title="{}" data-value="{raw}" data-field="{field}" data-unit="{unit}">{text}</span>"#,
+        title.trim_end()
+    )
+}
+
+// A sparkline of what the finest history tier holds for `metric`, with its
+// range. The full series is at `/api/stats/history`.
+fn trend(history: &History, metric: Metric, label: &str) -> String {
+    let series = history.recent(metric);
+    let points = &series.points;
+    if points.len() < 2 {
+        return format!("<li><strong>{}:</strong> collecting</li>", label);
+    }
+    let (lo, hi) = points.iter().fold((f64::MAX, f64::MIN), |(lo, hi), (_, v)| (lo.min(*v), hi.max(*v)));
+    let span = points[points.len() - 1].0 - points[0].0 + series.step;
+    format!(
+        "<li><strong>{}:</strong> {} {} to {} over the last {}</li>",
+        label,
+        sparkline(points),
+        show(metric, lo),
+        show(metric, hi),
+        format::duration(span)
+    )
+}
+
+// An inline SVG line through `points`, time across and value up, scaled to
+// fill the box.
+fn sparkline(points: &[(u64, f64)]) -> String {
+    let (first, last) = (points[0].0, points[points.len() - 1].0);
+    let (lo, hi) = points.iter().fold((f64::MAX, f64::MIN), |(lo, hi), (_, v)| (lo.min(*v), hi.max(*v)));
+    let coords: Vec<String> = points
+        .iter()
+        .map(|(t, v)| {
+            let x = (t - first) as f64 / (last - first).max(1) as f64 * SPARK_WIDTH;
+            // A flat line sits in the middle; otherwise leave a pixel for the
+            // stroke at either edge.
+            let y = if hi > lo { SPARK_HEIGHT - 1.0 - (v - lo) / (hi - lo) * (SPARK_HEIGHT - 2.0) } else { SPARK_HEIGHT / 2.0 };
+            format!("{:.1},{:.1}", x, y)
+        })
+        .collect();
+    format!(
+        r#"<svg width="{w}" height="{h}" viewBox="0 0 {w} {h}" style="vertical-align: middle"><polyline fill="none" stroke="currentColor" stroke-width="1" points="{}"/></svg>"#,
+        coords.join(" "),
+        w = SPARK_WIDTH,
+        h = SPARK_HEIGHT
+    )
+}
+
+fn show(metric: Metric, value: f64) -> String {
+    match metric {
+        Metric::Memory => format::bytes(value as u64),
+        Metric::Cpu => format::percent(value),
+        Metric::Load => format!("{:.2}", value),
+        Metric::ServerBytesIn | Metric::ServerBytesOut => format::byte_rate(value),
+        Metric::Requests => format::rate(value, "req"),
+    }
+}
+
+// Follows the `/ws/stats` feed, reconnecting if it drops, and rewrites every
+// `live` value as snapshots arrive. The formatting mirrors `format`.
+const LIVE_SCRIPT: &str = r#"
+(() => {
+    const units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    const bytes = n => {
+        if (n < 1024) return n + " B";
+        let i = 0;
+        while (n >= 1024 && i < units.length - 1) { n /= 1024; i++; }
+        return n.toFixed(1) + " " + units[i];
+    };
+    const duration = s => {
+        const parts = [[Math.floor(s / 86400), "d"], [Math.floor(s / 3600) % 24, "h"], [Math.floor(s / 60) % 60, "m"], [s % 60, "s"]];
+        const i = parts.findIndex(([n]) => n > 0);
+        if (i < 0 || i == parts.length - 1) return s + "s";
+        return parts[i].join("") + " " + parts[i + 1].join("");
+    };
+    const formats = {
+        "bytes": bytes,
+        "%": v => v.toFixed(1) + "%",
+        "seconds": duration,
+        "unix time": s => new Date(s * 1000).toISOString().slice(0, 19).replace("T", " ") + " UTC",
+        "MHz": String,
+        "": v => v.toFixed(2),
+    };
+    const status = document.getElementById("live");
+    let delay = 1000;
+    const connect = () => {
+        const ws = new WebSocket((location.protocol == "https:" ? "wss://" : "ws://") + location.host + "/ws/stats");
+        ws.onopen = () => { delay = 1000; };
+        ws.onmessage = event => {
+            const stats = JSON.parse(event.data);
+            for (const el of document.querySelectorAll("[data-field]")) {
+                const v = el.dataset.field.split(".").reduce((o, key) => o == null ? o : o[key], stats);
+                if (v == null) continue;
+                const unit = el.dataset.unit;
+                el.dataset.value = v;
+                el.title = unit ? v + " " + unit : String(v);
+                el.textContent = formats[unit](v);
+            }
+            const memory = stats.memory;
+            document.getElementById("memory-used-percent").textContent =
+                formats["%"](memory.total_bytes ? memory.used_bytes * 100 / memory.total_bytes : 0);
+            status.textContent = "live, as of " + new Date(stats.timestamp * 1000).toLocaleTimeString();
+        };
+        ws.onclose = () => {
+            status.textContent = "live updates lost, reconnecting";
+            setTimeout(connect, delay);
+            delay = Math.min(delay * 2, 30000);
+        };
+    };
+    if (window.WebSocket) connect();
+})();
+"#;
+
+fn ratio(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+// Strings reported by the system end up in markup, so escape them.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
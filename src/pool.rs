@@ -0,0 +1,162 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::response::Feed;
+use crate::websocket::{Handler, Message};
+
+// Occupancy counters shared between the pool and the stats page.
+pub struct PoolStats {
+    pub workers: usize,
+    pub queue_capacity: usize,
+    busy: AtomicUsize,
+    queued: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+impl PoolStats {
+    pub fn new(workers: usize, queue_capacity: usize) -> PoolStats {
+        PoolStats {
+            workers,
+            queue_capacity,
+            busy: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::Relaxed)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+// A fixed set of worker threads fed from a bounded queue. Jobs that don't fit
+// in the queue are handed back to the caller instead of being buffered.
+pub struct WorkerPool<T> {
+    sender: SyncSender<T>,
+    stats: Arc<PoolStats>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn new<F>(stats: Arc<PoolStats>, handler: F) -> WorkerPool<T>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(stats.queue_capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+
+        for id in 0..stats.workers {
+            let receiver = Arc::clone(&receiver);
+            let handler = Arc::clone(&handler);
+            let stats = Arc::clone(&stats);
+            thread::Builder::new()
+                .name(format!("worker-{}", id))
+                .spawn(move || worker_loop(&receiver, &*handler, &stats))
+                .expect("failed to spawn worker thread");
+        }
+
+        WorkerPool { sender, stats }
+    }
+
+    // Queues `job` for the next free worker, or returns it if the queue is full.
+    pub fn submit(&self, job: T) -> Result<(), T> {
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        match self.sender.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => {
+                self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(job)
+            }
+        }
+    }
+}
+
+fn worker_loop<T>(receiver: &Mutex<Receiver<T>>, handler: &dyn Fn(T), stats: &PoolStats) {
+    loop {
+        // Only hold the lock while waiting for a job, not while running it.
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        stats.queued.fetch_sub(1, Ordering::Relaxed);
+        stats.busy.fetch_add(1, Ordering::Relaxed);
+        // A panicking handler only loses its own connection, not the worker.
+        if panic::catch_unwind(AssertUnwindSafe(|| handler(job))).is_err() {
+            println!("Worker {} recovered from a panic", thread::current().name().unwrap_or("?"));
+        }
+        stats.busy.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Caps the long-lived streams (WebSockets and event streams) open at once.
+// In threaded mode each one keeps a worker until it ends, so without a cap
+// enough of them would leave none for ordinary requests.
+pub struct StreamLimit {
+    max: usize,
+    open: AtomicUsize,
+}
+
+impl StreamLimit {
+    pub fn new(max: usize) -> Arc<StreamLimit> {
+        Arc::new(StreamLimit { max, open: AtomicUsize::new(0) })
+    }
+
+    // Takes a slot, or returns `None` if all of them are in use.
+    pub fn acquire(self: &Arc<StreamLimit>) -> Option<StreamSlot> {
+        self.open
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| (open < self.max).then_some(open + 1))
+            .ok()?;
+        Some(StreamSlot(Arc::clone(self)))
+    }
+}
+
+// One stream's place under the limit, given back when dropped.
+pub struct StreamSlot(Arc<StreamLimit>);
+
+impl StreamSlot {
+    // Ties the slot to a WebSocket handler or feed, so it is held for as long
+    // as the stream runs.
+    pub fn hold<T>(self, stream: T) -> Limited<T> {
+        Limited { stream, _slot: self }
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Limited<T> {
+    stream: T,
+    _slot: StreamSlot,
+}
+
+impl<T: Handler> Handler for Limited<T> {
+    fn on_message(&mut self, message: Message) -> Option<Message> {
+        self.stream.on_message(message)
+    }
+
+    fn poll(&mut self, now: Instant) -> Option<Message> {
+        self.stream.poll(now)
+    }
+}
+
+impl<T: Feed> Feed for Limited<T> {
+    fn poll(&mut self, now: Instant, out: &mut Vec<u8>) -> bool {
+        self.stream.poll(now, out)
+    }
+}
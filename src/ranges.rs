@@ -0,0 +1,187 @@
+// Byte-range requests (RFC 9110, section 14). Handlers whose responses can be
+// served in parts mark them with `Accept-Ranges: bytes`; `apply` then answers
+// a `Range` request with 206 Partial Content, as a single part or as
+// multipart/byteranges, or with 416 when no range fits the body.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+use crate::http_date;
+use crate::request::{Method, Request};
+use crate::response::{Body, Response};
+
+// More ranges than this (after merging) are treated as no `Range` at all,
+// rather than building a response out of thousands of tiny parts.
+const MAX_RANGES: usize = 16;
+
+pub fn apply(req: &Request, response: Response) -> Response {
+    if response.status != 200 || req.method != Method::Get || response.header("Accept-Ranges") != Some("bytes") {
+        return response;
+    }
+    let header = match req.headers.get("Range") {
+        Some(header) => header,
+        None => return response,
+    };
+    // The client's copy is out of date, so it wants the whole thing.
+    if let Some(if_range) = req.headers.get("If-Range") {
+        if !if_range_matches(if_range, &response) {
+            return response;
+        }
+    }
+
+    let len = match response.body.len() {
+        Some(len) => len,
+        None => return response,
+    };
+    let ranges = match parse(header, len) {
+        Some(ranges) if ranges.len() <= MAX_RANGES => ranges,
+        _ => return response,
+    };
+    match ranges.as_slice() {
+        [] => Response::status_page(416).with_header("Content-Range", format!("bytes */{}", len)),
+        [(start, end)] => {
+            let mut response = response.with_header("Content-Range", content_range(*start, *end, len));
+            response.status = 206;
+            response.body = response.body.slice(*start, *end);
+            response
+        }
+        _ => multipart(response, &ranges, len),
+    }
+}
+
+fn multipart(response: Response, ranges: &[(u64, u64)], len: u64) -> Response {
+    let boundary = format!("{:016x}", RandomState::new().hash_one(len));
+    let content_type = response.header("Content-Type").map(str::to_string);
+
+    let mut parts = Vec::new();
+    for (i, (start, end)) in ranges.iter().enumerate() {
+        let mut head = if i == 0 { String::new() } else { "\r\n".to_string() };
+        head.push_str(&format!("--{}\r\n", boundary));
+        if let Some(content_type) = &content_type {
+            head.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        head.push_str(&format!("Content-Range: {}\r\n\r\n", content_range(*start, *end, len)));
+        parts.push(Body::Bytes(head.into_bytes()));
+        parts.push(response.body.slice(*start, *end));
+    }
+    parts.push(Body::Bytes(format!("\r\n--{}--\r\n", boundary).into_bytes()));
+
+    let mut response = response
+        .without_header("Content-Type")
+        .with_header("Content-Type", format!("multipart/byteranges; boundary={}", boundary));
+    response.status = 206;
+    response.body = Body::Parts(parts);
+    response
+}
+
+fn content_range(start: u64, end: u64, len: u64) -> String {
+    format!("bytes {}-{}/{}", start, end - 1, len)
+}
+
+// `If-Range` holds either an entity-tag, which has to match strongly, or the
+// `Last-Modified` date the client saw, which has to match exactly.
+fn if_range_matches(value: &str, response: &Response) -> bool {
+    let value = value.trim();
+    if value.starts_with('"') {
+        response.header("ETag") == Some(value)
+    } else if value.starts_with("W/") {
+        false
+    } else {
+        let last_modified = response.header("Last-Modified").and_then(http_date::parse);
+        http_date::parse(value).is_some_and(|date| last_modified == Some(date))
+    }
+}
+
+// Parses a `bytes=` range set against a body of `len` bytes into sorted,
+// non-overlapping `start..end` ranges, merging any that overlap or touch.
+// `None` means the header is malformed or uses another unit and should be
+// ignored; an empty list means none of the ranges is satisfiable.
+fn parse(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+    let (unit, set) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    let mut specs = 0;
+    for spec in set.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        specs += 1;
+        let (first, last) = spec.split_once('-')?;
+        if first.is_empty() {
+            // "-500": the last 500 bytes.
+            let suffix = number(last)?;
+            if suffix > 0 && len > 0 {
+                ranges.push((len.saturating_sub(suffix), len));
+            }
+        } else {
+            let first = number(first)?;
+            let last = if last.is_empty() { u64::MAX } else { number(last)? };
+            if last < first {
+                return None;
+            }
+            if first < len {
+                ranges.push((first, last.min(len - 1) + 1));
+            }
+        }
+    }
+    if specs == 0 {
+        return None;
+    }
+
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Some(merged)
+}
+
+fn number(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse("bytes=0-499", 1000), Some(vec![(0, 500)]));
+        assert_eq!(parse("bytes=500-", 1000), Some(vec![(500, 1000)]));
+        assert_eq!(parse("bytes=-200", 1000), Some(vec![(800, 1000)]));
+        assert_eq!(parse("bytes=900-5000", 1000), Some(vec![(900, 1000)]));
+        assert_eq!(parse("bytes=-5000", 1000), Some(vec![(0, 1000)]));
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_ranges() {
+        assert_eq!(parse("bytes=0-9, 20-29", 100), Some(vec![(0, 10), (20, 30)]));
+        assert_eq!(parse("bytes=20-29,0-9", 100), Some(vec![(0, 10), (20, 30)]));
+        assert_eq!(parse("bytes=0-9,10-19", 100), Some(vec![(0, 20)]));
+        assert_eq!(parse("bytes=0-50,25-75", 100), Some(vec![(0, 76)]));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_empty() {
+        assert_eq!(parse("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(parse("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(parse("bytes=0-0", 0), Some(vec![]));
+        assert_eq!(parse("bytes=2000-3000, 0-9", 1000), Some(vec![(0, 10)]));
+    }
+
+    #[test]
+    fn malformed_ranges_are_ignored() {
+        assert_eq!(parse("items=0-9", 100), None);
+        assert_eq!(parse("bytes=", 100), None);
+        assert_eq!(parse("bytes=9-0", 100), None);
+        assert_eq!(parse("bytes=a-b", 100), None);
+        assert_eq!(parse("bytes=+1-2", 100), None);
+        assert_eq!(parse("bytes=0-9,x", 100), None);
+    }
+}
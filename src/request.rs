@@ -0,0 +1,799 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::mem;
+use std::ops::Range;
+
+use crate::router;
+use crate::tls::TlsInfo;
+use crate::x509::Names;
+
+// Limits applied while parsing. Anything larger is rejected instead of buffered.
+const MAX_REQUEST_LINE: usize = 8 * 1024;
+const MAX_HEAD: usize = 16 * 1024;
+pub const MAX_HEADERS: usize = 100;
+pub const MAX_BODY: usize = 1024 * 1024;
+// A chunk-size line, including any chunk extensions.
+const MAX_CHUNK_LINE: usize = 1024;
+// What chunked framing (size lines, extensions, line endings) may add to a
+// body on the wire, so one sent a byte per chunk can't take 1000 times
+// `MAX_BODY` to arrive.
+const MAX_CHUNK_FRAMING: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    Trace,
+    Connect,
+    Other(String),
+}
+
+impl Method {
+    pub fn parse(token: &str) -> Method {
+        match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            "TRACE" => Method::Trace,
+            "CONNECT" => Method::Connect,
+            other => Method::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Other(s) => s,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+    Http2,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Version::Http10 => f.write_str("HTTP/1.0"),
+            Version::Http11 => f.write_str("HTTP/1.1"),
+            Version::Http2 => f.write_str("HTTP/2"),
+        }
+    }
+}
+
+// Header fields in the order they were received. Lookups are case-insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // True if any `name` header lists `token` in its comma-separated value.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn push(&mut self, name: String, value: String) {
+        self.entries.push((name, value));
+    }
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: Method,
+    pub target: String,
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    // Fields sent after a chunked body. Kept apart from `headers` so that a
+    // trailer can never stand in for a header the client didn't send.
+    pub trailers: HeaderMap,
+    // Set by the connection for requests that arrived over TLS.
+    pub tls: Option<TlsInfo>,
+}
+
+impl Request {
+    // The client's verified certificate, unless the route's policy is to
+    // ignore it.
+    pub fn client_cert(&self) -> Option<&Names> {
+        self.tls.as_ref()?.client_cert.as_ref()
+    }
+
+    // The path component of the target, without the query string.
+    pub fn path(&self) -> &str {
+        let target = match self.target.find("://") {
+            // absolute-form: skip the scheme and authority
+            Some(i) => {
+                let rest = &self.target[i + 3..];
+                rest.find('/').map(|j| &rest[j..]).unwrap_or("/")
+            }
+            None => &self.target,
+        };
+        target.split('?').next().unwrap_or("")
+    }
+
+    // The first value given for query parameter `name`, percent-decoded and
+    // with `+` read as a space. A parameter without `=` has an empty value.
+    pub fn query(&self, name: &str) -> Option<String> {
+        let (_, query) = self.target.split_once('?')?;
+        query.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |s: &str| router::percent_decode(&s.replace('+', " "));
+            (decode(key) == name).then(|| decode(value))
+        })
+    }
+
+    // Whether the client wants the connection kept open after this request.
+    // HTTP/1.1 defaults to persistent, HTTP/1.0 has to ask for it, and
+    // HTTP/2 ends connections with frames instead.
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http2 => true,
+            Version::Http11 => !self.headers.has_token("Connection", "close"),
+            Version::Http10 => self.headers.has_token("Connection", "keep-alive"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    BadRequest(&'static str),
+    UriTooLong,
+    HeadersTooLarge,
+    PayloadTooLarge,
+    NotImplemented(&'static str),
+    VersionNotSupported,
+}
+
+impl ParseError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ParseError::BadRequest(_) => 400,
+            ParseError::PayloadTooLarge => 413,
+            ParseError::UriTooLong => 414,
+            ParseError::HeadersTooLarge => 431,
+            ParseError::NotImplemented(_) => 501,
+            ParseError::VersionNotSupported => 505,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::BadRequest(why) => write!(f, "bad request: {}", why),
+            ParseError::UriTooLong => f.write_str("request target too long"),
+            ParseError::HeadersTooLarge => f.write_str("request header fields too large"),
+            ParseError::PayloadTooLarge => f.write_str("request body too large"),
+            ParseError::NotImplemented(what) => write!(f, "not implemented: {}", what),
+            ParseError::VersionNotSupported => f.write_str("HTTP version not supported"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Parse(ParseError),
+    Io(io::Error),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> Self {
+        ReadError::Parse(e)
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Parse(e) => e.fmt(f),
+            ReadError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+// Reads from `reader` until `buf` holds a complete request, which is then
+// removed from the front of `buf`. Bytes past the end of the request are left
+// in place. Returns `Ok(None)` when the peer closes before sending anything.
+pub fn read_request<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> Result<Option<Request>, ReadError> {
+    let mut chunk = [0u8; 4096];
+    let mut parser = Parser::default();
+    loop {
+        if let Some((request, used)) = parser.parse(buf)? {
+            buf.drain(..used);
+            return Ok(Some(request));
+        }
+        let n = match reader.read(&mut chunk) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if n == 0 {
+            if buf.iter().all(|b| *b == b'\r' || *b == b'\n') {
+                return Ok(None);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-request").into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// Parses a request as its bytes arrive. Once the head is in, it is kept along
+// with how far the body has been decoded, so each call only looks at what is
+// new. Between calls `buf` may only grow at the end.
+#[derive(Default)]
+pub struct Parser {
+    pending: Option<Pending>,
+}
+
+// A request whose head has been parsed but whose body hasn't all arrived.
+struct Pending {
+    method: Method,
+    target: String,
+    version: Version,
+    headers: HeaderMap,
+    // Where the body starts in `buf`.
+    body_start: usize,
+    framing: Framing,
+}
+
+impl Parser {
+    // Attempts to parse one request from the start of `buf`. Returns the
+    // request and the number of bytes it occupied, or `None` if more input is
+    // needed. After a request or an error the parser starts afresh, on a
+    // buffer the caller has removed the request from.
+    pub fn parse(&mut self, buf: &[u8]) -> Result<Option<(Request, usize)>, ParseError> {
+        let mut pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => match parse_head(buf)? {
+                Some(pending) => pending,
+                None => return Ok(None),
+            },
+        };
+        let body_start = pending.body_start;
+        let complete = match &mut pending.framing {
+            Framing::Length(len) => {
+                let total = body_start + *len;
+                buf.get(body_start..total).map(|body| (body.to_vec(), HeaderMap::default(), total))
+            }
+            Framing::Chunked(decoder) => {
+                let decoded = decoder.decode(&buf[body_start..])?;
+                decoded.map(|(body, trailers, used)| (body, trailers, body_start + used))
+            }
+        };
+        let (body, trailers, total) = match complete {
+            Some(complete) => complete,
+            None => {
+                self.pending = Some(pending);
+                return Ok(None);
+            }
+        };
+
+        let request = Request {
+            method: pending.method,
+            target: pending.target,
+            version: pending.version,
+            headers: pending.headers,
+            body,
+            trailers,
+            tls: None,
+        };
+        Ok(Some((request, total)))
+    }
+}
+
+// Parses the request line and headers, once they have all arrived.
+fn parse_head(buf: &[u8]) -> Result<Option<Pending>, ParseError> {
+    // Clients may send stray CRLFs between requests; skip them.
+    let start = buf.iter().position(|b| *b != b'\r' && *b != b'\n').unwrap_or(buf.len());
+    let input = &buf[start..];
+
+    let line_end = match find(&input[..input.len().min(MAX_REQUEST_LINE + 1)], b"\n") {
+        Some(i) => i,
+        None if input.len() > MAX_REQUEST_LINE => return Err(ParseError::UriTooLong),
+        None => return Ok(None),
+    };
+    if line_end > MAX_REQUEST_LINE {
+        return Err(ParseError::UriTooLong);
+    }
+
+    let head_end = match head_end(input) {
+        Some(i) => i,
+        None if input.len() > MAX_HEAD => return Err(ParseError::HeadersTooLarge),
+        None => return Ok(None),
+    };
+
+    let head = std::str::from_utf8(&input[..head_end]).map_err(|_| ParseError::BadRequest("head is not valid UTF-8"))?;
+    let mut lines = head.split('\n').map(|l| l.strip_suffix('\r').unwrap_or(l));
+
+    let (method, target, version) = parse_request_line(lines.next().unwrap_or(""))?;
+
+    let mut headers = HeaderMap::default();
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        let (name, value) = parse_header_line(line)?;
+        headers.push(name.to_string(), value.to_string());
+    }
+
+    if version == Version::Http11 && headers.get_all("Host").count() != 1 {
+        return Err(ParseError::BadRequest("HTTP/1.1 requires exactly one Host header"));
+    }
+
+    let framing = body_framing(version, &headers)?;
+    Ok(Some(Pending {
+        method,
+        target: target.to_string(),
+        version,
+        headers,
+        body_start: start + head_end,
+        framing,
+    }))
+}
+
+// Just past the empty line that ends the head, with either line ending. Only
+// the first `MAX_HEAD` bytes are searched, never the body or the requests
+// pipelined behind it.
+fn head_end(input: &[u8]) -> Option<usize> {
+    let head = &input[..input.len().min(MAX_HEAD)];
+    let mut pos = 0;
+    while let Some(i) = find(&head[pos..], b"\n") {
+        pos += i + 1;
+        if head[pos..].starts_with(b"\n") {
+            return Some(pos + 1);
+        }
+        if head[pos..].starts_with(b"\r\n") {
+            return Some(pos + 2);
+        }
+    }
+    None
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, &str, Version), ParseError> {
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) => (m, t, v),
+        _ => return Err(ParseError::BadRequest("malformed request line")),
+    };
+
+    if !is_token(method) {
+        return Err(ParseError::BadRequest("invalid method"));
+    }
+    if target.is_empty() || target.bytes().any(|b| b <= b' ' || b == 0x7f) {
+        return Err(ParseError::BadRequest("invalid request target"));
+    }
+    if !(target.starts_with('/') || target == "*" || target.contains("://") || method == "CONNECT") {
+        return Err(ParseError::BadRequest("invalid request target"));
+    }
+
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        v if v.len() == 8 && v.starts_with("HTTP/") && v.as_bytes()[6] == b'.' => {
+            return Err(ParseError::VersionNotSupported)
+        }
+        _ => return Err(ParseError::BadRequest("invalid HTTP version")),
+    };
+
+    Ok((Method::parse(method), target, version))
+}
+
+fn parse_header_line(line: &str) -> Result<(&str, &str), ParseError> {
+    if line.starts_with(' ') || line.starts_with('\t') {
+        return Err(ParseError::BadRequest("obsolete line folding"));
+    }
+    let (name, value) = line.split_once(':').ok_or(ParseError::BadRequest("header line without colon"))?;
+    if !is_token(name) {
+        return Err(ParseError::BadRequest("invalid header name"));
+    }
+    let value = value.trim_matches(|c| c == ' ' || c == '\t');
+    if value.bytes().any(|b| (b < b' ' && b != b'\t') || b == 0x7f) {
+        return Err(ParseError::BadRequest("invalid header value"));
+    }
+    Ok((name, value))
+}
+
+// How the end of the request body is found.
+enum Framing {
+    Length(usize),
+    Chunked(ChunkedDecoder),
+}
+
+fn body_framing(version: Version, headers: &HeaderMap) -> Result<Framing, ParseError> {
+    if headers.get("Transfer-Encoding").is_some() {
+        if headers.get("Content-Length").is_some() {
+            return Err(ParseError::BadRequest("both Transfer-Encoding and Content-Length"));
+        }
+        // HTTP/1.0 has no transfer codings, so there's no telling where a
+        // body framed with one ends.
+        if version == Version::Http10 {
+            return Err(ParseError::BadRequest("Transfer-Encoding in HTTP/1.0"));
+        }
+        let codings: Vec<&str> = headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .collect();
+        let chunked = codings.iter().filter(|c| c.eq_ignore_ascii_case("chunked")).count();
+        return match codings.last() {
+            Some(last) if last.eq_ignore_ascii_case("chunked") && chunked == 1 => match codings.len() {
+                1 => Ok(Framing::Chunked(ChunkedDecoder::default())),
+                _ => Err(ParseError::NotImplemented("transfer codings other than chunked")),
+            },
+            // Chunked has to come last, exactly once, or the body has no end.
+            _ => Err(ParseError::BadRequest("invalid Transfer-Encoding")),
+        };
+    }
+
+    let mut length = None;
+    for value in headers.get_all("Content-Length") {
+        for item in value.split(',') {
+            let item = item.trim();
+            if item.is_empty() || !item.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::BadRequest("invalid Content-Length"));
+            }
+            let n: usize = item.parse().map_err(|_| ParseError::PayloadTooLarge)?;
+            if length.is_some_and(|l| l != n) {
+                return Err(ParseError::BadRequest("conflicting Content-Length"));
+            }
+            length = Some(n);
+        }
+    }
+
+    match length {
+        Some(n) if n > MAX_BODY => Err(ParseError::PayloadTooLarge),
+        Some(n) => Ok(Framing::Length(n)),
+        None => Ok(Framing::Length(0)),
+    }
+}
+
+// Decodes a chunked body from the start of `input`:
+//
+//     5;ext=1\r\n hello\r\n  0\r\n  Trailer: value\r\n  \r\n
+//
+// Returns the body, the trailer fields and the number of bytes used, or
+// `None` if more input is needed. Chunk extensions are ignored.
+pub fn decode_chunked(input: &[u8]) -> Result<Option<(Vec<u8>, HeaderMap, usize)>, ParseError> {
+    ChunkedDecoder::default().decode(input)
+}
+
+// `decode_chunked` for a body that arrives in pieces. It remembers where the
+// chunks are and where it stopped, so each call carries on from there. The
+// chunks are only located until the whole body has arrived, then copied once.
+#[derive(Default)]
+pub struct ChunkedDecoder {
+    chunks: Vec<Range<usize>>,
+    body_len: usize,
+    // The start of the next chunk-size or trailer line.
+    pos: usize,
+    // Where the trailers start, once the last chunk has been seen.
+    trailer_start: Option<usize>,
+    trailers: HeaderMap,
+}
+
+impl ChunkedDecoder {
+    // Like `decode_chunked`. `input` must start with the same bytes on every
+    // call, with more of them each time.
+    pub fn decode(&mut self, input: &[u8]) -> Result<Option<(Vec<u8>, HeaderMap, usize)>, ParseError> {
+        while self.trailer_start.is_none() {
+            let too_long = ParseError::BadRequest("chunk size line too long");
+            let line = match next_line(input, self.pos, MAX_CHUNK_LINE, too_long)? {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+            if line.end - self.body_len > MAX_CHUNK_FRAMING {
+                return Err(ParseError::PayloadTooLarge);
+            }
+            let size = line.text.split(';').next().unwrap_or("").trim_end_matches([' ', '\t']);
+            if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(ParseError::BadRequest("invalid chunk size"));
+            }
+            let size = usize::from_str_radix(size, 16).map_err(|_| ParseError::PayloadTooLarge)?;
+            if size == 0 {
+                self.pos = line.end;
+                self.trailer_start = Some(line.end);
+                break;
+            }
+            if size > MAX_BODY - self.body_len {
+                return Err(ParseError::PayloadTooLarge);
+            }
+
+            let data_end = line.end + size;
+            let rest = input.get(data_end..).unwrap_or(&[]);
+            let next = if rest.starts_with(b"\r\n") {
+                data_end + 2
+            } else if rest.starts_with(b"\n") {
+                data_end + 1
+            } else if rest.is_empty() || rest == b"\r" {
+                // The size line is read again next time.
+                return Ok(None);
+            } else {
+                return Err(ParseError::BadRequest("chunk data not followed by CRLF"));
+            };
+            self.chunks.push(line.end..data_end);
+            self.body_len += size;
+            self.pos = next;
+        }
+
+        let trailer_start = self.trailer_start.unwrap_or(self.pos);
+        loop {
+            let limit = MAX_HEAD.saturating_sub(self.pos - trailer_start);
+            let line = match next_line(input, self.pos, limit, ParseError::HeadersTooLarge)? {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+            self.pos = line.end;
+            if line.text.is_empty() {
+                break;
+            }
+            if self.trailers.len() == MAX_HEADERS {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            let (name, value) = parse_header_line(line.text)?;
+            self.trailers.push(name.to_string(), value.to_string());
+        }
+
+        let mut body = Vec::with_capacity(self.body_len);
+        for chunk in self.chunks.drain(..) {
+            body.extend_from_slice(&input[chunk]);
+        }
+        Ok(Some((body, mem::take(&mut self.trailers), self.pos)))
+    }
+}
+
+struct Line<'a> {
+    // Without the line ending.
+    text: &'a str,
+    // Offset just past the line ending.
+    end: usize,
+}
+
+// The line starting at `start`, if it has fully arrived. A line longer than
+// `max` fails with `too_long`.
+fn next_line(input: &[u8], start: usize, max: usize, too_long: ParseError) -> Result<Option<Line<'_>>, ParseError> {
+    let rest = &input[start.min(input.len())..];
+    let len = match find(rest, b"\n") {
+        Some(len) if len <= max => len,
+        None if rest.len() <= max => return Ok(None),
+        _ => return Err(too_long),
+    };
+    let text = std::str::from_utf8(&rest[..len]).map_err(|_| ParseError::BadRequest("chunked framing is not valid UTF-8"))?;
+    Ok(Some(Line { text: text.strip_suffix('\r').unwrap_or(text), end: start + len + 1 }))
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out its input a few bytes per read, like a slow client.
+    struct Trickle<'a> {
+        input: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.input.len().min(self.step).min(buf.len());
+            buf[..n].copy_from_slice(&self.input[..n]);
+            self.input = &self.input[n..];
+            Ok(n)
+        }
+    }
+
+    fn status(input: &[u8]) -> u16 {
+        Parser::default().parse(input).unwrap_err().status()
+    }
+
+    #[test]
+    fn reads_requests_split_across_reads() {
+        let input = b"POST /submit HTTP/1.1\r\nHost: x\r\nContent-Length: 11\r\n\r\nhello world";
+        for step in [1, 2, 5, 4096] {
+            let mut reader = Trickle { input, step };
+            let mut buf = Vec::new();
+            let req = read_request(&mut reader, &mut buf).unwrap().unwrap();
+            assert_eq!(req.method, Method::Post);
+            assert_eq!(req.target, "/submit");
+            assert_eq!(req.body, b"hello world");
+            assert!(buf.is_empty());
+        }
+        let mut reader = Trickle { input: b"GET / HTTP/1.1\r\nHo", step: 3 };
+        assert!(matches!(read_request(&mut reader, &mut Vec::new()), Err(ReadError::Io(_))));
+        let mut reader = Trickle { input: b"\r\n", step: 1 };
+        assert!(read_request(&mut reader, &mut Vec::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn leaves_pipelined_requests_in_the_buffer() {
+        let first = "GET /a HTTP/1.1\r\nHost: x\r\n\r\n";
+        let second = "POST /b HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\nhi";
+        let third = "GET /c HTTP/1.1\r\nHo";
+        let mut buf = format!("{}{}{}", first, second, third).into_bytes();
+        let mut reader = Trickle { input: b"", step: 1 };
+        assert_eq!(read_request(&mut reader, &mut buf).unwrap().unwrap().target, "/a");
+        let (req, used) = Parser::default().parse(&buf).unwrap().unwrap();
+        assert_eq!((req.target.as_str(), req.body.as_slice()), ("/b", &b"hi"[..]));
+        assert_eq!(used, second.len());
+        assert_eq!(read_request(&mut reader, &mut buf).unwrap().unwrap().target, "/b");
+        assert_eq!(buf, third.as_bytes());
+        // Stray line endings between requests are skipped.
+        let (req, used) = Parser::default().parse(b"\r\n\r\nGET /d HTTP/1.1\r\nHost: x\r\n\r\nGET").unwrap().unwrap();
+        assert_eq!((req.target.as_str(), used), ("/d", 32));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        for input in [
+            &b"GET /\r\n\r\n"[..],
+            b"GET / HTTP/1.1 extra\r\nHost: x\r\n\r\n",
+            b"G(T / HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"GET relative HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"GET / HTTQ/1.1\r\nHost: x\r\n\r\n",
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\nHost: y\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\nNo colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\n folded\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\nBad Name: 1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\nContent-Length: 1x\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\nContent-Length: 1, 2\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+            b"GET / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n",
+        ] {
+            assert_eq!(status(input), 400, "{}", String::from_utf8_lossy(input));
+        }
+        assert_eq!(status(b"GET / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"), 501);
+    }
+
+    #[test]
+    fn rejects_requests_over_the_limits() {
+        let body = format!("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+        assert_eq!(status(body.as_bytes()), 413);
+        let huge = "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 99999999999999999999999\r\n\r\n";
+        assert_eq!(status(huge.as_bytes()), 413);
+
+        let target = format!("GET /{} HTTP/1.1\r\n", "a".repeat(MAX_REQUEST_LINE));
+        assert_eq!(status(target.as_bytes()), 414);
+        // Even before the line ends.
+        assert_eq!(status(&target.as_bytes()[..MAX_REQUEST_LINE + 1]), 414);
+
+        let long = format!("GET / HTTP/1.1\r\nHost: x\r\nX-Big: {}\r\n\r\n", "a".repeat(MAX_HEAD));
+        assert_eq!(status(long.as_bytes()), 431);
+        let many: String = (0..=MAX_HEADERS).map(|i| format!("X-{}: 1\r\n", i)).collect();
+        assert_eq!(status(format!("GET / HTTP/1.1\r\nHost: x\r\n{}\r\n", many).as_bytes()), 431);
+
+        assert_eq!(status(b"GET / HTTP/2.0\r\nHost: x\r\n\r\n"), 505);
+        assert_eq!(status(b"GET / HTTP/1.2\r\nHost: x\r\n\r\n"), 505);
+    }
+
+    #[test]
+    fn reads_query_parameters() {
+        let (req, _) = Parser::default().parse(b"GET /api?metric=cpu&to=&flag&q=a+b%2Bc&metric=load HTTP/1.1\r\nHost: x\r\n\r\n").unwrap().unwrap();
+        assert_eq!(req.query("metric").as_deref(), Some("cpu"));
+        assert_eq!(req.query("to").as_deref(), Some(""));
+        assert_eq!(req.query("flag").as_deref(), Some(""));
+        assert_eq!(req.query("q").as_deref(), Some("a b+c"));
+        assert_eq!(req.query("from"), None);
+        assert_eq!(req.path(), "/api");
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let (body, trailers, used) = decode_chunked(b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\n\r\nnext").unwrap().unwrap();
+        assert_eq!(body, b"hello world");
+        assert_eq!(trailers.len(), 0);
+        assert_eq!(used, 32);
+    }
+
+    #[test]
+    fn keeps_trailers() {
+        let (body, trailers, _) = decode_chunked(b"3\r\nabc\r\n0\r\nChecksum: 1f\r\n\r\n").unwrap().unwrap();
+        assert_eq!(body, b"abc");
+        assert_eq!(trailers.get("checksum"), Some("1f"));
+    }
+
+    #[test]
+    fn waits_for_the_whole_body() {
+        let input = b"3\r\nabc\r\n0\r\nChecksum: 1f\r\n\r\n";
+        for end in 0..input.len() {
+            assert!(decode_chunked(&input[..end]).unwrap().is_none(), "{} bytes", end);
+        }
+    }
+
+    #[test]
+    fn decodes_bodies_as_they_arrive() {
+        let input = b"POST /up HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        let mut parser = Parser::default();
+        for end in 0..input.len() {
+            assert!(parser.parse(&input[..end]).unwrap().is_none(), "{} bytes", end);
+        }
+        let (req, used) = parser.parse(input).unwrap().unwrap();
+        assert_eq!(req.body, b"abcde");
+        assert_eq!(used, input.len());
+    }
+
+    #[test]
+    fn limits_chunk_framing() {
+        // A byte per chunk, each with a long extension.
+        let chunk = format!("1;{}\r\na\r\n", "x".repeat(500));
+        let input = chunk.repeat(200);
+        assert_eq!(decode_chunked(input.as_bytes()).unwrap_err().status(), 413);
+        // Small chunks within the allowance are fine.
+        let input = format!("{}0\r\n\r\n", "1\r\na\r\n".repeat(1000));
+        assert_eq!(decode_chunked(input.as_bytes()).unwrap().unwrap().0.len(), 1000);
+    }
+
+    #[test]
+    fn rejects_bad_framing() {
+        assert_eq!(decode_chunked(b"x\r\n").unwrap_err().status(), 400);
+        assert_eq!(decode_chunked(b"3\r\nabcd\r\n").unwrap_err().status(), 400);
+        assert_eq!(decode_chunked(b"200000\r\n").unwrap_err().status(), 413);
+        assert_eq!(decode_chunked(b"ffffffffffffffffffff\r\n").unwrap_err().status(), 413);
+    }
+}
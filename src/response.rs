@@ -0,0 +1,593 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::sendfile;
+use crate::websocket;
+
+// Streamed output is gathered into chunks of about this size before it is
+// sent, so a handler writing a line at a time doesn't cost a syscall each.
+const STREAM_CHUNK: usize = 8 * 1024;
+
+// Writes a streamed body. The argument is only valid for the call.
+pub type Producer = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+// Feeds and WebSocket handlers are polled about this often.
+pub const TICK: Duration = Duration::from_millis(250);
+
+// A body that trickles out over time, such as an event stream. Unlike a
+// `Producer` it never blocks: it is polled about every `TICK` for whatever is
+// due, so the event loop and HTTP/2 can interleave it with other work.
+pub trait Feed: Send {
+    // Appends the bytes due by `now` to `out`. Returns false once the body is
+    // complete.
+    fn poll(&mut self, now: Instant, out: &mut Vec<u8>) -> bool;
+}
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    // Compiled into the binary, e.g. the embedded site.
+    Static(&'static [u8]),
+    // `len` bytes of an open file starting at `offset`, sent with sendfile
+    // where the connection allows it. Shared so that ranges of one file can
+    // be sent as several parts.
+    File { file: Arc<File>, offset: u64, len: u64 },
+    // Sent one after the other, e.g. the parts of a multipart/byteranges body.
+    Parts(Vec<Body>),
+    // Produced while the response is being sent, so its length isn't known
+    // up front: sent chunked to HTTP/1.1 clients, and delimited by closing the
+    // connection for HTTP/1.0 ones.
+    Stream(Producer),
+    // Polled until it ends, framed like a stream. Never compressed.
+    Feed(Box<dyn Feed>),
+}
+
+impl Body {
+    // `None` for a streamed body.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Static(bytes) => Some(bytes.len() as u64),
+            Body::File { len, .. } => Some(*len),
+            Body::Parts(parts) => parts.iter().map(Body::len).sum(),
+            Body::Stream(_) | Body::Feed(_) => None,
+        }
+    }
+
+    // Bytes `start..end` of the body. Files and static bytes are not copied;
+    // streams have no known length to take a range of.
+    pub fn slice(&self, start: u64, end: u64) -> Body {
+        match self {
+            Body::Bytes(bytes) => Body::Bytes(bytes[start as usize..end as usize].to_vec()),
+            Body::Static(bytes) => Body::Static(&bytes[start as usize..end as usize]),
+            Body::File { file, offset, .. } => Body::File {
+                file: Arc::clone(file),
+                offset: offset + start,
+                len: end - start,
+            },
+            Body::Parts(parts) => {
+                let mut sliced = Vec::new();
+                let mut part_start = 0;
+                for part in parts {
+                    let part_end = part_start + part.len().unwrap_or(0);
+                    if part_end > start && part_start < end {
+                        sliced.push(part.slice(start.max(part_start) - part_start, end.min(part_end) - part_start));
+                    }
+                    part_start = part_end;
+                }
+                Body::Parts(sliced)
+            }
+            Body::Stream(_) | Body::Feed(_) => unreachable!("streamed bodies can't be sliced"),
+        }
+    }
+
+    // The whole body in memory, reading files and running streams if need be.
+    pub fn into_vec(self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.copy_to(&mut out)?;
+        Ok(out)
+    }
+
+    // Writes the bare body, without any transfer coding.
+    pub fn copy_to(self, w: &mut dyn Write) -> io::Result<()> {
+        let mut pending = Vec::new();
+        self.write_to(&mut pending, w, false)?;
+        w.write_all(&pending)
+    }
+
+    // Appends in-memory bytes to `pending`; before a file, sends what is
+    // pending and then the file, so that small bodies still go out in a
+    // single write with the head. A stream is framed as chunks if `chunked`.
+    fn write_to<W: Transport + ?Sized>(self, pending: &mut Vec<u8>, w: &mut W, chunked: bool) -> io::Result<()> {
+        match self {
+            Body::Bytes(bytes) => pending.extend_from_slice(&bytes),
+            Body::Static(bytes) => pending.extend_from_slice(bytes),
+            Body::File { file, offset, len } => {
+                w.write_all(pending)?;
+                pending.clear();
+                w.send_file_all(&file, offset, len)?;
+            }
+            Body::Parts(parts) => {
+                for part in parts {
+                    part.write_to(pending, w, chunked)?;
+                }
+            }
+            Body::Stream(produce) => {
+                let mut writer = StreamWriter { inner: w, out: pending, buf: Vec::new(), chunked };
+                produce(&mut writer)?;
+                writer.finish();
+            }
+            // Sent as it comes; a worker thread can afford to sleep between
+            // polls.
+            Body::Feed(mut feed) => {
+                let mut writer = StreamWriter { inner: w, out: pending, buf: Vec::new(), chunked };
+                while feed.poll(Instant::now(), &mut writer.buf) {
+                    writer.flush()?;
+                    thread::sleep(TICK);
+                }
+                writer.finish();
+            }
+        }
+        Ok(())
+    }
+}
+
+// What a streamed body is written through. Output collects in `buf` until
+// there is a chunk's worth or the producer flushes; it is then framed onto
+// `out`, behind anything already pending such as the response head, and sent.
+struct StreamWriter<'a, W: Write + ?Sized> {
+    inner: &'a mut W,
+    out: &'a mut Vec<u8>,
+    buf: Vec<u8>,
+    chunked: bool,
+}
+
+impl<W: Write + ?Sized> StreamWriter<'_, W> {
+    fn frame(&mut self) {
+        if self.buf.is_empty() {
+            return;
+        }
+        if self.chunked {
+            self.out.extend_from_slice(format!("{:x}\r\n", self.buf.len()).as_bytes());
+            self.out.append(&mut self.buf);
+            self.out.extend_from_slice(b"\r\n");
+        } else {
+            self.out.append(&mut self.buf);
+        }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        self.frame();
+        self.inner.write_all(self.out)?;
+        self.out.clear();
+        Ok(())
+    }
+
+    // Leaves the rest of the body, and the last chunk, in `out` for the caller
+    // to send.
+    fn finish(mut self) {
+        self.frame();
+        if self.chunked {
+            self.out.extend_from_slice(b"0\r\n\r\n");
+        }
+    }
+}
+
+impl<W: Write + ?Sized> Write for StreamWriter<'_, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= STREAM_CHUNK {
+            self.send()?;
+        }
+        Ok(data.len())
+    }
+
+    // Sends everything written so far, e.g. for a handler that produces output
+    // over time.
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()?;
+        self.inner.flush()
+    }
+}
+
+// Something a response can be written to. Sockets override `send_file` to let
+// the kernel do the copy; buffers read the file through userspace.
+pub trait Transport: Write {
+    // Sends up to `len` bytes of `file` from `offset` and returns how many
+    // went out, like `write`.
+    fn send_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<u64> {
+        sendfile::copy(self, file, offset, len)
+    }
+
+    // Like `write_all`: keeps calling `send_file` until all `len` bytes are
+    // out.
+    fn send_file_all(&mut self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            match self.send_file(file, pos, end - pos) {
+                // The file shrank after Content-Length was sent.
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => pos += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Vec<u8> {}
+
+// For writing bodies through encoders.
+impl Transport for dyn Write + '_ {}
+
+impl Transport for TcpStream {
+    fn send_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<u64> {
+        sendfile::send_file(self, file, offset, len)
+    }
+}
+
+// Counts the bytes that actually reach `inner`.
+struct Counted<'a, W: ?Sized> {
+    inner: &'a mut W,
+    sent: u64,
+}
+
+impl<W: Write + ?Sized> Write for Counted<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.sent += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Transport + ?Sized> Transport for Counted<'_, W> {
+    fn send_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<u64> {
+        let n = self.inner.send_file(file, offset, len)?;
+        self.sent += n;
+        Ok(n)
+    }
+}
+
+// A response that was cut short, and how much of it got out first.
+#[derive(Debug)]
+pub struct WriteError {
+    pub error: io::Error,
+    pub sent: u64,
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} after {} bytes", self.error, self.sent)
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    pub body: Body,
+    // For a 101 switching to WebSocket, what takes the connection over once
+    // the response is sent.
+    pub upgrade: Option<Box<dyn websocket::Handler>>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+            upgrade: None,
+        }
+    }
+
+    pub fn html(body: String) -> Response {
+        Response::new(200)
+            .with_header("Content-Type", "text/html; charset=UTF-8")
+            .with_body(body.into_bytes())
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=UTF-8")
+            .with_body(body.into().into_bytes())
+    }
+
+    // A plain-text response whose body is just the status line, e.g. "404 Not Found".
+    pub fn status_page(status: u16) -> Response {
+        Response::text(status, format!("{} {}\n", status, reason(status)))
+    }
+
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Response {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    pub fn without_header(mut self, name: &str) -> Response {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Response {
+        self.body = Body::Bytes(body);
+        self
+    }
+
+    pub fn with_static_body(mut self, body: &'static [u8]) -> Response {
+        self.body = Body::Static(body);
+        self
+    }
+
+    pub fn with_file(mut self, file: File, len: u64) -> Response {
+        self.body = Body::File {
+            file: Arc::new(file),
+            offset: 0,
+            len,
+        };
+        self
+    }
+
+    // A body written by `produce` as the response is sent, rather than built
+    // in memory first. `produce` may flush to send what it has so far.
+    pub fn with_stream(mut self, produce: impl FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static) -> Response {
+        self.body = Body::Stream(Box::new(produce));
+        self
+    }
+
+    pub fn with_upgrade(mut self, handler: Box<dyn websocket::Handler>) -> Response {
+        self.upgrade = Some(handler);
+        self
+    }
+
+    // A body polled from `feed` as the response is sent.
+    pub fn with_feed(mut self, feed: impl Feed + 'static) -> Response {
+        self.body = Body::Feed(Box::new(feed));
+        self
+    }
+
+    // Serializes the response. `Content-Length` is derived from the body for
+    // every status that may carry one, unless the body is streamed; with
+    // `Transfer-Encoding: chunked` set, a streamed body is sent as chunks.
+    // `include_body` is false for HEAD requests. Returns once everything has
+    // been written, with the number of bytes sent.
+    pub fn write_to<W: Transport>(self, w: &mut W, include_body: bool) -> Result<u64, WriteError> {
+        let mut counted = Counted { inner: w, sent: 0 };
+        match self.send(&mut counted, include_body) {
+            Ok(()) => Ok(counted.sent),
+            Err(error) => Err(WriteError { error, sent: counted.sent }),
+        }
+    }
+
+    fn send<W: Transport + ?Sized>(self, w: &mut W, include_body: bool) -> io::Result<()> {
+        // One write for head and body, so the two don't go out as separate
+        // segments and stall on Nagle + delayed ACK.
+        let mut out = self.head().into_bytes();
+        if include_body {
+            let chunked = self.is_chunked();
+            self.body.write_to(&mut out, w, chunked)?;
+        }
+        w.write_all(&out)?;
+        w.flush()
+    }
+
+    // The status line and headers, with `Content-Length` added as for
+    // `write_to`.
+    pub fn head(&self) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !(self.status < 200 || self.status == 204 || self.status == 304 || self.is_chunked()) {
+            if let Some(len) = self.body.len() {
+                head.push_str(&format!("Content-Length: {}\r\n", len));
+            }
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.header("Transfer-Encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked"))
+    }
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    use crate::request;
+
+    // A socket that takes at most `max` bytes a write, is interrupted every
+    // third call and times out (`WouldBlock`) once `limit` bytes are in.
+    struct Flaky {
+        out: Vec<u8>,
+        max: usize,
+        limit: usize,
+        calls: usize,
+    }
+
+    impl Flaky {
+        fn new(max: usize, limit: usize) -> Flaky {
+            Flaky { out: Vec::new(), max, limit, calls: 0 }
+        }
+
+        // Splits off the head, which has to name the body's length or have
+        // none.
+        fn body(&self) -> (String, &[u8]) {
+            let end = self.out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            (String::from_utf8_lossy(&self.out[..end]).into_owned(), &self.out[end..])
+        }
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            if self.calls.is_multiple_of(3) {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let n = buf.len().min(self.max).min(self.limit - self.out.len());
+            if n == 0 && !buf.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.out.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Flaky {}
+
+    fn temp_file(name: &str, contents: &[u8]) -> File {
+        let path = std::env::temp_dir().join(format!("response-test-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let file = File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        file
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn sends_everything_through_short_writes() {
+        let body = contents(10_000);
+        let mut socket = Flaky::new(7, usize::MAX);
+        let sent = Response::new(200).with_body(body.clone()).write_to(&mut socket, true).unwrap();
+        assert_eq!(sent, socket.out.len() as u64);
+        let (head, sent_body) = socket.body();
+        assert!(head.contains("Content-Length: 10000\r\n"));
+        assert_eq!(sent_body, body);
+    }
+
+    #[test]
+    fn sends_whole_files_through_short_writes() {
+        let body = contents(100_000);
+        let mut socket = Flaky::new(1000, usize::MAX);
+        let response = Response::new(200).with_file(temp_file("whole", &body), body.len() as u64);
+        response.write_to(&mut socket, true).unwrap();
+        let (head, sent_body) = socket.body();
+        assert!(head.contains("Content-Length: 100000\r\n"));
+        assert_eq!(sent_body, body);
+    }
+
+    #[test]
+    fn sends_streams_as_chunks_through_short_writes() {
+        let mut socket = Flaky::new(5, usize::MAX);
+        let response = Response::new(200).with_header("Transfer-Encoding", "chunked").with_stream(|w| {
+            for i in 0..2000 {
+                writeln!(w, "line {}", i)?;
+            }
+            Ok(())
+        });
+        response.write_to(&mut socket, true).unwrap();
+        let (head, sent_body) = socket.body();
+        assert!(!head.contains("Content-Length"));
+        let (decoded, _, used) = request::decode_chunked(sent_body).unwrap().unwrap();
+        assert_eq!(used, sent_body.len());
+        let expected: String = (0..2000).map(|i| format!("line {}\n", i)).collect();
+        assert_eq!(decoded, expected.as_bytes());
+    }
+
+    #[test]
+    fn reports_what_got_out_before_a_timeout() {
+        let mut socket = Flaky::new(100, 3000);
+        let err = Response::new(200).with_body(contents(10_000)).write_to(&mut socket, true).unwrap_err();
+        assert_eq!(err.error.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(err.sent, 3000);
+        assert_eq!(socket.out.len(), 3000);
+
+        let mut socket = Flaky::new(100, 3000);
+        let response = Response::new(200).with_file(temp_file("timeout", &contents(10_000)), 10_000);
+        let err = response.write_to(&mut socket, true).unwrap_err();
+        assert_eq!(err.error.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(err.sent, 3000);
+    }
+
+    #[test]
+    fn fails_rather_than_falling_short_of_the_length() {
+        // The file shrank after its length was taken: the error tells the
+        // caller to drop the connection instead of leaving the client waiting.
+        let mut socket = Flaky::new(1000, usize::MAX);
+        let response = Response::new(200).with_file(temp_file("shrunk", &contents(5000)), 8000);
+        let err = response.write_to(&mut socket, true).unwrap_err();
+        assert_eq!(err.error.kind(), io::ErrorKind::UnexpectedEof);
+        let (head, sent_body) = socket.body();
+        assert!(head.contains("Content-Length: 8000\r\n"));
+        assert_eq!(sent_body.len(), 5000);
+        assert_eq!(err.sent, socket.out.len() as u64);
+
+        // A stream that fails has no length to contradict, and never gets
+        // its last chunk.
+        let mut socket = Flaky::new(1000, usize::MAX);
+        let response = Response::new(200).with_header("Transfer-Encoding", "chunked").with_stream(|w| {
+            w.write_all(&contents(20_000))?;
+            Err(io::Error::other("producer failed"))
+        });
+        assert!(response.write_to(&mut socket, true).is_err());
+        let (head, sent_body) = socket.body();
+        assert!(!head.contains("Content-Length"));
+        assert!(!sent_body.ends_with(b"0\r\n\r\n"));
+    }
+}
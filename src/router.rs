@@ -0,0 +1,271 @@
+use crate::request::{Method, Request};
+use crate::response::Response;
+
+// Route label for requests that matched no route, or were never routed
+// because they could not be parsed.
+pub const UNMATCHED: &str = "unmatched";
+
+pub type Handler = Box<dyn Fn(&Request, &Params) -> Response + Send + Sync>;
+
+// Values captured from `:name` and `*name` segments of a route pattern.
+#[derive(Debug, Default)]
+pub struct Params {
+    values: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+enum Segment {
+    Literal(String),
+    // `:name` matches exactly one non-empty segment.
+    Param(String),
+    // `*name` matches the rest of the path, including nothing at all.
+    Rest(String),
+}
+
+struct Route {
+    pattern: String,
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+impl Route {
+    fn matches(&self, path: &[String]) -> Option<Params> {
+        let mut params = Params::default();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Rest(name) => {
+                    let rest = path.get(i..).unwrap_or(&[]).join("/");
+                    params.values.push((name.clone(), rest));
+                    return Some(params);
+                }
+                Segment::Literal(lit) => {
+                    if path.get(i) != Some(lit) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => match path.get(i) {
+                    Some(value) if !value.is_empty() => params.values.push((name.clone(), value.clone())),
+                    _ => return None,
+                },
+            }
+        }
+        if path.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+// Routes are tried in registration order; the first one whose pattern and
+// method both match handles the request.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    pub fn route<F>(&mut self, method: Method, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        let segments = split_path(pattern)
+            .into_iter()
+            .map(|s| {
+                if let Some(name) = s.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = s.strip_prefix('*') {
+                    Segment::Rest(name.to_string())
+                } else {
+                    Segment::Literal(s)
+                }
+            })
+            .collect();
+        self.routes.push(Route {
+            pattern: pattern.to_string(),
+            method,
+            segments,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    // Dispatches to the matching handler. GET routes also answer HEAD. A path
+    // that matches under other methods gets 405 with `Allow` (or 204 for
+    // OPTIONS); a path that matches nothing gets 404. Also returns the pattern
+    // of the route that answered, or `UNMATCHED`, for use as a metrics label.
+    pub fn dispatch(&self, req: &Request) -> (Response, &str) {
+        let path = split_path(req.path());
+        let mut allowed: Vec<&str> = Vec::new();
+        let mut matched = UNMATCHED;
+
+        for route in &self.routes {
+            let params = match route.matches(&path) {
+                Some(params) => params,
+                None => continue,
+            };
+            let method_matches = route.method == req.method || (route.method == Method::Get && req.method == Method::Head);
+            if method_matches {
+                return ((route.handler)(req, &params), &route.pattern);
+            }
+            if allowed.is_empty() {
+                matched = &route.pattern;
+            }
+            let mut methods = vec![route.method.as_str()];
+            if route.method == Method::Get {
+                methods.push("HEAD");
+            }
+            for m in methods {
+                if !allowed.contains(&m) {
+                    allowed.push(m);
+                }
+            }
+        }
+
+        if allowed.is_empty() {
+            return (Response::status_page(404), UNMATCHED);
+        }
+        if !allowed.contains(&"OPTIONS") {
+            allowed.push("OPTIONS");
+        }
+        let allow = allowed.join(", ");
+        let response = if req.method == Method::Options {
+            Response::new(204).with_header("Allow", allow)
+        } else {
+            Response::status_page(405).with_header("Allow", allow)
+        };
+        (response, matched)
+    }
+}
+
+// Splits a path into percent-decoded segments, ignoring the leading slash.
+// "/" becomes a single empty segment so that it can be routed like any other.
+pub fn split_path(path: &str) -> Vec<String> {
+    path.strip_prefix('/').unwrap_or(path).split('/').map(percent_decode).collect()
+}
+
+// Decodes %XX escapes, leaving malformed ones as they are.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                out.push(hi << 4 | lo);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request;
+
+    fn req(method: &str, target: &str) -> Request {
+        let head = format!("{} {} HTTP/1.1\r\nHost: x\r\n\r\n", method, target);
+        request::Parser::default().parse(head.as_bytes()).unwrap().unwrap().0
+    }
+
+    // Answers with the route's name and what it captured.
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.get("/", |_, _| Response::text(200, "root"));
+        router.get("/users/:id", |_, p| Response::text(200, format!("user {}", p.get("id").unwrap())));
+        router.post("/users/:id", |_, p| Response::text(201, format!("update {}", p.get("id").unwrap())));
+        router.get("/files/*path", |_, p| Response::text(200, format!("file {}", p.get("path").unwrap())));
+        router
+    }
+
+    fn body(response: &Response) -> String {
+        match &response.body {
+            crate::response::Body::Bytes(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+            _ => panic!("expected a plain body"),
+        }
+    }
+
+    #[test]
+    fn captures_parameters() {
+        let router = router();
+        let (response, route) = router.dispatch(&req("GET", "/users/42?x=1"));
+        assert_eq!((body(&response).as_str(), route), ("user 42", "/users/:id"));
+        let (response, _) = router.dispatch(&req("GET", "/users/a%20b"));
+        assert_eq!(body(&response), "user a b");
+        // A parameter is exactly one non-empty segment.
+        assert_eq!(router.dispatch(&req("GET", "/users/")).0.status, 404);
+        assert_eq!(router.dispatch(&req("GET", "/users/1/2")).0.status, 404);
+        assert_eq!(body(&router.dispatch(&req("GET", "/")).0), "root");
+    }
+
+    #[test]
+    fn captures_the_rest_of_the_path() {
+        let router = router();
+        assert_eq!(body(&router.dispatch(&req("GET", "/files/a/b.txt")).0), "file a/b.txt");
+        assert_eq!(body(&router.dispatch(&req("GET", "/files/")).0), "file ");
+        assert_eq!(body(&router.dispatch(&req("GET", "/files")).0), "file ");
+    }
+
+    #[test]
+    fn picks_the_route_for_the_method() {
+        let router = router();
+        let (response, route) = router.dispatch(&req("POST", "/users/7"));
+        assert_eq!((response.status, body(&response).as_str(), route), (201, "update 7", "/users/:id"));
+        // GET routes answer HEAD too.
+        assert_eq!(router.dispatch(&req("HEAD", "/users/7")).0.status, 200);
+    }
+
+    #[test]
+    fn tells_404_from_405() {
+        let router = router();
+        let (response, route) = router.dispatch(&req("GET", "/nowhere"));
+        assert_eq!((response.status, route), (404, UNMATCHED));
+        let (response, route) = router.dispatch(&req("DELETE", "/users/7"));
+        assert_eq!((response.status, route), (405, "/users/:id"));
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, POST, OPTIONS"));
+        let (response, _) = router.dispatch(&req("PUT", "/"));
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+    }
+
+    #[test]
+    fn answers_options() {
+        let router = router();
+        let response = router.dispatch(&req("OPTIONS", "/users/7")).0;
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, POST, OPTIONS"));
+        assert_eq!(router.dispatch(&req("OPTIONS", "/nowhere")).0.status, 404);
+    }
+}
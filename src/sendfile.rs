@@ -0,0 +1,63 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+// The most `copy` reads from the file at a time.
+const COPY_CHUNK: u64 = 64 * 1024;
+
+// Sends up to `len` bytes of `file`, starting at `offset`, to a socket and
+// returns how many went out; like `write`, that may be fewer than asked for.
+// On Linux the kernel copies straight from the page cache with sendfile(2);
+// elsewhere, or if the kernel refuses for this file, it is read through a
+// buffer.
+#[cfg(target_os = "linux")]
+pub fn send_file<S: AsRawFd>(socket: &S, file: &File, offset: u64, len: u64) -> io::Result<u64>
+where
+    for<'a> &'a S: Write,
+{
+    // The most sendfile(2) transfers in one call.
+    const MAX_CHUNK: u64 = 0x7fff_f000;
+
+    let mut pos = offset as libc::off_t;
+    let count = len.min(MAX_CHUNK) as usize;
+    let n = unsafe { libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut pos, count) };
+    if n >= 0 {
+        return Ok(n as u64);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        // Not supported for this file or socket: copy instead.
+        Some(libc::EINVAL) | Some(libc::ENOSYS) => {
+            let mut socket = socket;
+            copy(&mut socket, file, offset, len)
+        }
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_file<S>(socket: &S, file: &File, offset: u64, len: u64) -> io::Result<u64>
+where
+    for<'a> &'a S: Write,
+{
+    let mut socket = socket;
+    copy(&mut socket, file, offset, len)
+}
+
+// Reads up to `len` bytes of `file`, starting at `offset`, and writes them
+// with a single `write`, returning how many were written. Zero means the
+// file ended early.
+pub fn copy<W: Write + ?Sized>(w: &mut W, file: &File, offset: u64, len: u64) -> io::Result<u64> {
+    let mut file = file;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; len.min(COPY_CHUNK) as usize];
+    let n = file.read(&mut buf)?;
+    if n == 0 {
+        return Ok(0);
+    }
+    match w.write(&buf[..n])? {
+        0 => Err(io::ErrorKind::WriteZero.into()),
+        written => Ok(written as u64),
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::events::EventLog;
+use crate::metrics::Metrics;
+
+// Graceful shutdown. The first SIGTERM or SIGINT starts draining: the
+// `draining` event goes out, new connections are turned away, connections
+// stop being kept alive and long-lived streams wind down. The process exits
+// once every connection has closed, or after the drain timeout; a second
+// signal exits straight away.
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+// Acquire pairs with the Release store in `handle_signals`: whoever sees the
+// flag also sees everything published before it was set.
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::Acquire)
+}
+
+// Takes over SIGTERM and SIGINT. The signals are blocked in every thread,
+// which inherit the mask from this one, so this has to run before any other
+// thread is started.
+#[cfg(target_os = "linux")]
+pub fn handle_signals(events: Arc<EventLog>, metrics: Arc<Metrics>, timeout: Duration) {
+    use std::time::Instant;
+    use std::{mem, process, ptr, thread};
+
+    use serde_json::json;
+
+    let set = unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        set
+    };
+    let spawned = thread::Builder::new().name("signals".to_string()).spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+            continue;
+        }
+        if draining() {
+            println!("Stopping without waiting for connections");
+            process::exit(1);
+        }
+        println!("Draining connections for up to {}s", timeout.as_secs());
+        // Published first, so streams that see the flag have it to send.
+        events.publish("draining", json!({ "timeout_secs": timeout.as_secs() }));
+        DRAINING.store(true, Ordering::Release);
+        let metrics = Arc::clone(&metrics);
+        thread::spawn(move || {
+            let deadline = Instant::now() + timeout;
+            while metrics.active_connections() > 0 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(100));
+            }
+            match metrics.active_connections() {
+                0 => println!("Drained; stopping"),
+                open => println!("Stopping with {} connections still open", open),
+            }
+            process::exit(0);
+        });
+    });
+    if let Err(e) = spawned {
+        println!("Unable to handle signals: {}", e);
+        unsafe { libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, ptr::null_mut()) };
+    }
+}
+
+// Elsewhere the signals keep their default action.
+#[cfg(not(target_os = "linux"))]
+pub fn handle_signals(_events: Arc<EventLog>, _metrics: Arc<Metrics>, _timeout: Duration) {}
@@ -0,0 +1,274 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::http_date;
+use crate::mime;
+use crate::negotiate;
+use crate::request::Request;
+use crate::response::Response;
+
+// Precompressed siblings looked for next to each file, by content coding.
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", ".br"), ("gzip", ".gz")];
+
+// Serves files from a directory on disk. Request paths are mapped below the
+// root, directories answer with their index.html, and nothing that resolves
+// outside the root (through `..` or a symlink) is ever opened.
+pub struct DocRoot {
+    // Canonical, so resolved paths can be checked against it by prefix.
+    root: PathBuf,
+}
+
+impl DocRoot {
+    pub fn open(root: &Path) -> io::Result<DocRoot> {
+        let root = root.canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a directory"));
+        }
+        Ok(DocRoot { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn serve(&self, req: &Request) -> Response {
+        let path = req.path();
+        let segments = match path_segments(path) {
+            Ok(segments) => segments,
+            Err(status) => return Response::status_page(status),
+        };
+        let mut name = self.root.join(segments.iter().collect::<PathBuf>());
+        let mut resolved = match self.resolve(&name) {
+            Some(resolved) => resolved,
+            None => return Response::status_page(404),
+        };
+
+        if resolved.is_dir() {
+            // Redirect to the slash-terminated URL so relative links in the
+            // index page resolve against the directory.
+            if !path.ends_with('/') {
+                return directory_redirect(req, &segments);
+            }
+            name.push("index.html");
+            resolved = match self.resolve(&name) {
+                Some(resolved) => resolved,
+                None => return Response::status_page(404),
+            };
+        }
+
+        // Precompressed siblings (`app.js.br`, `app.js.gz`) are sent in place
+        // of the file to clients that accept them.
+        let siblings: Vec<(&str, PathBuf)> = PRECOMPRESSED
+            .iter()
+            .filter_map(|(coding, extension)| {
+                let mut sibling = name.clone().into_os_string();
+                sibling.push(extension);
+                let sibling = self.resolve(Path::new(&sibling)).filter(|p| p.is_file())?;
+                Some((*coding, sibling))
+            })
+            .collect();
+        let offered: Vec<&str> = siblings.iter().map(|(coding, _)| *coding).collect();
+        let chosen = negotiate::encoding(req.headers.get("Accept-Encoding"), &offered)
+            .and_then(|c| siblings.iter().find(|(coding, _)| *coding == c));
+
+        let response = match chosen {
+            Some((coding, sibling)) => open(sibling).map(|r| r.with_header("Content-Encoding", *coding)),
+            None => open(&resolved),
+        };
+        match response {
+            Ok(response) if siblings.is_empty() => response.with_header("Content-Type", mime::from_path(&name)),
+            Ok(response) => response
+                .with_header("Content-Type", mime::from_path(&name))
+                .with_header("Vary", "Accept-Encoding"),
+            Err(status) => Response::status_page(status),
+        }
+    }
+
+    // Follows symlinks and returns the real path, if it exists and is still
+    // inside the root.
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let resolved = fs::canonicalize(path).ok()?;
+        resolved.starts_with(&self.root).then_some(resolved)
+    }
+}
+
+// Opens a regular file as a 200 response, with validators and range support.
+fn open(path: &Path) -> Result<Response, u16> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Err(403),
+        Err(_) => return Err(404),
+    };
+    let metadata = match file.metadata() {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Err(404),
+    };
+    let mut response = Response::new(200).with_header("Accept-Ranges", "bytes");
+    if let Ok(modified) = metadata.modified() {
+        // Size and modification time change whenever the contents do, and
+        // are much cheaper than hashing the file on every request.
+        let nanos = modified.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        response = response
+            .with_header("ETag", format!("\"{:x}-{:x}\"", metadata.len(), nanos))
+            .with_header("Last-Modified", http_date::format(http_date::unix_secs(modified)));
+    }
+    Ok(response.with_file(file, metadata.len()))
+}
+
+// Splits a raw request path into decoded segments, relative to the root.
+// Each segment is percent-decoded on its own, so `%2F` can't smuggle in a
+// separator; `..` and separators or NULs in a segment are refused outright
+// rather than normalised away. Also used for the embedded site.
+pub fn path_segments(path: &str) -> Result<Vec<String>, u16> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        let decoded = percent_decode(segment).ok_or(400u16)?;
+        let name = String::from_utf8(decoded).map_err(|_| 400u16)?;
+        if name.contains(['/', '\\', '\0']) {
+            return Err(403);
+        }
+        match name.as_str() {
+            "" | "." => {}
+            ".." => return Err(403),
+            _ => segments.push(name),
+        }
+    }
+    Ok(segments)
+}
+
+// Redirects a directory URL to the one with the trailing slash. The URL is
+// rebuilt from the checked segments rather than echoing the request path, so
+// a request for `//evil.example` can't become a redirect to another host.
+pub fn directory_redirect(req: &Request, segments: &[String]) -> Response {
+    let mut location = String::from("/");
+    for segment in segments {
+        for b in segment.bytes() {
+            if b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&b) {
+                location.push(b as char);
+            } else {
+                location.push_str(&format!("%{:02X}", b));
+            }
+        }
+        location.push('/');
+    }
+    if let Some(i) = req.target.find('?') {
+        location.push_str(&req.target[i..]);
+    }
+    Response::status_page(301).with_header("Location", location)
+}
+
+// Unlike the router's decoding, malformed escapes are an error here.
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hi = (*bytes.get(i + 1)? as char).to_digit(16)?;
+            let lo = (*bytes.get(i + 2)? as char).to_digit(16)?;
+            out.push((hi << 4 | lo) as u8);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    use crate::request::Parser;
+
+    // A document root next to a directory it must not expose:
+    //
+    //     site/index.html, site/docs/index.html, site/evil.example/index.html,
+    //     site/a b/index.html, site/inside -> index.html, site/escape -> ../secret.txt
+    //     secret.txt
+    struct Site {
+        dir: PathBuf,
+        root: DocRoot,
+    }
+
+    impl Site {
+        fn new(name: &str) -> Site {
+            let dir = std::env::temp_dir().join(format!("static-files-{}-{}", process::id(), name));
+            let site = dir.join("site");
+            // Left over from a run that didn't finish.
+            let _ = fs::remove_dir_all(&dir);
+            for sub in ["docs", "evil.example", "a b"] {
+                fs::create_dir_all(site.join(sub)).unwrap();
+                fs::write(site.join(sub).join("index.html"), sub).unwrap();
+            }
+            fs::write(site.join("index.html"), "home").unwrap();
+            fs::write(dir.join("secret.txt"), "secret").unwrap();
+            symlink(site.join("index.html"), site.join("inside")).unwrap();
+            symlink(dir.join("secret.txt"), site.join("escape")).unwrap();
+            let root = DocRoot::open(&site).unwrap();
+            Site { dir, root }
+        }
+
+        fn get(&self, target: &str) -> Response {
+            let head = format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", target);
+            self.root.serve(&Parser::default().parse(head.as_bytes()).unwrap().unwrap().0)
+        }
+    }
+
+    impl Drop for Site {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn serves_files_and_directory_indexes() {
+        let site = Site::new("serves");
+        assert_eq!(site.get("/").status, 200);
+        assert_eq!(site.get("/index.html").status, 200);
+        assert_eq!(site.get("/docs/").status, 200);
+        assert_eq!(site.get("/a%20b/").status, 200);
+        assert_eq!(site.get("/missing").status, 404);
+    }
+
+    #[test]
+    fn refuses_paths_out_of_the_root() {
+        let site = Site::new("traversal");
+        for target in ["/../secret.txt", "/docs/../../secret.txt", "/%2e%2e/secret.txt", "/.%2E/secret.txt"] {
+            assert_eq!(site.get(target).status, 403, "{}", target);
+        }
+        for target in ["/docs%2Findex.html", "/docs%2f..%2f..%2fsecret.txt", "/docs%5Cindex.html", "/index.html%00"] {
+            assert_eq!(site.get(target).status, 403, "{}", target);
+        }
+        assert_eq!(site.get("/%zz").status, 400);
+        assert_eq!(site.get("/%ff").status, 400);
+    }
+
+    #[test]
+    fn follows_symlinks_only_inside_the_root() {
+        let site = Site::new("symlinks");
+        assert_eq!(site.get("/inside").status, 200);
+        assert_eq!(site.get("/escape").status, 404);
+    }
+
+    #[test]
+    fn redirects_directories_to_a_local_path() {
+        let site = Site::new("redirects");
+        let location = |target: &str| {
+            let response = site.get(target);
+            assert_eq!(response.status, 301, "{}", target);
+            response.header("Location").unwrap().to_string()
+        };
+        assert_eq!(location("/docs"), "/docs/");
+        assert_eq!(location("/docs?page=2"), "/docs/?page=2");
+        assert_eq!(location("/a%20b"), "/a%20b/");
+        // Not a scheme-relative URL pointing at another host.
+        assert_eq!(location("//evil.example"), "/evil.example/");
+        assert_eq!(location("/./evil.example"), "/evil.example/");
+    }
+}
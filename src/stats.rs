@@ -0,0 +1,107 @@
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use sysinfo::{CpuExt, CpuRefreshKind, System, SystemExt};
+
+// One reading of the system, taken by the sampler thread. Handlers only ever
+// see complete snapshots; they are never mutated after being published.
+// Memory and swap figures are in bytes.
+pub struct Snapshot {
+    pub taken_at: SystemTime,
+    pub hostname: String,
+    pub os_name: String,
+    pub os_version: String,
+    pub kernel_version: String,
+    pub uptime_secs: u64,
+    // Unix time the system booted, in seconds.
+    pub boot_time: u64,
+    pub total_memory: u64,
+    pub used_memory: u64,
+    pub free_memory: u64,
+    pub available_memory: u64,
+    pub total_swap: u64,
+    pub used_swap: u64,
+    pub free_swap: u64,
+    pub cpu_count: usize,
+    pub cpu_brand: String,
+    pub cpu_frequency_mhz: u64,
+    // Usage since the previous sample, in percent: averaged over all cores,
+    // then per core.
+    pub cpu_usage: f32,
+    pub cpu_usage_per_core: Vec<f32>,
+    pub load_average: [f64; 3],
+}
+
+impl Snapshot {
+    fn take(sys: &System) -> Snapshot {
+        let load = sys.load_average();
+        Snapshot {
+            taken_at: SystemTime::now(),
+            hostname: sys.host_name().unwrap_or_default(),
+            os_name: sys.name().unwrap_or_default(),
+            os_version: sys.os_version().unwrap_or_default(),
+            kernel_version: sys.kernel_version().unwrap_or_default(),
+            uptime_secs: sys.uptime(),
+            boot_time: sys.boot_time(),
+            total_memory: sys.total_memory(),
+            used_memory: sys.used_memory(),
+            free_memory: sys.free_memory(),
+            available_memory: sys.available_memory(),
+            total_swap: sys.total_swap(),
+            used_swap: sys.used_swap(),
+            free_swap: sys.free_swap(),
+            cpu_count: sys.cpus().len(),
+            cpu_brand: sys.cpus().first().map(|c| c.brand().trim().to_string()).unwrap_or_default(),
+            cpu_frequency_mhz: sys.cpus().first().map(|c| c.frequency()).unwrap_or(0),
+            cpu_usage: sys.global_cpu_info().cpu_usage(),
+            cpu_usage_per_core: sys.cpus().iter().map(|c| c.cpu_usage()).collect(),
+            load_average: [load.one, load.five, load.fifteen],
+        }
+    }
+}
+
+// Owns the `sysinfo::System` and refreshes it on a fixed interval from a
+// background thread. Only memory and CPU usage/frequency are refreshed; a full
+// `refresh_all` also walks every process and disk, which we never show.
+pub struct Sampler {
+    latest: RwLock<Arc<Snapshot>>,
+}
+
+impl Sampler {
+    pub fn start(interval: Duration) -> Arc<Sampler> {
+        // CPU usage is the difference between two refreshes, so sampling
+        // faster than sysinfo's minimum interval just reads noise.
+        let interval = interval.max(System::MINIMUM_CPU_UPDATE_INTERVAL);
+
+        let mut sys = System::new();
+        refresh(&mut sys);
+        let sampler = Arc::new(Sampler {
+            latest: RwLock::new(Arc::new(Snapshot::take(&sys))),
+        });
+
+        let shared = Arc::clone(&sampler);
+        thread::Builder::new()
+            .name("stats-sampler".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                refresh(&mut sys);
+                let snapshot = Arc::new(Snapshot::take(&sys));
+                *shared.latest.write().unwrap() = snapshot;
+            })
+            .expect("failed to spawn stats sampler");
+
+        sampler
+    }
+
+    // The most recent snapshot. The lock is only held long enough to clone the
+    // `Arc`, so readers never wait on a refresh.
+    pub fn latest(&self) -> Arc<Snapshot> {
+        Arc::clone(&self.latest.read().unwrap())
+    }
+}
+
+fn refresh(sys: &mut System) {
+    sys.refresh_memory();
+    sys.refresh_cpu_specifics(CpuRefreshKind::new().with_cpu_usage().with_frequency());
+}
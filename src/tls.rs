@@ -0,0 +1,498 @@
+// TLS termination with rustls. The default certificate chain and private key
+// come from the PEM files named by TLS_CERT and TLS_KEY, or failing that from
+// a pair compiled in by build.rs (EMBED_TLS_CERT, EMBED_TLS_KEY). Further
+// pairs for particular host names are listed in TLS_SNI_CERTS, and the ACME
+// client keeps one for ACME_DOMAINS. With TLS_CLIENT_CA, clients may also
+// authenticate with a certificate.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use rustls::client::verify_server_name;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::{ClientHello, ParsedCertificate, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use serde_json::json;
+
+use crate::acme;
+use crate::config::Config;
+use crate::events::EventLog;
+use crate::metrics::Metered;
+use crate::response::Transport;
+use crate::router;
+use crate::x509::{self, Names};
+
+include!(concat!(env!("OUT_DIR"), "/tls.rs"));
+
+// Protocols offered in ALPN, most preferred first; HTTP/2 ahead of them
+// unless it is turned off.
+const ALPN: [&[u8]; 2] = [b"http/1.1", b"http/1.0"];
+pub const H2_ALPN: &[u8] = b"h2";
+// Offered as well while ACME is in use. A CA validating a TLS-ALPN-01
+// challenge asks for only this, and gets the challenge certificate.
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+// A TLS connection in threaded mode.
+pub type Stream<'a> = StreamOwned<ServerConnection, &'a TcpStream>;
+
+// Files have to be encrypted, so they are read through userspace rather than
+// sent with sendfile.
+impl Transport for Metered<'_, Stream<'_>> {}
+
+// What was negotiated for a connection.
+#[derive(Debug, Clone)]
+pub struct TlsInfo {
+    pub version: String,
+    pub cipher: String,
+    // The host name the client asked for (SNI), if it sent one.
+    pub server_name: Option<String>,
+    pub alpn: Option<String>,
+    // The client's certificate, if it sent one; it has been verified
+    // against TLS_CLIENT_CA.
+    pub client_cert: Option<Names>,
+}
+
+impl TlsInfo {
+    // A CA validating a TLS-ALPN-01 challenge; such connections carry no
+    // requests and are closed once the handshake is done.
+    pub fn is_acme_challenge(&self) -> bool {
+        self.alpn.as_deref() == Some("acme-tls/1")
+    }
+
+    pub fn is_h2(&self) -> bool {
+        self.alpn.as_deref() == Some("h2")
+    }
+
+    pub fn of(conn: &ServerConnection) -> TlsInfo {
+        TlsInfo {
+            client_cert: conn.peer_certificates().and_then(|certs| x509::names(certs.first()?)),
+            version: conn.protocol_version().map(|v| format!("{:?}", v)).unwrap_or_default(),
+            cipher: conn.negotiated_cipher_suite().map(|s| format!("{:?}", s.suite())).unwrap_or_default(),
+            server_name: conn.server_name().map(str::to_string),
+            alpn: conn.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+        }
+    }
+}
+
+// A TLS_SNI_CERTS entry: `name=cert.pem:key.pem`. The name is a host name or
+// a wildcard like `*.example.com`, which covers exactly one more label.
+#[derive(Debug, Clone)]
+pub struct SniCert {
+    pub name: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl FromStr for SniCert {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<SniCert, ()> {
+        let (name, files) = s.split_once('=').ok_or(())?;
+        let (cert, key) = files.split_once(':').ok_or(())?;
+        let name = name.trim().to_ascii_lowercase();
+        let host = name.strip_prefix("*.").unwrap_or(&name);
+        if host.is_empty() || host.contains('*') || cert.is_empty() || key.is_empty() {
+            return Err(());
+        }
+        Ok(SniCert { name, cert: cert.into(), key: key.into() })
+    }
+}
+
+// Where a certificate chain and its key are read from.
+#[derive(Debug)]
+enum Source {
+    Files { cert: PathBuf, key: PathBuf },
+    Embedded(&'static [u8], &'static [u8]),
+}
+
+impl Source {
+    fn load(&self, provider: &CryptoProvider) -> io::Result<CertifiedKey> {
+        let (certs, key) = match self {
+            Source::Files { cert, key } => (read(cert)?, read(key)?),
+            Source::Embedded(cert, key) => (cert.to_vec(), key.to_vec()),
+        };
+        let certs = CertificateDer::pem_slice_iter(&certs)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(format!("{}: {}", self, e)))?;
+        if certs.is_empty() {
+            return Err(invalid(format!("{}: no certificate", self)));
+        }
+        let key = PrivateKeyDer::from_pem_slice(&key).map_err(|e| invalid(format!("{}: private key: {}", self, e)))?;
+        CertifiedKey::from_der(certs, key, provider).map_err(|e| invalid(format!("{}: {}", self, e)))
+    }
+
+    // Modification times of the files, to notice when they are replaced.
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        match self {
+            Source::Files { cert, key } => [modified(cert), modified(key)],
+            Source::Embedded(..) => [None, None],
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Files { cert, .. } => write!(f, "{}", cert.display()),
+            Source::Embedded(..) => f.write_str("embedded certificate"),
+        }
+    }
+}
+
+// One complete set of loaded certificates.
+#[derive(Debug, Default)]
+struct Loaded {
+    count: usize,
+    default: Option<Arc<CertifiedKey>>,
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    // Wildcard entries, keyed by the domain after "*.".
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl Loaded {
+    fn lookup(&self, name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = name.to_ascii_lowercase();
+        if let Some(key) = self.exact.get(&name) {
+            return Some(Arc::clone(key));
+        }
+        let (_, parent) = name.split_once('.')?;
+        self.wildcard.get(parent).cloned()
+    }
+}
+
+// The certificates HTTPS is served with, picked per handshake by the name
+// the client asks for (SNI): an exact entry first, then a wildcard covering
+// it, then the default pair. A reload reads every file again and swaps the
+// whole set at once, or keeps the current one if anything fails to load, so
+// a handshake never sees a half-rotated set.
+#[derive(Debug)]
+pub struct Certificates {
+    default: Option<Source>,
+    named: Vec<(String, Source)>,
+    // The ACME client's certificate and the names it is for. It stands in
+    // for the default when there is none, and is skipped until the first
+    // one has been issued.
+    issued: Option<(Vec<String>, Source)>,
+    provider: Arc<CryptoProvider>,
+    loaded: RwLock<Arc<Loaded>>,
+    // File modification times as of the last reload attempt.
+    seen: Mutex<Vec<Option<SystemTime>>>,
+    // Certificates answering pending TLS-ALPN-01 challenges, by name.
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    // Where each reload is announced.
+    events: Arc<EventLog>,
+}
+
+impl Certificates {
+    // Checks which certificates are configured. Nothing is read until the
+    // first `reload`.
+    pub fn new(config: &Config, events: Arc<EventLog>) -> io::Result<Arc<Certificates>> {
+        let default = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(Source::Files { cert: cert.clone(), key: key.clone() }),
+            (None, None) => EMBEDDED_PEM.map(|(cert, key)| Source::Embedded(cert, key)),
+            _ => return Err(invalid("TLS_CERT and TLS_KEY have to be set together")),
+        };
+        if default.is_none() && config.tls_sni.is_empty() && config.acme_domains.is_empty() {
+            return Err(invalid("set TLS_CERT and TLS_KEY, TLS_SNI_CERTS or ACME_DOMAINS, or embed a pair at build time"));
+        }
+        let named = config
+            .tls_sni
+            .iter()
+            .map(|sni| (sni.name.clone(), Source::Files { cert: sni.cert.clone(), key: sni.key.clone() }))
+            .collect();
+        let issued = (!config.acme_domains.is_empty()).then(|| {
+            let source = Source::Files {
+                cert: config.acme_state_dir.join(acme::CERT_FILE),
+                key: config.acme_state_dir.join(acme::KEY_FILE),
+            };
+            (config.acme_domains.clone(), source)
+        });
+        Ok(Arc::new(Certificates {
+            default,
+            named,
+            issued,
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+            loaded: RwLock::new(Arc::new(Loaded::default())),
+            seen: Mutex::new(Vec::new()),
+            challenges: RwLock::new(HashMap::new()),
+            events,
+        }))
+    }
+
+    fn sources(&self) -> impl Iterator<Item = &Source> {
+        let named = self.named.iter().map(|(_, source)| source);
+        self.default.iter().chain(named).chain(self.issued.iter().map(|(_, source)| source))
+    }
+
+    // Loads every certificate and, if they all load, starts serving them.
+    // Returns how many there are, and logs and publishes the outcome either
+    // way.
+    pub fn reload(&self) -> io::Result<usize> {
+        *self.seen.lock().unwrap() = self.sources().flat_map(Source::modified).collect();
+        match self.load() {
+            Ok(loaded) => {
+                let count = loaded.count;
+                *self.loaded.write().unwrap() = Arc::new(loaded);
+                println!("Loaded {} TLS certificates", count);
+                self.events.publish("reload", json!({ "component": "tls", "certificates": count }));
+                Ok(count)
+            }
+            Err(e) => {
+                println!("Unable to load TLS certificates: {}", e);
+                self.events.publish("reload", json!({ "component": "tls", "error": e.to_string() }));
+                Err(e)
+            }
+        }
+    }
+
+    fn load(&self) -> io::Result<Loaded> {
+        let mut loaded = Loaded {
+            default: match &self.default {
+                Some(source) => Some(Arc::new(source.load(&self.provider)?)),
+                None => None,
+            },
+            ..Loaded::default()
+        };
+        loaded.count = usize::from(loaded.default.is_some()) + self.named.len();
+        for (name, source) in &self.named {
+            let key = Arc::new(source.load(&self.provider)?);
+            match name.strip_prefix("*.") {
+                Some(parent) => {
+                    loaded.wildcard.insert(parent.to_string(), key);
+                }
+                None => {
+                    // Catch a pair listed under the wrong name now rather
+                    // than as a failed handshake later.
+                    let host = ServerName::try_from(name.as_str()).map_err(|e| invalid(format!("{}: {}", name, e)))?;
+                    let cert = ParsedCertificate::try_from(&key.cert[0]).map_err(|e| invalid(format!("{}: {}", source, e)))?;
+                    verify_server_name(&cert, &host).map_err(|e| invalid(format!("{} for {}: {}", source, name, e)))?;
+                    loaded.exact.insert(name.clone(), key);
+                }
+            }
+        }
+        if let Some((names, source @ Source::Files { cert, .. })) = &self.issued {
+            if cert.exists() {
+                let key = Arc::new(source.load(&self.provider)?);
+                // Pairs configured by hand for the same names win.
+                for name in names {
+                    loaded.exact.entry(name.clone()).or_insert_with(|| Arc::clone(&key));
+                }
+                loaded.default.get_or_insert(key);
+                loaded.count += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    // Starts answering TLS-ALPN-01 challenges for `name` with this
+    // certificate (DER) and PKCS #8 key.
+    pub fn add_challenge(&self, name: &str, cert: Vec<u8>, key: Vec<u8>) -> io::Result<()> {
+        let key = PrivateKeyDer::try_from(key).map_err(invalid)?;
+        // Not `from_der`: webpki won't parse a certificate with the critical
+        // acmeIdentifier extension in it.
+        let key = self.provider.key_provider.load_private_key(key).map_err(io::Error::other)?;
+        let key = CertifiedKey::new(vec![CertificateDer::from(cert)], key);
+        self.challenges.write().unwrap().insert(name.to_string(), Arc::new(key));
+        Ok(())
+    }
+
+    pub fn remove_challenge(&self, name: &str) {
+        self.challenges.write().unwrap().remove(name);
+    }
+
+    // Checks the files every `interval` and reloads when any of them has
+    // been replaced. Failed loads are retried once the files change again.
+    pub fn watch(self: &Arc<Certificates>, interval: Duration) {
+        let certs = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let modified: Vec<_> = certs.sources().flat_map(Source::modified).collect();
+            if modified != *certs.seen.lock().unwrap() {
+                let _ = certs.reload();
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        if hello.alpn().is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN)) {
+            let name = hello.server_name()?;
+            return self.challenges.read().unwrap().get(name).cloned();
+        }
+        let loaded = Arc::clone(&self.loaded.read().unwrap());
+        hello.server_name().and_then(|name| loaded.lookup(name)).or_else(|| loaded.default.clone())
+    }
+}
+
+// How a route treats client certificates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuth {
+    // Requests without a verified client certificate get 403.
+    Required,
+    // A certificate is passed on to the handler and the log if there is one.
+    Optional,
+    // Any certificate is ignored, as if none had been sent.
+    None,
+}
+
+impl FromStr for ClientAuth {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ClientAuth, ()> {
+        match s {
+            "required" => Ok(ClientAuth::Required),
+            "optional" => Ok(ClientAuth::Optional),
+            "none" => Ok(ClientAuth::None),
+            _ => Err(()),
+        }
+    }
+}
+
+// A CLIENT_AUTH entry: `/path=policy`, covering that path and everything
+// below it.
+#[derive(Debug, Clone)]
+pub struct ClientAuthRule {
+    path: Vec<String>,
+    policy: ClientAuth,
+}
+
+impl FromStr for ClientAuthRule {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ClientAuthRule, ()> {
+        let (path, policy) = s.split_once('=').ok_or(())?;
+        if !path.starts_with('/') {
+            return Err(());
+        }
+        Ok(ClientAuthRule { path: segments(path), policy: policy.trim().parse()? })
+    }
+}
+
+// The policy for a request path: that of the most specific rule covering
+// it, or `Optional` if none does.
+pub fn client_auth(rules: &[ClientAuthRule], path: &str) -> ClientAuth {
+    let path = segments(path);
+    rules
+        .iter()
+        .filter(|rule| path.starts_with(&rule.path))
+        .max_by_key(|rule| rule.path.len())
+        .map_or(ClientAuth::Optional, |rule| rule.policy)
+}
+
+// Paths are compared the way the router sees them, percent-decoded, so an
+// encoded path can't slip past a rule. Empty and `.` segments are dropped,
+// which only makes rules cover more: `//admin/./x` is under `/admin`.
+fn segments(path: &str) -> Vec<String> {
+    router::split_path(path).into_iter().filter(|s| !s.is_empty() && s != ".").collect()
+}
+
+// Builds the server's TLS settings: rustls' defaults with the ring provider,
+// which means TLS 1.3 and 1.2 with forward-secret AEAD suites only. With a
+// client CA, every client is asked for a certificate but may go without one;
+// one that doesn't verify against the CA fails the handshake.
+pub fn server_config(certs: &Arc<Certificates>, config: &Config) -> io::Result<Arc<ServerConfig>> {
+    let provider = Arc::clone(&certs.provider);
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(e.to_string()))?;
+    let builder = match &config.tls_client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_slice_iter(&read(path)?) {
+                let cert = cert.map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+                roots.add(cert).map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()
+                .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls = builder.with_cert_resolver(Arc::clone(certs) as Arc<dyn ResolvesServerCert>);
+    tls.alpn_protocols = ALPN.iter().map(|p| p.to_vec()).collect();
+    if config.http2 {
+        tls.alpn_protocols.insert(0, H2_ALPN.to_vec());
+    }
+    if certs.issued.is_some() {
+        tls.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+    }
+    Ok(Arc::new(tls))
+}
+
+// Runs the handshake on a blocking socket. The socket's read timeout bounds
+// how long a client may take over it.
+pub fn accept<'a>(config: &Arc<ServerConfig>, socket: &'a TcpStream) -> io::Result<(Stream<'a>, TlsInfo)> {
+    let mut conn = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+    let mut io = socket;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut io)?;
+    }
+    let info = TlsInfo::of(&conn);
+    Ok((StreamOwned::new(conn, socket), info))
+}
+
+// fs::read, with the path in the error.
+fn read(path: &Path) -> io::Result<Vec<u8>> {
+    fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn invalid(why: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, why.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sni_entries() {
+        let sni: SniCert = "WWW.Example.com=/c/www.pem:/c/www.key".parse().unwrap();
+        assert_eq!(sni.name, "www.example.com");
+        assert_eq!(sni.cert, Path::new("/c/www.pem"));
+        assert_eq!(sni.key, Path::new("/c/www.key"));
+        assert_eq!("*.example.com=a:b".parse::<SniCert>().unwrap().name, "*.example.com");
+
+        assert!("example.com".parse::<SniCert>().is_err());
+        assert!("example.com=a.pem".parse::<SniCert>().is_err());
+        assert!("=a:b".parse::<SniCert>().is_err());
+        assert!("*.=a:b".parse::<SniCert>().is_err());
+        assert!("*.*.example.com=a:b".parse::<SniCert>().is_err());
+        assert!("example.com=:b".parse::<SniCert>().is_err());
+    }
+
+    #[test]
+    fn picks_the_most_specific_client_auth_rule() {
+        let rules: Vec<ClientAuthRule> = ["/=none", "/admin=required", "/admin/public/=optional", "/metrics=required"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        assert_eq!(client_auth(&rules, "/"), ClientAuth::None);
+        assert_eq!(client_auth(&rules, "/index.html"), ClientAuth::None);
+        assert_eq!(client_auth(&rules, "/metrics"), ClientAuth::Required);
+        assert_eq!(client_auth(&rules, "/admin/tls/reload"), ClientAuth::Required);
+        assert_eq!(client_auth(&rules, "/admin/public/x"), ClientAuth::Optional);
+        assert_eq!(client_auth(&rules, "/administrator"), ClientAuth::None);
+        // Spelled differently, but routed the same.
+        assert_eq!(client_auth(&rules, "/%61dmin/tls/reload"), ClientAuth::Required);
+        assert_eq!(client_auth(&rules, "//admin/./tls/reload"), ClientAuth::Required);
+        assert_eq!(client_auth(&[], "/admin"), ClientAuth::Optional);
+
+        assert!("admin=required".parse::<ClientAuthRule>().is_err());
+        assert!("/admin=always".parse::<ClientAuthRule>().is_err());
+    }
+}
@@ -0,0 +1,567 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use ring::digest;
+
+use crate::acme;
+use crate::request::{Method, Request, Version};
+use crate::response::{Response, TICK};
+use crate::shutdown;
+
+// RFC 6455 WebSocket. `accept` answers the opening handshake with a 101 that
+// carries a `Handler`; the connection is then handed to a `Connection`, which
+// parses the client's frames and queues ours without doing any IO itself.
+// `serve` drives one from a worker thread; the event loop feeds it directly.
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// Close codes (section 7.4.1).
+pub const NORMAL: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const INVALID_DATA: u16 = 1007;
+pub const TOO_BIG: u16 = 1009;
+
+// Messages from clients, put together from all their fragments, may be at
+// most this large.
+const MAX_MESSAGE: usize = 64 * 1024;
+
+// A client that has sent nothing for this long is pinged, and dropped if it
+// stays silent as long again.
+const PING_AFTER: Duration = Duration::from_secs(30);
+
+// How long to wait for the client to answer our close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+// The application end of a WebSocket. Methods run on the thread serving the
+// connection, so like route handlers they must not block.
+pub trait Handler: Send {
+    // A complete message from the client. Returns a reply, if any.
+    fn on_message(&mut self, _message: Message) -> Option<Message> {
+        None
+    }
+
+    // Called about every `response::TICK`; returns a message to push, if one
+    // is due.
+    fn poll(&mut self, now: Instant) -> Option<Message>;
+}
+
+// Answers a WebSocket opening handshake, handing the connection to `handler`
+// once the 101 is sent. Requests that aren't a valid handshake get 400, or
+// 426 naming the version we speak.
+pub fn accept(req: &Request, handler: impl Handler + 'static) -> Response {
+    if !req.headers.has_token("Upgrade", "websocket") {
+        return Response::status_page(426)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade");
+    }
+    if req.headers.get("Sec-WebSocket-Version") != Some("13") {
+        return Response::status_page(426).with_header("Sec-WebSocket-Version", "13");
+    }
+    let key = match req.headers.get("Sec-WebSocket-Key") {
+        Some(key) if is_key(key) => key,
+        _ => return Response::status_page(400),
+    };
+    if req.method != Method::Get || req.version != Version::Http11 || !req.headers.has_token("Connection", "upgrade") {
+        return Response::status_page(400);
+    }
+    Response::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key))
+        .with_upgrade(Box::new(handler))
+}
+
+// The key is 16 random bytes in base64.
+fn is_key(key: &str) -> bool {
+    let key = key.as_bytes();
+    key.len() == 24
+        && key.ends_with(b"==")
+        && key[..22].iter().all(|b| b.is_ascii_alphanumeric() || *b == b'+' || *b == b'/')
+}
+
+fn accept_key(key: &str) -> String {
+    let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, format!("{}{}", key, GUID).as_bytes());
+    acme::base64(hash.as_ref(), acme::STANDARD)
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// A close frame to send before giving up on the client.
+type Failure = (u16, &'static str);
+
+// Parses the frame at the start of `buf`, unmasking its payload. `Ok(None)`
+// until all of it has arrived; the length is checked before waiting for the
+// payload, so an oversized frame is refused without being buffered.
+fn parse_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, Failure> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let (fin, opcode) = (buf[0] & 0x80 != 0, buf[0] & 0x0f);
+    if buf[0] & 0x70 != 0 {
+        return Err((PROTOCOL_ERROR, "reserved bits set"));
+    }
+    if buf[1] & 0x80 == 0 {
+        return Err((PROTOCOL_ERROR, "unmasked frame from a client"));
+    }
+    let (len, mut used) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u64::from(u16::from_be_bytes([buf[2], buf[3]])), 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().expect("8 bytes")), 10),
+        126 | 127 => return Ok(None),
+        len => (u64::from(len), 2),
+    };
+    if opcode & 0x8 != 0 && (len > 125 || !fin) {
+        return Err((PROTOCOL_ERROR, "fragmented or oversized control frame"));
+    }
+    if len > MAX_MESSAGE as u64 {
+        return Err((TOO_BIG, "message too big"));
+    }
+    let len = len as usize;
+    if buf.len() < used + 4 + len {
+        return Ok(None);
+    }
+    let mask = [buf[used], buf[used + 1], buf[used + 2], buf[used + 3]];
+    used += 4;
+    let payload = buf[used..used + len].iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+    Ok(Some((Frame { fin, opcode, payload }, used + len)))
+}
+
+// Appends an unmasked, unfragmented frame, as servers send them.
+fn write_frame(out: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    out.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => out.push(len as u8),
+        len @ 126..=0xffff => {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+}
+
+// Codes an endpoint may send in a close frame. 1005 and 1006 only ever
+// appear in APIs, 1015 is for TLS failures, and 1016-2999 are unassigned.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+pub struct Connection {
+    handler: Box<dyn Handler>,
+    // The opcode and payload so far of a fragmented message.
+    partial: Option<(u8, Vec<u8>)>,
+    // Frames waiting to be sent.
+    pub out: Vec<u8>,
+    last_heard: Instant,
+    ping_sent: bool,
+    // When we sent our close frame.
+    close_sent: Option<Instant>,
+    // Nothing more will be sent or read; the connection should be dropped
+    // once `out` is.
+    done: bool,
+}
+
+impl Connection {
+    pub fn new(handler: Box<dyn Handler>) -> Connection {
+        println!("WebSocket opened");
+        Connection {
+            handler,
+            partial: None,
+            out: Vec::new(),
+            last_heard: Instant::now(),
+            ping_sent: false,
+            close_sent: None,
+            done: false,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // Acts on every complete frame at the start of `input`, then removes
+    // them.
+    pub fn receive(&mut self, input: &mut Vec<u8>) {
+        let mut used = 0;
+        while !self.done {
+            match parse_frame(&input[used..]) {
+                Ok(Some((frame, n))) => {
+                    used += n;
+                    self.last_heard = Instant::now();
+                    self.ping_sent = false;
+                    if let Err((code, reason)) = self.on_frame(frame) {
+                        self.fail(code, reason);
+                    }
+                }
+                Ok(None) => break,
+                Err((code, reason)) => self.fail(code, reason),
+            }
+        }
+        if self.done {
+            used = input.len();
+        }
+        input.drain(..used);
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<(), Failure> {
+        match frame.opcode {
+            TEXT | BINARY if self.partial.is_some() => Err((PROTOCOL_ERROR, "new message before the last one ended")),
+            TEXT | BINARY if frame.fin => self.on_message(frame.opcode, frame.payload),
+            TEXT | BINARY => {
+                self.partial = Some((frame.opcode, frame.payload));
+                Ok(())
+            }
+            CONTINUATION => {
+                let (opcode, mut payload) = self.partial.take().ok_or((PROTOCOL_ERROR, "continuation of nothing"))?;
+                if payload.len() + frame.payload.len() > MAX_MESSAGE {
+                    return Err((TOO_BIG, "message too big"));
+                }
+                payload.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    self.on_message(opcode, payload)
+                } else {
+                    self.partial = Some((opcode, payload));
+                    Ok(())
+                }
+            }
+            CLOSE => self.on_close(&frame.payload),
+            PING => {
+                if self.close_sent.is_none() {
+                    write_frame(&mut self.out, PONG, &frame.payload);
+                }
+                Ok(())
+            }
+            PONG => Ok(()),
+            _ => Err((PROTOCOL_ERROR, "unknown opcode")),
+        }
+    }
+
+    fn on_message(&mut self, opcode: u8, payload: Vec<u8>) -> Result<(), Failure> {
+        let message = match opcode {
+            TEXT => Message::Text(String::from_utf8(payload).map_err(|_| (INVALID_DATA, "text is not UTF-8"))?),
+            _ => Message::Binary(payload),
+        };
+        // Once we have sent a close frame, we may not send anything else.
+        if self.close_sent.is_none() {
+            if let Some(reply) = self.handler.on_message(message) {
+                self.send(reply);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, payload: &[u8]) -> Result<(), Failure> {
+        let code = match payload {
+            [] => None,
+            [_] => return Err((PROTOCOL_ERROR, "truncated close code")),
+            [a, b, reason @ ..] => {
+                let code = u16::from_be_bytes([*a, *b]);
+                if !is_valid_close_code(code) {
+                    return Err((PROTOCOL_ERROR, "invalid close code"));
+                }
+                if std::str::from_utf8(reason).is_err() {
+                    return Err((INVALID_DATA, "close reason is not UTF-8"));
+                }
+                Some(code)
+            }
+        };
+        match code {
+            Some(code) => println!("WebSocket closed by the client: {}", code),
+            None => println!("WebSocket closed by the client"),
+        }
+        // Echo the code back, unless this answers our own close.
+        if self.close_sent.is_none() {
+            write_frame(&mut self.out, CLOSE, &code.unwrap_or(NORMAL).to_be_bytes());
+        }
+        self.done = true;
+        Ok(())
+    }
+
+    // Sends whatever the handler has due, and keeps an eye on a client that
+    // has gone quiet.
+    pub fn tick(&mut self, now: Instant) {
+        if self.done {
+            return;
+        }
+        if let Some(sent) = self.close_sent {
+            if now.duration_since(sent) >= CLOSE_TIMEOUT {
+                println!("WebSocket client never answered our close");
+                self.done = true;
+            }
+            return;
+        }
+        if shutdown::draining() {
+            self.close(GOING_AWAY, "server shutting down");
+            return;
+        }
+        let silent = now.duration_since(self.last_heard);
+        if silent >= PING_AFTER * 2 {
+            println!("WebSocket client stopped answering pings");
+            self.done = true;
+            return;
+        }
+        if silent >= PING_AFTER && !self.ping_sent {
+            write_frame(&mut self.out, PING, b"");
+            self.ping_sent = true;
+        }
+        if let Some(message) = self.handler.poll(now) {
+            self.send(message);
+        }
+    }
+
+    fn send(&mut self, message: Message) {
+        match message {
+            Message::Text(text) => write_frame(&mut self.out, TEXT, text.as_bytes()),
+            Message::Binary(data) => write_frame(&mut self.out, BINARY, &data),
+        }
+    }
+
+    // Starts the closing handshake; the client should answer with a close
+    // frame of its own.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if self.done || self.close_sent.is_some() {
+            return;
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        write_frame(&mut self.out, CLOSE, &payload);
+        self.close_sent = Some(Instant::now());
+    }
+
+    // Closes the connection straight after telling the client why, as the
+    // RFC asks of an endpoint that receives something invalid.
+    fn fail(&mut self, code: u16, reason: &str) {
+        println!("Closing WebSocket: {} ({})", reason, code);
+        self.close(code, reason);
+        self.done = true;
+    }
+}
+
+// Runs a WebSocket from a worker thread until either side closes it. `buf`
+// holds anything the client sent after the handshake. Reads wait at most a
+// `TICK` so the handler is polled on time.
+pub fn serve<S: Read + Write>(stream: &mut S, socket: &TcpStream, mut buf: Vec<u8>, handler: Box<dyn Handler>) {
+    if let Err(e) = socket.set_read_timeout(Some(TICK)) {
+        println!("Unable to set socket timeouts: {}", e);
+        return;
+    }
+    let mut conn = Connection::new(handler);
+    let mut chunk = [0u8; 4096];
+    loop {
+        conn.receive(&mut buf);
+        conn.tick(Instant::now());
+        if let Err(e) = stream.write_all(&conn.out).and_then(|_| stream.flush()) {
+            println!("Failed sending WebSocket frames: {}", e);
+            return;
+        }
+        conn.out.clear();
+        if conn.is_done() {
+            return;
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => {
+                println!("WebSocket client went away");
+                return;
+            }
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => {}
+            Err(e) => {
+                println!("Unable to read stream: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request;
+
+    // Echoes messages back, and pushes "tick" when polled.
+    struct Echo;
+
+    impl Handler for Echo {
+        fn on_message(&mut self, message: Message) -> Option<Message> {
+            Some(message)
+        }
+
+        fn poll(&mut self, _now: Instant) -> Option<Message> {
+            Some(Message::Text("tick".to_string()))
+        }
+    }
+
+    fn handshake(extra: &str) -> Request {
+        let raw = format!(
+            "GET /ws HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n{}\r\n",
+            extra
+        );
+        request::Parser::default().parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    // A masked client frame.
+    fn frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut out = vec![first];
+        match payload.len() {
+            len @ 0..=125 => out.push(0x80 | len as u8),
+            len => {
+                out.push(0x80 | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        out
+    }
+
+    fn server_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_frame(&mut out, opcode, payload);
+        out
+    }
+
+    fn received(input: &[u8]) -> Connection {
+        let mut conn = Connection::new(Box::new(Echo));
+        conn.receive(&mut input.to_vec());
+        conn
+    }
+
+    #[test]
+    fn answers_the_handshake() {
+        // The example from section 1.3.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let req = handshake("Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n");
+        let response = accept(&req, Echo);
+        assert_eq!(response.status, 101);
+        assert_eq!(response.header("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert!(response.upgrade.is_some());
+
+        let response = accept(&handshake("Sec-WebSocket-Version: 8\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"), Echo);
+        assert_eq!((response.status, response.header("Sec-WebSocket-Version")), (426, Some("13")));
+        let response = accept(&handshake("Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: short\r\n"), Echo);
+        assert_eq!(response.status, 400);
+        let plain = request::Parser::default().parse(b"GET /ws HTTP/1.1\r\nHost: x\r\n\r\n").unwrap().unwrap().0;
+        assert_eq!(accept(&plain, Echo).status, 426);
+    }
+
+    #[test]
+    fn parses_masked_frames() {
+        let (frame, used) = parse_frame(&frame(0x81, b"Hello")).unwrap().unwrap();
+        assert_eq!((frame.fin, frame.opcode, frame.payload.as_slice(), used), (true, TEXT, &b"Hello"[..], 11));
+
+        let long = super::tests::frame(0x82, &[7; 300]);
+        assert!(parse_frame(&long[..long.len() - 1]).unwrap().is_none());
+        let (frame, used) = parse_frame(&long).unwrap().unwrap();
+        assert_eq!((frame.payload.len(), used), (300, long.len()));
+
+        assert_eq!(server_frame(TEXT, b"Hi"), b"\x81\x02Hi");
+        assert_eq!(&server_frame(BINARY, &[0; 300])[..4], b"\x82\x7e\x01\x2c");
+    }
+
+    #[test]
+    fn echoes_messages_and_answers_pings() {
+        let mut input = frame(0x01, b"Hel");
+        input.extend(frame(0x89, b"p"));
+        input.extend(frame(0x80, b"lo"));
+        let conn = received(&input);
+        let mut expected = server_frame(PONG, b"p");
+        expected.extend(server_frame(TEXT, b"Hello"));
+        assert_eq!(conn.out, expected);
+        assert!(!conn.is_done());
+
+        // A partial frame waits for the rest.
+        let mut conn = Connection::new(Box::new(Echo));
+        let whole = frame(0x82, &[1, 2, 3]);
+        let mut input = whole[..4].to_vec();
+        conn.receive(&mut input);
+        assert_eq!((input.len(), conn.out.len()), (4, 0));
+        input.extend_from_slice(&whole[4..]);
+        conn.receive(&mut input);
+        assert_eq!((input.len(), conn.out), (0, server_frame(BINARY, &[1, 2, 3])));
+    }
+
+    #[test]
+    fn closes_cleanly() {
+        let conn = received(&frame(0x88, b"\x03\xe9bye"));
+        assert_eq!(conn.out, server_frame(CLOSE, &GOING_AWAY.to_be_bytes()));
+        assert!(conn.is_done());
+
+        // Our close, answered by the client; nothing more is pushed.
+        let mut conn = Connection::new(Box::new(Echo));
+        conn.close(NORMAL, "");
+        conn.out.clear();
+        conn.receive(&mut frame(0x81, b"late"));
+        conn.tick(Instant::now());
+        assert!(conn.out.is_empty());
+        conn.receive(&mut frame(0x88, &NORMAL.to_be_bytes()));
+        assert!(conn.out.is_empty());
+        assert!(conn.is_done());
+    }
+
+    #[test]
+    fn fails_on_invalid_frames() {
+        let cases: [(Vec<u8>, u16); 9] = [
+            (b"\x81\x02Hi".to_vec(), PROTOCOL_ERROR),
+            (frame(0xc1, b"Hi"), PROTOCOL_ERROR),
+            (frame(0x83, b""), PROTOCOL_ERROR),
+            (frame(0x09, b""), PROTOCOL_ERROR),
+            (frame(0x80, b"Hi"), PROTOCOL_ERROR),
+            (frame(0x81, &[0xff, 0xfe]), INVALID_DATA),
+            (frame(0x88, &[0x03]), PROTOCOL_ERROR),
+            (frame(0x88, &1005u16.to_be_bytes()), PROTOCOL_ERROR),
+            (b"\x82\xff\x00\x00\x00\x00\x01\x00\x00\x00".to_vec(), TOO_BIG),
+        ];
+        for (input, code) in cases {
+            let conn = received(&input);
+            assert!(conn.is_done());
+            assert_eq!(conn.out[2..4], code.to_be_bytes());
+        }
+
+        let mut input = frame(0x01, b"a");
+        input.extend(frame(0x81, b"b"));
+        assert_eq!(received(&input).out[2..4], PROTOCOL_ERROR.to_be_bytes());
+
+        let mut input = frame(0x02, &[0; 60_000]);
+        input.extend(frame(0x80, &[0; 10_000]));
+        assert_eq!(received(&input).out[2..4], TOO_BIG.to_be_bytes());
+    }
+
+    #[test]
+    fn pushes_and_pings_on_ticks() {
+        let mut conn = Connection::new(Box::new(Echo));
+        let start = Instant::now();
+        conn.tick(start);
+        assert_eq!(conn.out, server_frame(TEXT, b"tick"));
+
+        conn.out.clear();
+        conn.tick(start + PING_AFTER);
+        let mut expected = server_frame(PING, b"");
+        expected.extend(server_frame(TEXT, b"tick"));
+        assert_eq!(conn.out, expected);
+
+        conn.tick(start + PING_AFTER * 2);
+        assert!(conn.is_done());
+    }
+}
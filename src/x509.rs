@@ -0,0 +1,471 @@
+// Just enough DER to read the subject, subject alternative names and expiry
+// of certificates rustls has already verified, so they can be logged, shown
+// to handlers and renewed in time, and to write the two kinds of document
+// the ACME client needs: a certificate signing request and the self-signed
+// certificate a TLS-ALPN-01 challenge is answered with. Nothing here checks
+// signatures or validity.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use ring::rand::SecureRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair};
+
+use crate::format::civil_from_days;
+use crate::http_date::days_from_civil;
+
+// Who a certificate was issued to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Names {
+    // The subject as an RFC 4514 string, most specific part first, e.g.
+    // "CN=ops,O=Example".
+    pub subject: String,
+    // Subject alternative names, each prefixed with its kind the way OpenSSL
+    // prints them: "DNS:host", "email:a@b", "URI:spiffe://x", "IP:10.0.0.1".
+    pub san: Vec<String>,
+}
+
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0c;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const BMP_STRING: u8 = 0x1e;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+// Explicitly tagged fields of TBSCertificate, and the implicitly tagged
+// attributes of a CSR.
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+const ATTRIBUTES: u8 = 0xa0;
+// GeneralName choices that are shown; the rest are skipped.
+const RFC822_NAME: u8 = 0x81;
+const DNS_NAME: u8 = 0x82;
+const URI: u8 = 0x86;
+const IP_ADDRESS: u8 = 0x87;
+
+// id-ce-subjectAltName, 2.5.29.17.
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+// id-pe-acmeIdentifier, 1.3.6.1.5.5.7.1.31 (RFC 8737).
+const ACME_IDENTIFIER: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f];
+// commonName, 2.5.4.3.
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+// id-ecPublicKey with the prime256v1 curve, and ecdsa-with-SHA256.
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+// PKCS #9 extensionRequest, 1.2.840.113549.1.9.14.
+const EXTENSION_REQUEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+
+// The fields of a TBSCertificate read here.
+struct Tbs<'a> {
+    validity: &'a [u8],
+    subject: &'a [u8],
+    extensions: Option<&'a [u8]>,
+}
+
+fn tbs(cert: &[u8]) -> Option<Tbs<'_>> {
+    let cert = Der::new(cert).read(SEQUENCE)?;
+    let mut tbs = Der::new(Der::new(cert).read(SEQUENCE)?);
+    if tbs.peek() == Some(VERSION) {
+        tbs.next()?;
+    }
+    // serialNumber, signature and issuer.
+    for _ in 0..3 {
+        tbs.next()?;
+    }
+    let validity = tbs.read(SEQUENCE)?;
+    let subject = tbs.read(SEQUENCE)?;
+    // subjectPublicKeyInfo, then the optional unique IDs and extensions.
+    tbs.next()?;
+    let mut extensions = None;
+    while let Some((tag, value)) = tbs.next() {
+        if tag == EXTENSIONS {
+            extensions = Some(value);
+        }
+    }
+    Some(Tbs { validity, subject, extensions })
+}
+
+// Reads the names from a DER-encoded certificate, or None if it isn't one.
+pub fn names(cert: &[u8]) -> Option<Names> {
+    let tbs = tbs(cert)?;
+    let san = match tbs.extensions {
+        Some(extensions) => subject_alt_names(extensions)?,
+        None => Vec::new(),
+    };
+    Some(Names { subject: distinguished_name(tbs.subject)?, san })
+}
+
+// When a DER-encoded certificate expires, in Unix seconds.
+pub fn not_after(cert: &[u8]) -> Option<u64> {
+    let mut validity = Der::new(tbs(cert)?.validity);
+    validity.next()?;
+    let (tag, time) = validity.next()?;
+    parse_time(tag, std::str::from_utf8(time).ok()?)
+}
+
+// UTCTime ("YYMMDDHHMMSSZ", 1950-2049) or GeneralizedTime
+// ("YYYYMMDDHHMMSSZ"), which is all RFC 5280 allows certificates to use.
+fn parse_time(tag: u8, time: &str) -> Option<u64> {
+    let (year, rest) = match tag {
+        UTC_TIME => {
+            let year: i64 = time.get(..2)?.parse().ok()?;
+            (if year < 50 { 2000 + year } else { 1900 + year }, &time[2..])
+        }
+        GENERALIZED_TIME => (time.get(..4)?.parse().ok()?, &time[4..]),
+        _ => return None,
+    };
+    if rest.len() != 11 || !rest.ends_with('Z') || !rest[..10].bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let field = |i: usize| rest[i..i + 2].parse::<u32>().unwrap_or(0);
+    let (month, day, h, m, s) = (field(0), field(2), field(4), field(6), field(8));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || h > 23 || m > 59 || s > 60 {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(days * 86_400 + u64::from(h * 3_600 + m * 60 + s))
+}
+
+fn distinguished_name(name: &[u8]) -> Option<String> {
+    let mut rdns = Vec::new();
+    let mut name = Der::new(name);
+    while !name.is_empty() {
+        let mut set = Der::new(name.read(SET)?);
+        let mut parts = Vec::new();
+        while !set.is_empty() {
+            let mut attribute = Der::new(set.read(SEQUENCE)?);
+            let kind = attribute_name(attribute.read(OID)?);
+            let (tag, value) = attribute.next()?;
+            parts.push(format!("{}={}", kind, escape(&string_value(tag, value))));
+        }
+        rdns.push(parts.join("+"));
+    }
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+fn attribute_name(oid: &[u8]) -> String {
+    let name = match oid {
+        [0x55, 0x04, 0x03] => "CN",
+        [0x55, 0x04, 0x05] => "serialNumber",
+        [0x55, 0x04, 0x06] => "C",
+        [0x55, 0x04, 0x07] => "L",
+        [0x55, 0x04, 0x08] => "ST",
+        [0x55, 0x04, 0x09] => "STREET",
+        [0x55, 0x04, 0x0a] => "O",
+        [0x55, 0x04, 0x0b] => "OU",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01] => "UID",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19] => "DC",
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01] => "emailAddress",
+        _ => return dotted(oid),
+    };
+    name.to_string()
+}
+
+// An OID in dotted-decimal form, e.g. "2.5.4.3".
+fn dotted(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut value: u64 = 0;
+    for &b in oid {
+        value = value << 7 | u64::from(b & 0x7f);
+        if b & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (value / 40).min(2);
+                arcs.push(first);
+                arcs.push(value - first * 40);
+            } else {
+                arcs.push(value);
+            }
+            value = 0;
+        }
+    }
+    arcs.iter().map(u64::to_string).collect::<Vec<_>>().join(".")
+}
+
+// Directory strings are UTF-8, or an ASCII subset of it, apart from the
+// UTF-16 BMPString some older CAs still use.
+fn string_value(tag: u8, value: &[u8]) -> String {
+    if tag == BMP_STRING {
+        let units: Vec<u16> = value.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+        return String::from_utf16_lossy(&units);
+    }
+    String::from_utf8_lossy(value).into_owned()
+}
+
+// Escapes an attribute value for an RFC 4514 string.
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, '"' | '+' | ',' | ';' | '<' | '>' | '\\')
+            || (i == 0 && matches!(c, '#' | ' '))
+            || (i == last && c == ' ');
+        if c == '\0' {
+            out.push_str("\\00");
+            continue;
+        }
+        if special {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn subject_alt_names(extensions: &[u8]) -> Option<Vec<String>> {
+    let mut extensions = Der::new(Der::new(extensions).read(SEQUENCE)?);
+    while !extensions.is_empty() {
+        let mut extension = Der::new(extensions.read(SEQUENCE)?);
+        let oid = extension.read(OID)?;
+        if extension.peek() == Some(BOOLEAN) {
+            extension.next()?;
+        }
+        let value = extension.read(OCTET_STRING)?;
+        if oid != SUBJECT_ALT_NAME {
+            continue;
+        }
+        let mut names = Der::new(Der::new(value).read(SEQUENCE)?);
+        let mut san = Vec::new();
+        while let Some((tag, name)) = names.next() {
+            let text = String::from_utf8_lossy(name);
+            match (tag, name.len()) {
+                (RFC822_NAME, _) => san.push(format!("email:{}", text)),
+                (DNS_NAME, _) => san.push(format!("DNS:{}", text)),
+                (URI, _) => san.push(format!("URI:{}", text)),
+                (IP_ADDRESS, 4) => san.push(format!("IP:{}", Ipv4Addr::from(<[u8; 4]>::try_from(name).ok()?))),
+                (IP_ADDRESS, 16) => san.push(format!("IP:{}", Ipv6Addr::from(<[u8; 16]>::try_from(name).ok()?))),
+                _ => {}
+            }
+        }
+        return Some(san);
+    }
+    Some(Vec::new())
+}
+
+// A certificate signing request for `domains`, the first of which is also
+// the subject's common name. `key` has to sign with
+// ECDSA_P256_SHA256_ASN1_SIGNING.
+pub fn csr(domains: &[String], key: &EcdsaKeyPair, rng: &dyn SecureRandom) -> Option<Vec<u8>> {
+    let subject = name(domains.first()?);
+    let extensions = tlv(SEQUENCE, &subject_alt_name(domains));
+    let request = tlv(SEQUENCE, &[tlv(OID, EXTENSION_REQUEST), tlv(SET, &extensions)].concat());
+    let info = [
+        tlv(INTEGER, &[0]),
+        subject,
+        public_key_info(key),
+        tlv(ATTRIBUTES, &request),
+    ]
+    .concat();
+    signed(&tlv(SEQUENCE, &info), key, rng)
+}
+
+// The self-signed certificate that answers a TLS-ALPN-01 challenge for
+// `domain`: it carries the SHA-256 digest of the key authorization in a
+// critical acmeIdentifier extension. Valid for a week from `now`.
+pub fn alpn_challenge_cert(domain: &str, digest: &[u8], key: &EcdsaKeyPair, rng: &dyn SecureRandom, now: u64) -> Option<Vec<u8>> {
+    let acme_identifier = [
+        tlv(OID, ACME_IDENTIFIER),
+        tlv(BOOLEAN, &[0xff]),
+        tlv(OCTET_STRING, &tlv(OCTET_STRING, digest)),
+    ];
+    let acme_identifier = tlv(SEQUENCE, &acme_identifier.concat());
+    let extensions = tlv(SEQUENCE, &[subject_alt_name(&[domain.to_string()]), acme_identifier].concat());
+    let validity = [utc_time(now.saturating_sub(86_400)), utc_time(now + 7 * 86_400)].concat();
+    let tbs = [
+        tlv(VERSION, &tlv(INTEGER, &[2])),
+        tlv(INTEGER, &[1]),
+        tlv(SEQUENCE, &tlv(OID, ECDSA_WITH_SHA256)),
+        name(domain),
+        tlv(SEQUENCE, &validity),
+        name(domain),
+        public_key_info(key),
+        tlv(EXTENSIONS, &extensions),
+    ]
+    .concat();
+    signed(&tlv(SEQUENCE, &tbs), key, rng)
+}
+
+// `CN=<common_name>`.
+fn name(common_name: &str) -> Vec<u8> {
+    let attribute = tlv(SEQUENCE, &[tlv(OID, COMMON_NAME), tlv(UTF8_STRING, common_name.as_bytes())].concat());
+    tlv(SEQUENCE, &tlv(SET, &attribute))
+}
+
+fn public_key_info(key: &EcdsaKeyPair) -> Vec<u8> {
+    let algorithm = tlv(SEQUENCE, &[tlv(OID, EC_PUBLIC_KEY), tlv(OID, PRIME256V1)].concat());
+    tlv(SEQUENCE, &[algorithm, bit_string(key.public_key().as_ref())].concat())
+}
+
+// A subjectAltName extension listing `domains` as DNS names.
+fn subject_alt_name(domains: &[String]) -> Vec<u8> {
+    let names: Vec<u8> = domains.iter().flat_map(|d| tlv(DNS_NAME, d.as_bytes())).collect();
+    tlv(SEQUENCE, &[tlv(OID, SUBJECT_ALT_NAME), tlv(OCTET_STRING, &tlv(SEQUENCE, &names))].concat())
+}
+
+// Appends the algorithm and signature to a to-be-signed structure.
+fn signed(tbs: &[u8], key: &EcdsaKeyPair, rng: &dyn SecureRandom) -> Option<Vec<u8>> {
+    let signature = key.sign(rng, tbs).ok()?;
+    let algorithm = tlv(SEQUENCE, &tlv(OID, ECDSA_WITH_SHA256));
+    Some(tlv(SEQUENCE, &[tbs.to_vec(), algorithm, bit_string(signature.as_ref())].concat()))
+}
+
+fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    tlv(BIT_STRING, &[&[0][..], bytes].concat())
+}
+
+// Unix seconds as a UTCTime, "YYMMDDHHMMSSZ".
+fn utc_time(unix_secs: u64) -> Vec<u8> {
+    let (days, secs) = (unix_secs / 86_400, unix_secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    let time = format!(
+        "{:02}{:02}{:02}{:02}{:02}{:02}Z",
+        year % 100,
+        month,
+        day,
+        secs / 3_600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    tlv(UTC_TIME, time.as_bytes())
+}
+
+// One DER element.
+fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+// Walks the elements of one level of DER.
+struct Der<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(input: &'a [u8]) -> Der<'a> {
+        Der { input }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.input.is_empty()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.first().copied()
+    }
+
+    // The next element's tag and contents. Certificates only use one-byte
+    // tags and lengths of up to four bytes.
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.input.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+        let (len, rest) = if first < 0x80 {
+            (usize::from(first), rest)
+        } else {
+            let n = usize::from(first & 0x7f);
+            if n == 0 || n > 4 || rest.len() < n {
+                return None;
+            }
+            let len = rest[..n].iter().fold(0, |len, &b| len << 8 | usize::from(b));
+            (len, &rest[n..])
+        };
+        if tag & 0x1f == 0x1f || rest.len() < len {
+            return None;
+        }
+        let (value, rest) = rest.split_at(len);
+        self.input = rest;
+        Some((tag, value))
+    }
+
+    // The contents of the next element, if it has this tag.
+    fn read(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.next()? {
+            (t, value) if t == tag => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::CertificateDer;
+
+    // Issued by a throwaway test CA with
+    // -subj "/C=NZ/O=Example, Inc./CN=ops client+UID=ops" -multivalue-rdn.
+    const CLIENT: &str = "\
+-----BEGIN CERTIFICATE-----\n\
+MIICFjCCAbugAwIBAgIUY8azVdSS67JKf8/t3iluc0JFmkEwCgYIKoZIzj0EAwIw\n\
+EjEQMA4GA1UEAwwHVGVzdCBDQTAeFw0yNjEwMTYxODIyNDFaFw0zNjEwMTMxODIy\n\
+NDFaME0xCzAJBgNVBAYTAk5aMRYwFAYDVQQKDA1FeGFtcGxlLCBJbmMuMSYwEQYD\n\
+VQQDDApvcHMgY2xpZW50MBEGCgmSJomT8ixkAQEMA29wczBZMBMGByqGSM49AgEG\n\
+CCqGSM49AwEHA0IABMNJhisP+XllT3oIsKKXxKInu5QV4OwCJqevws2fDyflQny4\n\
+w40pg3AaqTn4makprnwDJsg3jFeBDN3XuKrpYw6jgbMwgbAwWQYDVR0RBFIwUIIP\n\
+b3BzLmV4YW1wbGUuY29tgQ9vcHNAZXhhbXBsZS5jb22GFHNwaWZmZTovL2V4YW1w\n\
+bGUvb3BzhwQKAAABhxAAAAAAAAAAAAAAAAAAAAABMBMGA1UdJQQMMAoGCCsGAQUF\n\
+BwMCMB0GA1UdDgQWBBTrJEt307mzekYGVyweys/rX1kfQDAfBgNVHSMEGDAWgBQr\n\
+/lao/poBXmNdBQ4C+xZ8pwe6TzAKBggqhkjOPQQDAgNJADBGAiEAlG+TyAGK/lLE\n\
+MeOTHvfZAs0goGGY3OlddTZBEQnbCv4CIQColBFuCXkpDWsy85TqAgBl5s8zL9O2\n\
+VQLyrZQFlm8QZQ==\n\
+-----END CERTIFICATE-----\n";
+
+    #[test]
+    fn reads_subject_and_san() {
+        let cert = CertificateDer::from_pem_slice(CLIENT.as_bytes()).unwrap();
+        let names = names(&cert).unwrap();
+        assert_eq!(names.subject, "CN=ops client+UID=ops,O=Example\\, Inc.,C=NZ");
+        assert_eq!(
+            names.san,
+            [
+                "DNS:ops.example.com",
+                "email:ops@example.com",
+                "URI:spiffe://example/ops",
+                "IP:10.0.0.1",
+                "IP:::1",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let cert = CertificateDer::from_pem_slice(CLIENT.as_bytes()).unwrap();
+        assert_eq!(names(&cert[..cert.len() / 2]), None);
+        assert_eq!(names(&[]), None);
+        assert_eq!(names(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn reads_expiry() {
+        let cert = CertificateDer::from_pem_slice(CLIENT.as_bytes()).unwrap();
+        assert_eq!(not_after(&cert), Some(2_107_534_961));
+        assert_eq!(parse_time(UTC_TIME, "000229120000Z"), Some(951_825_600));
+        assert_eq!(parse_time(GENERALIZED_TIME, "20491231235959Z"), Some(2_524_607_999));
+        // Before 1970, and not in UTC.
+        assert_eq!(parse_time(UTC_TIME, "500101000000Z"), None);
+        assert_eq!(parse_time(UTC_TIME, "000229120000+0100"), None);
+        assert_eq!(parse_time(UTC_TIME, "001329120000Z"), None);
+    }
+
+    #[test]
+    fn formats_values() {
+        assert_eq!(dotted(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01]), "1.2.840.113549.1.9.1");
+        assert_eq!(dotted(&[0x55, 0x04, 0x03]), "2.5.4.3");
+        assert_eq!(escape(" a,b+c "), "\\ a\\,b\\+c\\ ");
+        assert_eq!(escape("#x#"), "\\#x#");
+        assert_eq!(string_value(BMP_STRING, &[0x00, 0x6f, 0x00, 0x70, 0x00, 0x73]), "ops");
+    }
+}